@@ -80,11 +80,16 @@ macro_rules! blist {
 	}};
 }
 
+// lists and dicts nested deeper than this are rejected, decoder
+// is recursive and input may come from anyone on the network
+const MAX_DEPTH: usize = 64;
+
 #[derive(Debug, Eq, PartialEq)]
 pub enum DecodeError {
 	BadFormat,
 	NumberTooLarge,
 	EarlyEndOfInput,
+	TooDeep,
 }
 
 pub type DecodeResult<T> = Result<T, DecodeError>;
@@ -100,6 +105,14 @@ pub fn decode(input: &[u8]) -> DecodeResult<BValue> {
 	decoder.read()
 }
 
+// Decodes one value from the start of input, returning it together with
+// the number of bytes it took, so that raw data following it can be read.
+pub fn decode_prefix(input: &[u8]) -> DecodeResult<(BValue, usize)> {
+	let mut decoder = Decoder::new(input);
+	let value = try!(decoder.read());
+	Ok((value, decoder.position))
+}
+
 struct Encoder {
 	result: Vec<u8>,
 }
@@ -182,6 +195,8 @@ impl Encoder {
 struct Decoder<'a> {
 	input: &'a [u8],
 	position: usize,
+	// lists and dicts we are inside of
+	depth: usize,
 }
 
 impl<'a> Decoder<'a> {
@@ -189,6 +204,7 @@ impl<'a> Decoder<'a> {
 		Decoder {
 			input: input,
 			position: 0,
+			depth: 0,
 		}
 	}
 
@@ -286,13 +302,14 @@ impl<'a> Decoder<'a> {
 	}
 
 	fn read_bytes(&mut self, amount: usize) -> DecodeResult<&[u8]> {
-		let new_position = self.position + amount;
-		if new_position <= self.input.len() {
-			let slice = &self.input[self.position..new_position];
-			self.position = new_position;
-			Ok(slice)
-		} else {
-			Err(DecodeError::EarlyEndOfInput)
+		// length comes from input, it may be anything
+		match self.position.checked_add(amount) {
+			Some(new_position) if new_position <= self.input.len() => {
+				let slice = &self.input[self.position..new_position];
+				self.position = new_position;
+				Ok(slice)
+			}
+			_ => Err(DecodeError::EarlyEndOfInput),
 		}
 	}
 
@@ -302,23 +319,34 @@ impl<'a> Decoder<'a> {
 		Ok(bytes)
 	}
 
-	fn read_list(&mut self) -> DecodeResult<BValue> {
+	fn enter(&mut self) -> DecodeResult<()> {
+		if self.depth >= MAX_DEPTH {
+			return Err(DecodeError::TooDeep);
+		}
+		self.depth += 1;
 		self.advance();
+		Ok(())
+	}
+
+	fn read_list(&mut self) -> DecodeResult<BValue> {
+		try!(self.enter());
 		let mut items = Vec::new();
 		while !self.match_char('e') {
 			items.push(try!(self.read()));
 		}
+		self.depth -= 1;
 		Ok(BValue::List(items))
 	}
 
 	fn read_dict(&mut self) -> DecodeResult<BValue> {
-		self.advance();
+		try!(self.enter());
 		let mut dict = ::std::collections::BTreeMap::new();
 		while !self.match_char('e') {
 			let key = try!(self.read_string());
 			let value = try!(self.read());
 			dict.insert(key, value);
 		}
+		self.depth -= 1;
 		Ok(BValue::Dict(dict))
 	}
 }
@@ -397,6 +425,38 @@ mod test {
 		use super::*;
 		use super::super::*;
 
+		#[test]
+		fn deep_nesting() {
+			let nested = |depth| {
+				let mut input = vec![b'l'; depth];
+				input.extend(vec![b'e'; depth]);
+				input
+			};
+			assert!(decode(&nested(MAX_DEPTH)).is_ok());
+			assert_eq!(decode(&nested(MAX_DEPTH + 1)), Err(DecodeError::TooDeep));
+			assert_eq!(decode(&vec![b'l'; 1 << 19]), Err(DecodeError::TooDeep));
+			// depth goes back down when lists end
+			let mut siblings = vec![b'l'];
+			for _ in 0..(2 * MAX_DEPTH) {
+				siblings.extend(b"d1:ale1:bdee");
+			}
+			siblings.push(b'e');
+			assert!(decode(&siblings).is_ok());
+		}
+
+		#[test]
+		fn huge_string_length() {
+			assert_eq!(decode(b"d1:t18446744073709551614:xe"), Err(DecodeError::EarlyEndOfInput));
+			assert_eq!(decode(b"18446744073709551615:x"), Err(DecodeError::EarlyEndOfInput));
+			assert_eq!(decode(b"3:ab"), Err(DecodeError::EarlyEndOfInput));
+		}
+
+		#[test]
+		fn prefix() {
+			let decoded = decode_prefix(b"d1:ai1eeraw data");
+			assert_eq!(decoded, Ok((bdict!(b"a".to_vec() => BValue::Int(1)), 8)));
+		}
+
 		fn check_decode(expected: BValue, input: &[u8]) {
 			let decoded = decode(input);
 			assert_eq!(decoded, Ok(expected));
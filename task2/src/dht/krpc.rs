@@ -0,0 +1,360 @@
+use std::collections::BTreeMap;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use bencode;
+use bencode::BValue;
+use dht::{NodeId, Node};
+
+
+// error codes defined by BEP 5
+pub const ERROR_GENERIC: i64 = 201;
+pub const ERROR_PROTOCOL: i64 = 203;
+pub const ERROR_METHOD_UNKNOWN: i64 = 204;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Query {
+	Ping,
+	FindNode([u8; 20]),
+	GetPeers([u8; 20]),
+	AnnouncePeer {
+		info_hash: [u8; 20],
+		port: u16,
+		// peer is reachable on the port the query came from
+		implied_port: bool,
+		token: Vec<u8>,
+	},
+	Unknown(Vec<u8>),
+}
+
+// Responses don't say which query they answer, so all the
+// possible fields are kept together.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Response {
+	pub nodes: Vec<Node>,
+	pub values: Vec<SocketAddr>,
+	pub token: Option<Vec<u8>>,
+}
+
+impl Response {
+	pub fn empty() -> Response {
+		Response {
+			nodes: Vec::new(),
+			values: Vec::new(),
+			token: None,
+		}
+	}
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Body {
+	Query(Query),
+	Response(Response),
+	Error(i64, String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+	pub transaction: Vec<u8>,
+	// errors don't carry id of the sender
+	pub sender: Option<NodeId>,
+	pub body: Body,
+}
+
+impl Message {
+	pub fn encode(&self) -> Vec<u8> {
+		let mut dict = BTreeMap::new();
+		dict.insert(b"t".to_vec(), BValue::Str(self.transaction.clone()));
+		let id = self.sender.as_ref().map(|id| BValue::Str(id.0.to_vec()));
+		match self.body {
+			Body::Query(ref query) => {
+				let mut args = BTreeMap::new();
+				if let Some(id) = id {
+					args.insert(b"id".to_vec(), id);
+				}
+				let method: &[u8] = match *query {
+					Query::Ping => b"ping",
+					Query::FindNode(ref target) => {
+						args.insert(b"target".to_vec(), BValue::Str(target.to_vec()));
+						b"find_node"
+					}
+					Query::GetPeers(ref info_hash) => {
+						args.insert(b"info_hash".to_vec(), BValue::Str(info_hash.to_vec()));
+						b"get_peers"
+					}
+					Query::AnnouncePeer { ref info_hash, port, implied_port, ref token } => {
+						args.insert(b"info_hash".to_vec(), BValue::Str(info_hash.to_vec()));
+						args.insert(b"port".to_vec(), BValue::Int(port as i64));
+						args.insert(b"implied_port".to_vec(), BValue::Int(implied_port as i64));
+						args.insert(b"token".to_vec(), BValue::Str(token.clone()));
+						b"announce_peer"
+					}
+					Query::Unknown(ref method) => method,
+				};
+				dict.insert(b"y".to_vec(), BValue::Str(b"q".to_vec()));
+				dict.insert(b"q".to_vec(), BValue::Str(method.to_vec()));
+				dict.insert(b"a".to_vec(), BValue::Dict(args));
+			}
+			Body::Response(ref response) => {
+				let mut values = BTreeMap::new();
+				if let Some(id) = id {
+					values.insert(b"id".to_vec(), id);
+				}
+				if response.nodes.len() > 0 {
+					values.insert(b"nodes".to_vec(), BValue::Str(encode_nodes(&response.nodes)));
+				}
+				if response.values.len() > 0 {
+					let peers = response.values.iter()
+						.filter_map(encode_peer)
+						.map(|peer| BValue::Str(peer.to_vec()))
+						.collect();
+					values.insert(b"values".to_vec(), BValue::List(peers));
+				}
+				if let Some(ref token) = response.token {
+					values.insert(b"token".to_vec(), BValue::Str(token.clone()));
+				}
+				dict.insert(b"y".to_vec(), BValue::Str(b"r".to_vec()));
+				dict.insert(b"r".to_vec(), BValue::Dict(values));
+			}
+			Body::Error(code, ref message) => {
+				dict.insert(b"y".to_vec(), BValue::Str(b"e".to_vec()));
+				dict.insert(b"e".to_vec(), blist![
+					BValue::Int(code),
+					BValue::Str(message.as_bytes().to_vec())
+				]);
+			}
+		}
+		bencode::encode(&BValue::Dict(dict))
+	}
+
+	pub fn decode(packet: &[u8]) -> Option<Message> {
+		let mut dict = match bencode::decode(packet).ok().and_then(BValue::get_dict) {
+			Some(dict) => dict,
+			None => return None,
+		};
+		let transaction = match dict.remove(&b"t"[..]).and_then(BValue::get_string) {
+			Some(transaction) => transaction,
+			None => return None,
+		};
+		let kind = dict.remove(&b"y"[..]).and_then(BValue::get_string);
+		let (sender, body) = match kind.as_ref().map(|kind| &kind[..]) {
+			Some(b"q") => {
+				let method = match dict.remove(&b"q"[..]).and_then(BValue::get_string) {
+					Some(method) => method,
+					None => return None,
+				};
+				let args = match dict.remove(&b"a"[..]).and_then(BValue::get_dict) {
+					Some(args) => args,
+					None => return None,
+				};
+				let sender = match get_id(&args, b"id") {
+					Some(id) => NodeId(id),
+					None => return None,
+				};
+				let query = match decode_query(method, &args) {
+					Some(query) => query,
+					None => return None,
+				};
+				(Some(sender), Body::Query(query))
+			}
+			Some(b"r") => {
+				let values = match dict.remove(&b"r"[..]).and_then(BValue::get_dict) {
+					Some(values) => values,
+					None => return None,
+				};
+				let sender = match get_id(&values, b"id") {
+					Some(id) => NodeId(id),
+					None => return None,
+				};
+				(Some(sender), Body::Response(decode_response(&values)))
+			}
+			Some(b"e") => {
+				let error = dict.remove(&b"e"[..]).and_then(BValue::get_list).unwrap_or_default();
+				let code = error.get(0).and_then(BValue::get_int).unwrap_or(ERROR_GENERIC);
+				let message = error.get(1)
+					.and_then(BValue::get_string_ref)
+					.map(|message| String::from_utf8_lossy(message).into_owned())
+					.unwrap_or_default();
+				(None, Body::Error(code, message))
+			}
+			_ => return None,
+		};
+		Some(Message {
+			transaction: transaction,
+			sender: sender,
+			body: body,
+		})
+	}
+}
+
+fn decode_query(method: Vec<u8>, args: &BTreeMap<Vec<u8>, BValue>) -> Option<Query> {
+	match &method[..] {
+		b"ping" => Some(Query::Ping),
+		b"find_node" => get_id(args, b"target").map(Query::FindNode),
+		b"get_peers" => get_id(args, b"info_hash").map(Query::GetPeers),
+		b"announce_peer" => {
+			let info_hash = match get_id(args, b"info_hash") {
+				Some(info_hash) => info_hash,
+				None => return None,
+			};
+			let port = args.get(&b"port"[..])
+				.and_then(BValue::get_int)
+				.filter(|&port| port > 0 && port <= 0xFFFF)
+				.unwrap_or(0) as u16;
+			let implied_port = args.get(&b"implied_port"[..])
+				.and_then(BValue::get_int)
+				.map(|implied| implied != 0)
+				.unwrap_or(false);
+			let token = match args.get(&b"token"[..]).and_then(BValue::get_string_ref) {
+				Some(token) => token.to_vec(),
+				None => return None,
+			};
+			if port == 0 && !implied_port {
+				return None;
+			}
+			Some(Query::AnnouncePeer {
+				info_hash: info_hash,
+				port: port,
+				implied_port: implied_port,
+				token: token,
+			})
+		}
+		_ => Some(Query::Unknown(method)),
+	}
+}
+
+fn decode_response(values: &BTreeMap<Vec<u8>, BValue>) -> Response {
+	let nodes = values.get(&b"nodes"[..])
+		.and_then(BValue::get_string_ref)
+		.map(decode_nodes)
+		.unwrap_or_default();
+	let peers = values.get(&b"values"[..])
+		.and_then(BValue::get_list_ref)
+		.map(|peers| {
+			peers.iter()
+				.filter_map(BValue::get_string_ref)
+				.filter_map(decode_peer)
+				.collect()
+		})
+		.unwrap_or_default();
+	let token = values.get(&b"token"[..])
+		.and_then(BValue::get_string_ref)
+		.map(|token| token.to_vec());
+	Response {
+		nodes: nodes,
+		values: peers,
+		token: token,
+	}
+}
+
+fn get_id(dict: &BTreeMap<Vec<u8>, BValue>, key: &[u8]) -> Option<[u8; 20]> {
+	match dict.get(key).and_then(BValue::get_string_ref) {
+		Some(bytes) if bytes.len() == 20 => {
+			let mut id = [0; 20];
+			id.copy_from_slice(bytes);
+			Some(id)
+		}
+		_ => None,
+	}
+}
+
+// nodes are encoded as 20 bytes of id followed by 6 bytes of address
+pub fn encode_nodes(nodes: &[Node]) -> Vec<u8> {
+	let mut encoded = Vec::new();
+	for node in nodes {
+		if let Some(address) = encode_peer(&node.address) {
+			encoded.extend_from_slice(&node.id.0);
+			encoded.extend_from_slice(&address);
+		}
+	}
+	encoded
+}
+
+pub fn decode_nodes(encoded: &[u8]) -> Vec<Node> {
+	encoded.chunks(26)
+		.filter(|chunk| chunk.len() == 26)
+		.filter_map(|chunk| {
+			let mut id = [0; 20];
+			id.copy_from_slice(&chunk[..20]);
+			decode_peer(&chunk[20..]).map(|address| Node::new(NodeId(id), address))
+		})
+		.collect()
+}
+
+pub fn encode_peer(address: &SocketAddr) -> Option<[u8; 6]> {
+	match *address {
+		SocketAddr::V4(address) => {
+			let ip = address.ip().octets();
+			let port = address.port();
+			Some([ip[0], ip[1], ip[2], ip[3], (port >> 8) as u8, port as u8])
+		}
+		SocketAddr::V6(_) => None,
+	}
+}
+
+pub fn decode_peer(encoded: &[u8]) -> Option<SocketAddr> {
+	if encoded.len() != 6 {
+		return None;
+	}
+	let ip = Ipv4Addr::new(encoded[0], encoded[1], encoded[2], encoded[3]);
+	let port = ((encoded[4] as u16) << 8) | encoded[5] as u16;
+	Some(SocketAddr::V4(SocketAddrV4::new(ip, port)))
+}
+
+
+#[cfg(test)]
+mod test {
+	use dht::{NodeId, Node};
+	use super::*;
+
+	fn roundtrip(message: Message) {
+		let encoded = message.encode();
+		assert_eq!(Message::decode(&encoded), Some(message));
+	}
+
+	#[test]
+	fn ping() {
+		let message = Message {
+			transaction: b"aa".to_vec(),
+			sender: Some(NodeId(*b"abcdefghij0123456789")),
+			body: Body::Query(Query::Ping),
+		};
+		assert_eq!(message.encode(),
+			b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe".to_vec());
+		roundtrip(message);
+	}
+
+	#[test]
+	fn queries_and_responses() {
+		let sender = Some(NodeId([1; 20]));
+		roundtrip(Message {
+			transaction: b"x".to_vec(),
+			sender: sender.clone(),
+			body: Body::Query(Query::AnnouncePeer {
+				info_hash: [2; 20],
+				port: 6881,
+				implied_port: false,
+				token: b"token".to_vec(),
+			}),
+		});
+		roundtrip(Message {
+			transaction: b"y".to_vec(),
+			sender: sender.clone(),
+			body: Body::Response(Response {
+				nodes: vec![Node::new(NodeId([3; 20]), "10.0.0.1:80".parse().unwrap())],
+				values: vec!["10.0.0.2:6881".parse().unwrap()],
+				token: Some(b"t".to_vec()),
+			}),
+		});
+		roundtrip(Message {
+			transaction: b"z".to_vec(),
+			sender: None,
+			body: Body::Error(ERROR_PROTOCOL, "bad token".to_string()),
+		});
+	}
+
+	#[test]
+	fn rejects_malformed() {
+		assert_eq!(Message::decode(b"d1:t2:aa1:y1:qe"), None);
+		assert_eq!(Message::decode(b"d1:ad2:id3:abce1:q4:ping1:t2:aa1:y1:qe"), None);
+		assert_eq!(Message::decode(b"garbage"), None);
+	}
+}
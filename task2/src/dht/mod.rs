@@ -0,0 +1,744 @@
+pub mod krpc;
+pub mod routing;
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::io::{Read, Write, ErrorKind};
+use std::net;
+use std::net::{SocketAddr, IpAddr, ToSocketAddrs};
+use std::path::Path;
+use std::time::{Duration, Instant};
+use mio::net::UdpSocket;
+use bencode::{BValue, encode, decode};
+use downloader::PeerAddress;
+use downloader::connection::event_loop::EventLoop;
+use dht::krpc::{Message, Body, Query, Response, ERROR_PROTOCOL, ERROR_METHOD_UNKNOWN};
+use dht::routing::{RoutingTable, K};
+
+
+// queries sent at once by a single lookup
+const ALPHA: usize = 3;
+// lookups only remember this many nodes closest to the target
+const MAX_CANDIDATES: usize = K * 4;
+const QUERY_TIMEOUT: u64 = 5; // seconds
+// tokens are valid until the secret changes twice
+const SECRET_INTERVAL: u64 = 5 * 60; // seconds
+const PEER_LIFETIME: u64 = 30 * 60; // seconds
+const MAX_STORED_PEERS: usize = 100;
+// how often peers of every torrent are searched for and announced
+const SEARCH_INTERVAL: u64 = 5 * 60; // seconds
+// used instead of the above when we can't reach anybody
+const RETRY_INTERVAL: u64 = 30; // seconds
+const REFRESH_INTERVAL: u64 = 15 * 60; // seconds
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct NodeId(pub [u8; 20]);
+
+impl NodeId {
+	pub fn random() -> NodeId {
+		let mut id = [0; 20];
+		for byte in id.iter_mut() {
+			*byte = ::rand::random();
+		}
+		NodeId(id)
+	}
+
+	pub fn distance(&self, other: &[u8; 20]) -> [u8; 20] {
+		let mut distance = [0; 20];
+		for i in 0..20 {
+			distance[i] = self.0[i] ^ other[i];
+		}
+		distance
+	}
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Node {
+	pub id: NodeId,
+	pub address: SocketAddr,
+}
+
+impl Node {
+	pub fn new(id: NodeId, address: SocketAddr) -> Node {
+		Node {
+			id: id,
+			address: address,
+		}
+	}
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum LookupKind {
+	FindNode,
+	GetPeers,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum CandidateState {
+	Fresh,
+	Pending,
+	Responded,
+	Failed,
+}
+
+struct Candidate {
+	// bootstrap nodes are known only by address
+	id: Option<NodeId>,
+	address: SocketAddr,
+	state: CandidateState,
+	token: Option<Vec<u8>>,
+}
+
+// Iterative search for nodes closest to the target: we keep asking the
+// closest nodes we know about for even closer ones, until the K closest
+// have all answered.
+struct Lookup {
+	target: [u8; 20],
+	kind: LookupKind,
+	candidates: Vec<Candidate>,
+}
+
+impl Lookup {
+	fn new(target: [u8; 20], kind: LookupKind) -> Lookup {
+		Lookup {
+			target: target,
+			kind: kind,
+			candidates: Vec::new(),
+		}
+	}
+
+	fn add(&mut self, id: Option<NodeId>, address: SocketAddr) {
+		if self.candidates.iter().any(|c| c.address == address) {
+			return;
+		}
+		self.candidates.push(Candidate {
+			id: id,
+			address: address,
+			state: CandidateState::Fresh,
+			token: None,
+		});
+		let target = self.target;
+		// nodes with unknown id go first, we have no idea how close they are
+		self.candidates.sort_by_key(|c| c.id.as_ref().map(|id| id.distance(&target)));
+		if self.candidates.len() > MAX_CANDIDATES {
+			if let Some(index) = self.candidates.iter().rposition(|c| c.state == CandidateState::Fresh) {
+				self.candidates.remove(index);
+			}
+		}
+	}
+
+	fn query(&self) -> Query {
+		match self.kind {
+			LookupKind::FindNode => Query::FindNode(self.target),
+			LookupKind::GetPeers => Query::GetPeers(self.target),
+		}
+	}
+
+	// closest not yet queried node among the K closest live ones
+	fn next(&self) -> Option<usize> {
+		self.candidates.iter()
+			.enumerate()
+			.filter(|&(_, c)| c.state != CandidateState::Failed)
+			.take(K)
+			.find(|&(_, c)| c.state == CandidateState::Fresh)
+			.map(|(index, _)| index)
+	}
+
+	fn in_flight(&self) -> usize {
+		self.candidates.iter().filter(|c| c.state == CandidateState::Pending).count()
+	}
+
+	fn is_done(&self) -> bool {
+		self.in_flight() == 0 && self.next().is_none()
+	}
+
+	fn candidate(&mut self, address: &SocketAddr) -> Option<&mut Candidate> {
+		self.candidates.iter_mut().find(|c| c.address == *address)
+	}
+}
+
+struct Pending {
+	address: SocketAddr,
+	sent_at: Instant,
+	lookup: Option<[u8; 20]>,
+}
+
+struct Torrent {
+	// port we announce, if we accept connections
+	port: Option<u16>,
+	next_search: Instant,
+	found: Vec<PeerAddress>,
+}
+
+// Mainline DHT node (BEP 5). Like trackers it does not block, `update`
+// has to be called regularly to process incoming messages.
+pub struct Dht {
+	id: NodeId,
+	socket: UdpSocket,
+	table: RoutingTable,
+	pending: HashMap<Vec<u8>, Pending>,
+	next_transaction: u16,
+	lookups: HashMap<[u8; 20], Lookup>,
+	bootstrap: Vec<SocketAddr>,
+	last_refresh: Option<Instant>,
+	secret: [u8; 20],
+	previous_secret: [u8; 20],
+	secret_changed: Instant,
+	// peers announced to us by others
+	stored: HashMap<[u8; 20], Vec<(SocketAddr, Instant)>>,
+	torrents: HashMap<[u8; 20], Torrent>,
+}
+
+impl Dht {
+	pub fn new<A: ToSocketAddrs>(address: A) -> io::Result<Dht> {
+		let socket = try!(net::UdpSocket::bind(address).and_then(UdpSocket::from_socket));
+		let id = NodeId::random();
+		Ok(Dht {
+			table: RoutingTable::new(id.clone()),
+			id: id,
+			socket: socket,
+			pending: HashMap::new(),
+			next_transaction: ::rand::random(),
+			lookups: HashMap::new(),
+			bootstrap: Vec::new(),
+			last_refresh: None,
+			secret: NodeId::random().0,
+			previous_secret: NodeId::random().0,
+			secret_changed: Instant::now(),
+			stored: HashMap::new(),
+			torrents: HashMap::new(),
+		})
+	}
+
+	pub fn id(&self) -> &NodeId {
+		&self.id
+	}
+
+	pub fn local_addr(&self) -> io::Result<SocketAddr> {
+		self.socket.local_addr()
+	}
+
+	// incoming messages wake the event loop up
+	pub fn register(&self, event_loop: &EventLoop) -> io::Result<()> {
+		event_loop.watch(&self.socket)
+	}
+
+	pub fn node_count(&self) -> usize {
+		self.table.len()
+	}
+
+	// nodes used to join the network when our routing table is empty
+	pub fn add_bootstrap<A: ToSocketAddrs>(&mut self, address: A) {
+		match address.to_socket_addrs() {
+			Ok(addresses) => {
+				for address in addresses.filter(SocketAddr::is_ipv4) {
+					if !self.bootstrap.contains(&address) {
+						self.bootstrap.push(address);
+					}
+				}
+			}
+			Err(e) => {
+				debug!("Failed to resolve DHT bootstrap node: {:?}", e);
+			}
+		}
+	}
+
+	// Starts looking for peers of the torrent, and announcing
+	// that we are downloading it if port is given.
+	pub fn add_torrent(&mut self, info_hash: [u8; 20], port: Option<u16>) {
+		self.torrents.insert(info_hash, Torrent {
+			port: port,
+			next_search: Instant::now(),
+			found: Vec::new(),
+		});
+	}
+
+	pub fn remove_torrent(&mut self, info_hash: &[u8; 20]) {
+		self.torrents.remove(info_hash);
+		self.lookups.remove(info_hash);
+	}
+
+	// peers found since the last call
+	pub fn take_peers(&mut self, info_hash: &[u8; 20]) -> Vec<PeerAddress> {
+		match self.torrents.get_mut(info_hash) {
+			Some(torrent) => ::std::mem::replace(&mut torrent.found, Vec::new()),
+			None => Vec::new(),
+		}
+	}
+
+	pub fn update(&mut self) {
+		self.receive();
+		self.check_timeouts();
+		self.rotate_secret();
+		self.refresh_table();
+		self.search_torrents();
+		self.step_lookups();
+	}
+
+	// Node cache lets us rejoin the network without bootstrap nodes.
+	// Saved file is a bencoded dict with our "id" and compact "nodes".
+	pub fn save_nodes(&self, path: &Path) -> io::Result<()> {
+		let state = bdict![
+			b"id".to_vec() => BValue::Str(self.id.0.to_vec()),
+			b"nodes".to_vec() => BValue::Str(krpc::encode_nodes(&self.table.nodes()))
+		];
+		let mut temp_path = path.to_path_buf().into_os_string();
+		temp_path.push(".tmp");
+		{
+			let mut file = try!(fs::File::create(&temp_path));
+			try!(file.write_all(&encode(&state)));
+		}
+		fs::rename(&temp_path, path)
+	}
+
+	// Should be called before the node is used, as it restores our id.
+	pub fn load_nodes(&mut self, path: &Path) -> bool {
+		let mut contents = Vec::new();
+		let read = fs::File::open(path)
+			.and_then(|mut file| file.read_to_end(&mut contents));
+		if let Err(e) = read {
+			debug!("Failed to read DHT node cache {:?}: {:?}", path, e);
+			return false;
+		}
+		let mut state = match decode(&contents).ok().and_then(BValue::get_dict) {
+			Some(state) => state,
+			None => {
+				warn!("DHT node cache {:?} is corrupted", path);
+				return false;
+			}
+		};
+
+		if let Some(id) = state.remove(&b"id"[..]).and_then(BValue::get_string) {
+			if id.len() == 20 && self.table.len() == 0 {
+				self.id.0.copy_from_slice(&id);
+				self.table = RoutingTable::new(self.id.clone());
+			}
+		}
+		let nodes = state.remove(&b"nodes"[..])
+			.and_then(BValue::get_string)
+			.map(|nodes| krpc::decode_nodes(&nodes))
+			.unwrap_or_default();
+		debug!("Loaded {} DHT nodes", nodes.len());
+		// nodes may be long gone, they get into routing table once they answer
+		for node in nodes {
+			self.add_bootstrap(node.address);
+		}
+		true
+	}
+
+	fn refresh_table(&mut self) {
+		let now = Instant::now();
+		let interval = if self.table.len() < K { RETRY_INTERVAL } else { REFRESH_INTERVAL };
+		let due = match self.last_refresh {
+			Some(time) => now - time >= Duration::from_secs(interval),
+			None => true,
+		};
+		let target = self.id.0;
+		if due && !self.lookups.contains_key(&target) {
+			self.last_refresh = Some(now);
+			self.start_lookup(target, LookupKind::FindNode);
+		}
+	}
+
+	fn search_torrents(&mut self) {
+		let now = Instant::now();
+		let due = self.torrents.iter()
+			.filter(|&(hash, torrent)| torrent.next_search <= now && !self.lookups.contains_key(hash))
+			.map(|(hash, _)| *hash)
+			.collect::<Vec<_>>();
+		for info_hash in due {
+			self.torrents.get_mut(&info_hash).unwrap().next_search =
+				now + Duration::from_secs(SEARCH_INTERVAL);
+			self.start_lookup(info_hash, LookupKind::GetPeers);
+		}
+	}
+
+	fn start_lookup(&mut self, target: [u8; 20], kind: LookupKind) {
+		let mut lookup = Lookup::new(target, kind);
+		for node in self.table.closest(&target, K) {
+			lookup.add(Some(node.id), node.address);
+		}
+		if self.table.len() < K {
+			for address in &self.bootstrap {
+				lookup.add(None, *address);
+			}
+		}
+		self.lookups.insert(target, lookup);
+	}
+
+	fn step_lookups(&mut self) {
+		let mut queries = Vec::new();
+		let mut finished = Vec::new();
+		for (target, lookup) in self.lookups.iter_mut() {
+			while lookup.in_flight() < ALPHA {
+				match lookup.next() {
+					Some(index) => {
+						lookup.candidates[index].state = CandidateState::Pending;
+						queries.push((*target, lookup.candidates[index].address, lookup.query()));
+					}
+					None => break,
+				}
+			}
+			if lookup.is_done() {
+				finished.push(*target);
+			}
+		}
+		for (target, address, query) in queries {
+			self.send_query(address, query, Some(target));
+		}
+		for target in finished {
+			let lookup = self.lookups.remove(&target).unwrap();
+			self.finish_lookup(lookup);
+		}
+	}
+
+	fn finish_lookup(&mut self, lookup: Lookup) {
+		let responded = lookup.candidates.iter()
+			.filter(|c| c.state == CandidateState::Responded)
+			.take(K)
+			.collect::<Vec<_>>();
+		debug!("DHT lookup finished, {} nodes responded", responded.len());
+		if lookup.kind != LookupKind::GetPeers {
+			return;
+		}
+		let port = match self.torrents.get_mut(&lookup.target) {
+			Some(torrent) => {
+				if responded.len() == 0 {
+					torrent.next_search = Instant::now() + Duration::from_secs(RETRY_INTERVAL);
+				}
+				torrent.port
+			}
+			None => return,
+		};
+		if let Some(port) = port {
+			for candidate in responded {
+				if let Some(ref token) = candidate.token {
+					self.send_query(candidate.address, Query::AnnouncePeer {
+						info_hash: lookup.target,
+						port: port,
+						implied_port: false,
+						token: token.clone(),
+					}, None);
+				}
+			}
+		}
+	}
+
+	fn send_query(&mut self, address: SocketAddr, query: Query, lookup: Option<[u8; 20]>) {
+		let transaction = vec![(self.next_transaction >> 8) as u8, self.next_transaction as u8];
+		self.next_transaction = self.next_transaction.wrapping_add(1);
+		let message = Message {
+			transaction: transaction.clone(),
+			sender: Some(self.id.clone()),
+			body: Body::Query(query),
+		};
+		self.send(&message, address);
+		self.pending.insert(transaction, Pending {
+			address: address,
+			sent_at: Instant::now(),
+			lookup: lookup,
+		});
+	}
+
+	fn send(&self, message: &Message, address: SocketAddr) {
+		if let Err(e) = self.socket.send_to(&message.encode(), &address) {
+			debug!("Failed to send DHT message to {:?}: {:?}", address, e);
+		}
+	}
+
+	fn check_timeouts(&mut self) {
+		let timeout = Duration::from_secs(QUERY_TIMEOUT);
+		let now = Instant::now();
+		let expired = self.pending.iter()
+			.filter(|&(_, pending)| now - pending.sent_at >= timeout)
+			.map(|(transaction, _)| transaction.clone())
+			.collect::<Vec<_>>();
+		for transaction in expired {
+			let pending = self.pending.remove(&transaction).unwrap();
+			self.query_failed(pending);
+		}
+	}
+
+	fn query_failed(&mut self, pending: Pending) {
+		self.table.failed(&pending.address);
+		let lookup = pending.lookup.and_then(|target| self.lookups.get_mut(&target));
+		if let Some(candidate) = lookup.and_then(|lookup| lookup.candidate(&pending.address)) {
+			candidate.state = CandidateState::Failed;
+		}
+	}
+
+	fn receive(&mut self) {
+		let mut buffer = [0_u8; 2048];
+		loop {
+			match self.socket.recv_from(&mut buffer) {
+				Ok((size, from)) => {
+					match Message::decode(&buffer[..size]) {
+						Some(message) => self.handle_message(message, from),
+						None => debug!("Got malformed DHT message from {:?}", from),
+					}
+				}
+				Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
+					return;
+				}
+				Err(e) => {
+					// on some systems ICMP errors of earlier sends show up
+					// here, anything left is read on the next update
+					debug!("Failed to receive DHT message: {:?}", e);
+					return;
+				}
+			}
+		}
+	}
+
+	fn handle_message(&mut self, message: Message, from: SocketAddr) {
+		match message.body {
+			Body::Query(query) => {
+				if let Some(id) = message.sender {
+					self.table.seen(Node::new(id, from));
+				}
+				self.answer(message.transaction, query, from);
+			}
+			Body::Response(response) => {
+				self.handle_response(message.transaction, message.sender, response, from);
+			}
+			Body::Error(code, text) => {
+				debug!("DHT node {:?} returned error {}: {}", from, code, text);
+				match self.pending.remove(&message.transaction) {
+					Some(pending) if pending.address == from => self.query_failed(pending),
+					Some(pending) => {
+						self.pending.insert(message.transaction, pending);
+					}
+					None => {}
+				}
+			}
+		}
+	}
+
+	fn handle_response(
+			&mut self,
+			transaction: Vec<u8>,
+			sender: Option<NodeId>,
+			response: Response,
+			from: SocketAddr) {
+		let pending = match self.pending.remove(&transaction) {
+			Some(pending) => pending,
+			None => {
+				debug!("Got DHT response with unknown transaction id");
+				return;
+			}
+		};
+		if pending.address != from {
+			self.pending.insert(transaction, pending);
+			return;
+		}
+		if let Some(ref id) = sender {
+			self.table.seen(Node::new(id.clone(), from));
+		}
+
+		let target = match pending.lookup {
+			Some(target) => target,
+			None => return,
+		};
+		if let Some(lookup) = self.lookups.get_mut(&target) {
+			if let Some(candidate) = lookup.candidate(&from) {
+				candidate.state = CandidateState::Responded;
+				candidate.token = response.token.clone();
+				if candidate.id.is_none() {
+					candidate.id = sender;
+				}
+			}
+			for node in response.nodes {
+				if node.id != self.id {
+					lookup.add(Some(node.id), node.address);
+				}
+			}
+		}
+		if let Some(torrent) = self.torrents.get_mut(&target) {
+			for value in response.values {
+				let peer = PeerAddress::from(value);
+				if !torrent.found.contains(&peer) {
+					torrent.found.push(peer);
+				}
+			}
+		}
+	}
+
+	fn answer(&mut self, transaction: Vec<u8>, query: Query, from: SocketAddr) {
+		let body = match query {
+			Query::Ping => {
+				Body::Response(Response::empty())
+			}
+			Query::FindNode(target) => {
+				let mut response = Response::empty();
+				response.nodes = self.table.closest(&target, K);
+				Body::Response(response)
+			}
+			Query::GetPeers(info_hash) => {
+				let mut response = Response::empty();
+				response.token = Some(self.token(&from.ip(), &self.secret));
+				response.values = self.stored_peers(&info_hash);
+				if response.values.len() == 0 {
+					response.nodes = self.table.closest(&info_hash, K);
+				}
+				Body::Response(response)
+			}
+			Query::AnnouncePeer { info_hash, port, implied_port, token } => {
+				let ip = from.ip();
+				if token != self.token(&ip, &self.secret) &&
+						token != self.token(&ip, &self.previous_secret) {
+					Body::Error(ERROR_PROTOCOL, "bad token".to_string())
+				} else {
+					let port = if implied_port { from.port() } else { port };
+					self.store_peer(info_hash, SocketAddr::new(ip, port));
+					Body::Response(Response::empty())
+				}
+			}
+			Query::Unknown(_) => {
+				Body::Error(ERROR_METHOD_UNKNOWN, "Method Unknown".to_string())
+			}
+		};
+		let sender = match body {
+			Body::Error(_, _) => None,
+			_ => Some(self.id.clone()),
+		};
+		self.send(&Message {
+			transaction: transaction,
+			sender: sender,
+			body: body,
+		}, from);
+	}
+
+	// token proves that announcing node asked us for peers
+	// recently from the same ip
+	fn token(&self, ip: &IpAddr, secret: &[u8; 20]) -> Vec<u8> {
+		let mut hasher = ::sha1::Sha1::new();
+		hasher.update(secret);
+		match *ip {
+			IpAddr::V4(ip) => hasher.update(&ip.octets()),
+			IpAddr::V6(ip) => hasher.update(&ip.octets()),
+		}
+		hasher.digest().bytes()[..8].to_vec()
+	}
+
+	fn rotate_secret(&mut self) {
+		if Instant::now() - self.secret_changed >= Duration::from_secs(SECRET_INTERVAL) {
+			self.previous_secret = self.secret;
+			self.secret = NodeId::random().0;
+			self.secret_changed = Instant::now();
+		}
+	}
+
+	fn store_peer(&mut self, info_hash: [u8; 20], address: SocketAddr) {
+		let peers = self.stored.entry(info_hash).or_insert_with(Vec::new);
+		peers.retain(|&(a, _)| a != address);
+		if peers.len() >= MAX_STORED_PEERS {
+			peers.remove(0);
+		}
+		peers.push((address, Instant::now()));
+	}
+
+	fn stored_peers(&mut self, info_hash: &[u8; 20]) -> Vec<SocketAddr> {
+		let lifetime = Duration::from_secs(PEER_LIFETIME);
+		let now = Instant::now();
+		match self.stored.get_mut(info_hash) {
+			Some(peers) => {
+				peers.retain(|&(_, time)| now - time < lifetime);
+				peers.iter().map(|&(address, _)| address).collect()
+			}
+			None => Vec::new(),
+		}
+	}
+}
+
+
+#[cfg(test)]
+mod test {
+	use std::net::Ipv4Addr;
+	use std::thread;
+	use std::time::{Duration, Instant};
+	use downloader::PeerAddress;
+	use super::*;
+
+	// several nodes on localhost forming a private network, all of them
+	// bootstrapped from the first one
+	fn network(size: usize) -> Vec<Dht> {
+		let mut nodes = (0..size)
+			.map(|_| Dht::new("127.0.0.1:0").unwrap())
+			.collect::<Vec<_>>();
+		let first = nodes[0].local_addr().unwrap();
+		for node in nodes.iter_mut().skip(1) {
+			node.add_bootstrap(first);
+		}
+		nodes
+	}
+
+	fn update_until<F: Fn(&mut [Dht]) -> bool>(nodes: &mut [Dht], done: F) {
+		let deadline = Instant::now() + Duration::from_secs(10);
+		while !done(nodes) {
+			assert!(Instant::now() < deadline, "DHT did not converge in time");
+			for node in nodes.iter_mut() {
+				node.update();
+			}
+			thread::sleep(Duration::from_millis(5));
+		}
+	}
+
+	#[test]
+	fn nodes_find_each_other() {
+		let mut nodes = network(6);
+		update_until(&mut nodes, |nodes| nodes.iter().all(|n| n.node_count() >= 3));
+	}
+
+	#[test]
+	fn announce_and_get_peers() {
+		let mut nodes = network(6);
+		update_until(&mut nodes, |nodes| nodes.iter().all(|n| n.node_count() >= 3));
+
+		let info_hash = [0x42; 20];
+		nodes[2].add_torrent(info_hash, Some(6881));
+		update_until(&mut nodes, |nodes| nodes.iter().any(|n| n.stored.len() > 0));
+
+		nodes[5].add_torrent(info_hash, None);
+		let expected = PeerAddress::new(Ipv4Addr::new(127, 0, 0, 1), 6881);
+		update_until(&mut nodes, |nodes| nodes[5].torrents[&info_hash].found.contains(&expected));
+		assert_eq!(nodes[5].take_peers(&info_hash), vec![expected]);
+		assert!(nodes[5].take_peers(&info_hash).is_empty());
+	}
+
+	#[test]
+	fn rejects_announce_with_bad_token() {
+		let mut node = Dht::new("127.0.0.1:0").unwrap();
+		let from = "127.0.0.1:1234".parse().unwrap();
+		node.answer(b"aa".to_vec(), Query::AnnouncePeer {
+			info_hash: [1; 20],
+			port: 80,
+			implied_port: false,
+			token: b"forged".to_vec(),
+		}, from);
+		assert!(node.stored.is_empty());
+
+		let token = node.token(&from.ip(), &node.secret);
+		node.answer(b"ab".to_vec(), Query::AnnouncePeer {
+			info_hash: [1; 20],
+			port: 80,
+			implied_port: true,
+			token: token,
+		}, from);
+		assert_eq!(node.stored_peers(&[1; 20]), vec![from]);
+	}
+
+	#[test]
+	fn node_cache() {
+		let mut nodes = network(3);
+		update_until(&mut nodes, |nodes| nodes[0].node_count() >= 2);
+		let path = ::std::env::temp_dir().join(format!("dht-cache-{}", ::rand::random::<u32>()));
+		nodes[0].save_nodes(&path).unwrap();
+
+		let mut restored = Dht::new("127.0.0.1:0").unwrap();
+		assert!(restored.load_nodes(&path));
+		let _ = ::std::fs::remove_file(&path);
+		assert_eq!(restored.id(), nodes[0].id());
+		assert_eq!(restored.bootstrap.len(), 2);
+	}
+}
@@ -0,0 +1,157 @@
+use std::net::SocketAddr;
+use std::time::Instant;
+use dht::{NodeId, Node};
+
+
+// nodes kept in a single bucket
+pub const K: usize = 8;
+// node is dropped after failing to answer this many queries in a row
+const MAX_FAILURES: u32 = 2;
+
+struct Entry {
+	node: Node,
+	last_seen: Instant,
+	failures: u32,
+}
+
+// Kademlia routing table. Bucket `i` holds nodes whose id shares exactly
+// `i` leading bits with ours, so we know a lot about our neighbourhood and
+// a little about far away parts of the id space.
+pub struct RoutingTable {
+	own: NodeId,
+	buckets: Vec<Vec<Entry>>,
+}
+
+impl RoutingTable {
+	pub fn new(own: NodeId) -> RoutingTable {
+		RoutingTable {
+			own: own,
+			buckets: (0..160).map(|_| Vec::new()).collect(),
+		}
+	}
+
+	pub fn len(&self) -> usize {
+		self.buckets.iter().map(Vec::len).sum()
+	}
+
+	// Records that node is alive. New nodes are added if there is room in
+	// their bucket, or if some node there stopped responding.
+	pub fn seen(&mut self, node: Node) {
+		let index = match self.bucket_index(&node.id) {
+			Some(index) => index,
+			None => return,
+		};
+		let bucket = &mut self.buckets[index];
+		if let Some(entry) = bucket.iter_mut().find(|e| e.node.id == node.id) {
+			entry.node.address = node.address;
+			entry.last_seen = Instant::now();
+			entry.failures = 0;
+			return;
+		}
+		if bucket.len() >= K {
+			match bucket.iter().position(|e| e.failures > 0) {
+				Some(position) => {
+					bucket.remove(position);
+				}
+				None => return,
+			}
+		}
+		bucket.push(Entry {
+			node: node,
+			last_seen: Instant::now(),
+			failures: 0,
+		});
+	}
+
+	pub fn failed(&mut self, address: &SocketAddr) {
+		for bucket in &mut self.buckets {
+			for entry in bucket.iter_mut().filter(|e| e.node.address == *address) {
+				entry.failures += 1;
+			}
+			bucket.retain(|e| e.failures <= MAX_FAILURES);
+		}
+	}
+
+	pub fn closest(&self, target: &[u8; 20], count: usize) -> Vec<Node> {
+		let mut nodes = self.nodes();
+		nodes.sort_by_key(|node| node.id.distance(target));
+		nodes.truncate(count);
+		nodes
+	}
+
+	pub fn nodes(&self) -> Vec<Node> {
+		self.buckets.iter()
+			.flat_map(|bucket| bucket.iter().map(|e| e.node.clone()))
+			.collect()
+	}
+
+	// time when we last heard from any node
+	pub fn last_activity(&self) -> Option<Instant> {
+		self.buckets.iter()
+			.flat_map(|bucket| bucket.iter().map(|e| e.last_seen))
+			.max()
+	}
+
+	fn bucket_index(&self, id: &NodeId) -> Option<usize> {
+		let distance = self.own.distance(&id.0);
+		distance.iter()
+			.position(|&byte| byte != 0)
+			.map(|byte| byte * 8 + distance[byte].leading_zeros() as usize)
+	}
+}
+
+
+#[cfg(test)]
+mod test {
+	use dht::{NodeId, Node};
+	use super::*;
+
+	fn node(first: u8, last: u8) -> Node {
+		let mut id = [0; 20];
+		id[0] = first;
+		id[19] = last;
+		Node::new(NodeId(id), format!("10.0.0.{}:{}", first, 1000 + last as u16).parse().unwrap())
+	}
+
+	#[test]
+	fn full_bucket_keeps_good_nodes() {
+		let mut table = RoutingTable::new(NodeId([0; 20]));
+		// all of these go to bucket 0, their first bit differs from ours
+		for i in 0..(K as u8 + 2) {
+			table.seen(node(0x80, i));
+		}
+		assert_eq!(table.len(), K);
+
+		let failing = node(0x80, 3).address;
+		table.failed(&failing);
+		table.seen(node(0x80, 100));
+		assert_eq!(table.len(), K);
+		assert!(table.nodes().iter().all(|n| n.address != failing));
+	}
+
+	#[test]
+	fn closest_nodes() {
+		let mut table = RoutingTable::new(NodeId([0; 20]));
+		table.seen(node(0x80, 0));
+		table.seen(node(0x01, 0));
+		table.seen(node(0x10, 0));
+		// own id is never stored
+		table.seen(Node::new(NodeId([0; 20]), "10.0.0.9:1".parse().unwrap()));
+
+		let mut target = [0; 20];
+		target[0] = 0x11;
+		let closest = table.closest(&target, 2);
+		assert_eq!(closest, vec![node(0x10, 0), node(0x01, 0)]);
+	}
+
+	#[test]
+	fn drops_unresponsive_nodes() {
+		let mut table = RoutingTable::new(NodeId([0; 20]));
+		let n = node(0x40, 1);
+		table.seen(n.clone());
+		for _ in 0..3 {
+			table.failed(&n.address);
+		}
+		assert_eq!(table.len(), 0);
+	}
+}
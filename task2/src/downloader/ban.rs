@@ -0,0 +1,190 @@
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+use downloader::PeerAddress;
+
+
+// peers that took part in this many failed pieces without
+// being singled out are banned anyway
+const MAX_STRIKES: usize = 3;
+
+// Finds out which peers send us corrupt data and keeps them away.
+//
+// When a piece fails hash check and all of its blocks came from one peer,
+// that peer is banned right away. If several peers contributed, they
+// become suspects and the piece is downloaded again from a single peer:
+// if it fails again that peer is to blame, if it passes the peer is
+// cleared, and when only one suspect is left it must have been the one.
+pub struct BanList {
+	banned: HashSet<IpAddr>,
+	// failed pieces and peers that sent their blocks
+	suspects: HashMap<usize, Vec<Option<PeerAddress>>>,
+	// the only peer allowed to download a failed piece
+	assigned: HashMap<usize, PeerAddress>,
+	strikes: HashMap<IpAddr, usize>,
+}
+
+impl Default for BanList {
+	fn default() -> BanList {
+		BanList {
+			banned: HashSet::new(),
+			suspects: HashMap::new(),
+			assigned: HashMap::new(),
+			strikes: HashMap::new(),
+		}
+	}
+}
+
+impl BanList {
+	pub fn new() -> BanList {
+		BanList::default()
+	}
+
+	// whole address is banned, so that peer cannot just reconnect from another port
+	pub fn is_banned(&self, peer: &PeerAddress) -> bool {
+		self.banned.contains(&peer.ip())
+	}
+
+	// Piece failed hash check, sources are peers that sent its blocks,
+	// None is an extra suspect for blocks we don't know the sender of.
+	// Returns peers that were banned because of it.
+	pub fn piece_failed(&mut self, piece: usize, sources: Vec<Option<PeerAddress>>) -> Vec<PeerAddress> {
+		self.assigned.remove(&piece);
+		if sources.len() == 1 {
+			self.suspects.remove(&piece);
+			return self.ban(sources.into_iter().flatten().collect());
+		}
+
+		let mut banned = Vec::new();
+		for peer in sources.iter().filter_map(|peer| peer.as_ref()) {
+			if self.strike(peer) {
+				banned.push(peer.clone());
+			}
+		}
+		let banned = self.ban(banned);
+		if sources.len() > 1 {
+			debug!("Piece #{} failed, suspects: {:?}", piece, sources);
+			self.suspects.insert(piece, sources);
+		}
+		banned
+	}
+
+	// Piece passed hash check, returns peers that were banned because
+	// it turned out they sent corrupt data before.
+	pub fn piece_passed(&mut self, piece: usize, sources: Vec<Option<PeerAddress>>) -> Vec<PeerAddress> {
+		self.assigned.remove(&piece);
+		let suspects = match self.suspects.remove(&piece) {
+			Some(suspects) => suspects,
+			None => return Vec::new(),
+		};
+		let source = match sources.as_slice() {
+			&[Some(ref source)] => source.ip(),
+			// data came from several peers again, we learned nothing
+			_ => return Vec::new(),
+		};
+		let remaining = suspects.into_iter()
+			.filter(|peer| peer.as_ref().map(|peer| peer.ip()) != Some(source))
+			.collect::<Vec<_>>();
+		if remaining.len() == 1 {
+			self.ban(remaining.into_iter().flatten().collect())
+		} else {
+			Vec::new()
+		}
+	}
+
+	// Failed pieces are only given to one peer: the first one that asks.
+	pub fn may_request(&self, piece: usize, peer: &PeerAddress) -> bool {
+		match self.assigned.get(&piece) {
+			Some(assigned) => assigned == peer,
+			None => true,
+		}
+	}
+
+	pub fn piece_requested(&mut self, piece: usize, peer: &PeerAddress) {
+		if self.suspects.contains_key(&piece) && !self.assigned.contains_key(&piece) {
+			self.assigned.insert(piece, peer.clone());
+		}
+	}
+
+	// peer went away or didn't send blocks in time, let someone else try
+	pub fn release(&mut self, peer: &PeerAddress) {
+		self.assigned.retain(|_, assigned| assigned != peer);
+	}
+
+	fn strike(&mut self, peer: &PeerAddress) -> bool {
+		let strikes = self.strikes.entry(peer.ip()).or_insert(0);
+		*strikes += 1;
+		*strikes >= MAX_STRIKES
+	}
+
+	fn ban(&mut self, peers: Vec<PeerAddress>) -> Vec<PeerAddress> {
+		for peer in &peers {
+			info!("Banning {:?} for sending corrupt data", peer);
+			self.banned.insert(peer.ip());
+		}
+		peers
+	}
+}
+
+
+#[cfg(test)]
+mod test {
+	use std::net::Ipv4Addr;
+	use downloader::PeerAddress;
+	use super::BanList;
+
+	fn peer(last: u8) -> PeerAddress {
+		PeerAddress::new(Ipv4Addr::new(10, 0, 0, last), 6881)
+	}
+
+	#[test]
+	fn single_source_is_banned() {
+		let mut bans = BanList::new();
+		assert_eq!(bans.piece_failed(3, vec![Some(peer(1))]), vec![peer(1)]);
+		assert!(bans.is_banned(&PeerAddress::new(Ipv4Addr::new(10, 0, 0, 1), 1234)));
+		assert!(!bans.is_banned(&peer(2)));
+	}
+
+	#[test]
+	fn failed_piece_is_downloaded_from_one_peer() {
+		let mut bans = BanList::new();
+		assert!(bans.piece_failed(3, vec![Some(peer(1)), Some(peer(2))]).is_empty());
+		assert!(bans.may_request(3, &peer(2)));
+		bans.piece_requested(3, &peer(2));
+		assert!(!bans.may_request(3, &peer(1)));
+		assert!(bans.may_request(4, &peer(1)));
+
+		// peer 2 sent correct piece on its own, so peer 1 is to blame
+		assert_eq!(bans.piece_passed(3, vec![Some(peer(2))]), vec![peer(1)]);
+		assert!(bans.is_banned(&peer(1)));
+		assert!(!bans.is_banned(&peer(2)));
+		assert!(bans.may_request(3, &peer(1)));
+	}
+
+	#[test]
+	fn assigned_peer_fails_again() {
+		let mut bans = BanList::new();
+		bans.piece_failed(3, vec![Some(peer(1)), Some(peer(2)), Some(peer(3))]);
+		bans.piece_requested(3, &peer(3));
+		bans.release(&peer(3));
+		bans.piece_requested(3, &peer(1));
+		assert!(bans.may_request(3, &peer(1)) && !bans.may_request(3, &peer(3)));
+		assert_eq!(bans.piece_failed(3, vec![Some(peer(1))]), vec![peer(1)]);
+		assert!(!bans.is_banned(&peer(2)) && !bans.is_banned(&peer(3)));
+	}
+
+	#[test]
+	fn unknown_blocks_are_suspects() {
+		let mut bans = BanList::new();
+		// blocks restored on resume were in the piece too, peer 1 may be innocent
+		assert!(bans.piece_failed(3, vec![Some(peer(1)), None]).is_empty());
+		assert!(!bans.is_banned(&peer(1)));
+
+		// piece passed when downloaded from peer 1 alone, restored data was bad
+		bans.piece_requested(3, &peer(1));
+		assert!(bans.piece_passed(3, vec![Some(peer(1))]).is_empty());
+		assert!(!bans.is_banned(&peer(1)));
+
+		// nobody to blame for a piece of restored blocks only
+		assert!(bans.piece_failed(4, vec![None]).is_empty());
+	}
+}
@@ -0,0 +1,178 @@
+use std::time::{Duration, Instant};
+use downloader::PeerAddress;
+use downloader::peer::Peer;
+
+
+const ROUND_INTERVAL: u64 = 10; // seconds
+// optimistic unchoke is moved to another peer every this many rounds
+const OPTIMISTIC_ROUNDS: usize = 3;
+
+pub struct Choker {
+	slots: usize,
+	last_round: Instant,
+	round: usize,
+	optimistic: Option<PeerAddress>,
+}
+
+impl Choker {
+	pub fn new(slots: usize) -> Choker {
+		Choker {
+			slots: slots,
+			// make first round happen as soon as we have peers
+			last_round: Instant::now() - Duration::from_secs(ROUND_INTERVAL),
+			round: 0,
+			optimistic: None,
+		}
+	}
+
+	// Unchokes peers that give us the best download rate (or, when we are
+	// seeding, the ones that take data from us fastest), plus one randomly
+	// picked peer so that new peers get a chance to prove themselves.
+	// Returns whether choking round happened.
+	pub fn update(&mut self, peers: &mut [Peer], seeding: bool) -> bool {
+		let now = Instant::now();
+		if peers.len() == 0 {
+			return false;
+		}
+		if now - self.last_round < Duration::from_secs(ROUND_INTERVAL) {
+			self.fill_free_slots(peers);
+			return false;
+		}
+		self.last_round = now;
+
+		let mut rates = peers.iter_mut()
+			.enumerate()
+			.map(|(index, peer)| {
+				let (down, up) = peer.take_transfer_stats();
+				let rate = if seeding { up } else { down };
+				(index, rate)
+			})
+			.collect::<Vec<_>>();
+
+		rates.retain(|&(index, _)| peers[index].interested());
+		rates.sort_by(|a, b| b.1.cmp(&a.1));
+		let mut unchoked = vec![false; peers.len()];
+		for &(index, _) in rates.iter().take(self.slots) {
+			unchoked[index] = true;
+		}
+
+		// optimistic unchoke is wasted on a peer that got a slot anyway
+		let optimistic_valid = match self.optimistic {
+			Some(ref address) => peers.iter()
+				.enumerate()
+				.any(|(index, p)| p.address() == address && !unchoked[index]),
+			None => false,
+		};
+		if self.round % OPTIMISTIC_ROUNDS == 0 || !optimistic_valid {
+			self.optimistic = pick_optimistic(peers, &unchoked);
+		}
+		self.round += 1;
+
+		for (index, peer) in peers.iter_mut().enumerate() {
+			if !peer.is_connected() {
+				// nothing can be sent before our bitfield
+				continue;
+			}
+			let optimistic = self.optimistic.as_ref() == Some(peer.address());
+			peer.set_choking(!(unchoked[index] || optimistic));
+		}
+		true
+	}
+
+	// Peers that became interested between rounds don't have to wait
+	// for the next one while there are unused slots.
+	fn fill_free_slots(&mut self, peers: &mut [Peer]) {
+		let mut free = (self.slots + 1).saturating_sub(
+			peers.iter().filter(|p| p.is_connected() && !p.am_choked()).count());
+		for peer in peers.iter_mut() {
+			if free == 0 {
+				break;
+			}
+			if peer.is_connected() && peer.interested() && peer.am_choked() {
+				peer.set_choking(false);
+				if self.optimistic.is_none() {
+					self.optimistic = Some(peer.address().clone());
+				}
+				free -= 1;
+			}
+		}
+	}
+}
+
+fn pick_optimistic(peers: &[Peer], unchoked: &[bool]) -> Option<PeerAddress> {
+	let candidates = peers.iter()
+		.enumerate()
+		.filter(|&(index, p)| p.is_connected() && p.interested() && !unchoked[index])
+		.map(|(_, p)| p)
+		.collect::<Vec<_>>();
+	if candidates.len() == 0 {
+		None
+	} else {
+		let index = ::rand::random::<usize>() % candidates.len();
+		Some(candidates[index].address().clone())
+	}
+}
+
+
+#[cfg(test)]
+mod test {
+	use std::cell::RefCell;
+	use std::rc::Rc;
+	use std::time::{Duration, Instant};
+	use downloader::connection::Message;
+	use downloader::connection::fake::{Wire, connected_peer, receive};
+	use downloader::peer::Peer;
+	use super::{Choker, ROUND_INTERVAL};
+
+	fn interested_peers(count: u8) -> (Vec<Peer>, Vec<Rc<RefCell<Wire>>>) {
+		let mut peers = Vec::new();
+		let mut wires = Vec::new();
+		for last in 1..(count + 1) {
+			let (mut peer, wire) = connected_peer(last, 4, false);
+			receive(&mut peer, &wire, Message::Interested);
+			peers.push(peer);
+			wires.push(wire);
+		}
+		(peers, wires)
+	}
+
+	fn next_round(choker: &mut Choker) {
+		choker.last_round = Instant::now() - Duration::from_secs(ROUND_INTERVAL);
+	}
+
+	#[test]
+	fn fastest_peers_and_optimistic_unchoke() {
+		let (mut peers, wires) = interested_peers(4);
+		let mut choker = Choker::new(1);
+		for _ in 0..10 {
+			// second peer gives us the most
+			receive(&mut peers[1], &wires[1], Message::Piece(0, 0, vec![0; 100]));
+			next_round(&mut choker);
+			assert!(choker.update(&mut peers, false));
+			assert!(!peers[1].am_choked());
+			// optimistic unchoke always goes to someone else
+			let unchoked = peers.iter().filter(|peer| !peer.am_choked()).count();
+			assert_eq!(unchoked, 2);
+		}
+	}
+
+	#[test]
+	fn new_peers_get_free_slots() {
+		let (mut peers, wires) = interested_peers(4);
+		let mut choker = Choker::new(2);
+		assert!(choker.update(&mut peers[..1], false));
+		assert!(!peers[0].am_choked());
+
+		// no round is due, but slots are free
+		assert!(!choker.update(&mut peers, false));
+		let unchoked = peers.iter().filter(|peer| !peer.am_choked()).count();
+		assert_eq!(unchoked, 3);
+
+		// peers that are not interested don't take slots
+		receive(&mut peers[0], &wires[0], Message::NotInterested);
+		next_round(&mut choker);
+		assert!(choker.update(&mut peers, false));
+		assert!(peers[0].am_choked());
+		assert!(peers[1..].iter().all(|peer| !peer.am_choked()));
+	}
+}
@@ -1,46 +1,41 @@
-use std::io;
+use std::cell::RefCell;
+use std::collections::VecDeque;
 use std::io::{Read, Write, ErrorKind};
-use std::net::TcpStream;
-use std::sync::mpsc;
-use std::sync::mpsc::{Sender, Receiver, TryRecvError};
-use std::thread;
-use std::time::Duration;
+use std::net::Shutdown;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+use mio::net::TcpStream;
 use downloader::{DownloaderId, PeerAddress};
 use downloader::connection::*;
 
 
+// peer has this long to connect and send its handshake
+const HANDSHAKE_TIMEOUT: u64 = 20; // seconds
+const MAX_MESSAGE_LENGTH: usize = 1 << 20;
+
+// Handle of a connection that is driven by the event loop. Messages
+// are written out right away if the socket can take them, received
+// ones wait here until asked for.
 pub struct BtConnection {
-	sender: Sender<OutMessage>,
-	receiver: Receiver<InMessage>,
-	thread: thread::JoinHandle<()>,
+	// none if connecting failed right away
+	socket: Option<Rc<RefCell<Socket>>>,
+	error: Option<Error>,
 	alive: bool,
 }
 
 impl BtConnection {
-	pub fn new(handshake: HandshakeInfo, peer: PeerAddress) -> BtConnection {
-		let (send1, recv1) = mpsc::channel();
-		let (send2, recv2) = mpsc::channel();
-		let send = send1.clone();
-		let thread = thread::spawn(move || {
-			let result = Internal::new(handshake, peer.clone(), send1, recv2)
-				.map_err(Error::IoError)
-				.and_then(|mut con| con.run());
-			match result {
-				Ok(()) => {
-					debug!("Connection to {:?} closed.", peer);
-				}
-				Err(e) => {
-					debug!("Connection to {:?} closed: {:?}", peer, e);
-					// also push that error to channel
-					let _ = send.send(InMessage::Error(e));
-				}
-			}
-		});
+	pub fn new(socket: Rc<RefCell<Socket>>) -> BtConnection {
+		BtConnection {
+			socket: Some(socket),
+			error: None,
+			alive: true,
+		}
+	}
 
+	pub fn failed(error: Error) -> BtConnection {
 		BtConnection {
-			sender: send2,
-			receiver: recv1,
-			thread: thread,
+			socket: None,
+			error: Some(error),
 			alive: true,
 		}
 	}
@@ -48,44 +43,39 @@ impl BtConnection {
 
 impl Connection for BtConnection {
 	fn send(&mut self, msg: Message) {
-		// we don't care if the message is not sent
-		let _ = self.sender.send(OutMessage::Normal(msg));
+		if let Some(ref socket) = self.socket {
+			socket.borrow_mut().send(RawMessage::from_message(msg));
+		}
 	}
 
 	fn receive(&mut self) -> Option<InMessage> {
-		match self.receiver.try_recv() {
-			Ok(e @ InMessage::Error(_)) => {
-				self.alive = false;
-				Some(e)
-			}
-			Ok(msg) => {
-				Some(msg)
-			}
-			Err(TryRecvError::Empty) => {
-				None
-			}
-			Err(TryRecvError::Disconnected) => {
-				Some(InMessage::Error(Error::Closed))
-			}
+		let msg = match self.socket {
+			Some(ref socket) => socket.borrow_mut().received.pop_front(),
+			None => self.error.take().map(InMessage::Error),
+		};
+		if let Some(InMessage::Error(_)) = msg {
+			self.alive = false;
 		}
+		msg
 	}
 
 	fn close(&mut self) {
-		let _ = self.sender.send(OutMessage::Close);
+		if let Some(ref socket) = self.socket {
+			socket.borrow_mut().close();
+		}
 		self.alive = false;
-		// do I really need to join the thread, or can I just let it run loose?
-		// does not work right now anyways - self is only borrowed
-		// let _ = self.thread.join();
 	}
 
 	fn is_alive(&self) -> bool {
 		self.alive
 	}
-}
 
-enum OutMessage {
-	Normal(Message),
-	Close,
+	fn pending_send_bytes(&self) -> usize {
+		match self.socket {
+			Some(ref socket) => socket.borrow().send_buffer.len(),
+			None => 0,
+		}
+	}
 }
 
 enum RawMessage {
@@ -99,6 +89,12 @@ enum RawMessage {
 	Request(usize, usize, usize),
 	Piece(usize, usize, Vec<u8>),
 	Cancel(usize, usize, usize),
+	SuggestPiece(usize),
+	HaveAll,
+	HaveNone,
+	RejectRequest(usize, usize, usize),
+	AllowedFast(usize),
+	Extended(u8, Vec<u8>),
 }
 
 impl RawMessage {
@@ -113,380 +109,435 @@ impl RawMessage {
 			Message::Request(piece, off, len) => RawMessage::Request(piece, off, len),
 			Message::Piece(piece, off, data) => RawMessage::Piece(piece, off, data),
 			Message::Cancel(piece, off, len) => RawMessage::Cancel(piece, off, len),
+			Message::SuggestPiece(piece) => RawMessage::SuggestPiece(piece),
+			Message::HaveAll => RawMessage::HaveAll,
+			Message::HaveNone => RawMessage::HaveNone,
+			Message::RejectRequest(piece, off, len) => RawMessage::RejectRequest(piece, off, len),
+			Message::AllowedFast(piece) => RawMessage::AllowedFast(piece),
+			Message::Extended(id, payload) => RawMessage::Extended(id, payload),
 		}
 	}
+
+	fn into_message(self, peer: &PeerAddress) -> Option<Message> {
+		let msg = match self {
+			RawMessage::KeepAlive => {
+				debug!("Got KeepAlive from {:?}", peer);
+				return None;
+			}
+			RawMessage::Choke => {
+				debug!("Got Choke from {:?}", peer);
+				Message::Choke
+			}
+			RawMessage::Unchoke => {
+				debug!("Got Unchoke from {:?}", peer);
+				Message::Unchoke
+			}
+			RawMessage::Interested => {
+				debug!("Got Interested from {:?}", peer);
+				Message::Interested
+			}
+			RawMessage::NotInterested => {
+				debug!("Got NotInterested from {:?}", peer);
+				Message::NotInterested
+			}
+			RawMessage::Have(piece) => {
+				debug!("Got Have({}) from {:?}", piece, peer);
+				Message::Have(piece)
+			}
+			RawMessage::Bitfield(bits) => {
+				debug!("Got Bitfield({} bits) from {:?}", bits.len() * 8, peer);
+				Message::Bitfield(bits)
+			}
+			RawMessage::Request(piece, offset, len) => {
+				debug!("Got Request({}, {}, {}) from {:?}", piece, offset, len, peer);
+				Message::Request(piece, offset, len)
+			}
+			RawMessage::Piece(piece, offset, bytes) => {
+				debug!("Got Piece({}, {}, {} bytes) from {:?}", piece, offset, bytes.len(), peer);
+				Message::Piece(piece, offset, bytes)
+			}
+			RawMessage::Cancel(piece, offset, len) => {
+				debug!("Got Cancel({}, {}, {}) from {:?}", piece, offset, len, peer);
+				Message::Cancel(piece, offset, len)
+			}
+			RawMessage::SuggestPiece(piece) => {
+				debug!("Got SuggestPiece({}) from {:?}", piece, peer);
+				Message::SuggestPiece(piece)
+			}
+			RawMessage::HaveAll => {
+				debug!("Got HaveAll from {:?}", peer);
+				Message::HaveAll
+			}
+			RawMessage::HaveNone => {
+				debug!("Got HaveNone from {:?}", peer);
+				Message::HaveNone
+			}
+			RawMessage::RejectRequest(piece, offset, len) => {
+				debug!("Got RejectRequest({}, {}, {}) from {:?}", piece, offset, len, peer);
+				Message::RejectRequest(piece, offset, len)
+			}
+			RawMessage::AllowedFast(piece) => {
+				debug!("Got AllowedFast({}) from {:?}", piece, peer);
+				Message::AllowedFast(piece)
+			}
+			RawMessage::Extended(id, payload) => {
+				debug!("Got Extended({}, {} bytes) from {:?}", id, payload.len(), peer);
+				Message::Extended(id, payload)
+			}
+		};
+		Some(msg)
+	}
 }
 
-struct Internal {
-	sender: Sender<InMessage>,
-	receiver: Receiver<OutMessage>,
-	handshake: HandshakeInfo,
+// State of a single peer socket, shared by the event loop
+// doing the IO and the connection handle.
+pub struct Socket {
 	stream: TcpStream,
+	handshake: HandshakeInfo,
+	peer: PeerAddress,
+	// peer connected to us
+	incoming: bool,
+	connected: bool,
+	handshake_done: bool,
+	started: Instant,
 	recv_buffer: Vec<u8>,
-	peer: String,
+	send_buffer: Vec<u8>,
+	// messages sent before the handshake is complete
+	queued: Vec<RawMessage>,
+	received: VecDeque<InMessage>,
+	closed: bool,
 }
 
-impl Internal {
-	fn new(
-			handshake: HandshakeInfo,
-			peer: PeerAddress,
-			send: Sender<InMessage>,
-			recv: Receiver<OutMessage>) -> Result<Internal, io::Error> {
-		debug!("Connecting to peer: {:?}", peer);
-		let socket = try!(TcpStream::connect(peer.clone()));
-		debug!("Connected to peer: {:?}", peer);
-		Ok(Internal {
-			sender: send,
-			receiver: recv,
+impl Socket {
+	pub fn new(stream: TcpStream, handshake: HandshakeInfo, peer: PeerAddress, incoming: bool) -> Socket {
+		let mut socket = Socket {
+			stream: stream,
 			handshake: handshake,
-			stream: socket,
+			peer: peer,
+			incoming: incoming,
+			// accepted sockets are connected already
+			connected: incoming,
+			handshake_done: false,
+			started: Instant::now(),
 			recv_buffer: Vec::new(),
-			peer: format!("{:?}", peer),
-		})
+			send_buffer: Vec::new(),
+			queued: Vec::new(),
+			received: VecDeque::new(),
+			closed: false,
+		};
+		// when we are the one who connected we introduce ourselves first,
+		// otherwise we wait to learn which torrent the peer wants
+		if !incoming {
+			socket.push_handshake();
+		}
+		socket
 	}
 
-	fn send(&mut self, msg: InMessage) {
-		// we don't care if the message is not sent
-		let _ = self.sender.send(msg);
+	pub fn stream(&self) -> &TcpStream {
+		&self.stream
 	}
 
-	fn receive(&mut self) -> Option<OutMessage> {
-		match self.receiver.try_recv() {
-			Ok(msg) => Some(msg),
-			Err(TryRecvError::Empty) => None,
-			Err(TryRecvError::Disconnected) => Some(OutMessage::Close),
-		}
+	pub fn is_closed(&self) -> bool {
+		self.closed
 	}
 
-	fn next_message_length(&self) -> Option<u32> {
-		if self.recv_buffer.len() >= 4 {
-			Some(u32_from_bytes(&self.recv_buffer[0..4]))
-		} else {
-			None
+	// Does whatever IO the socket is ready for. Called by event loop
+	// when something happened to the socket.
+	pub fn ready(&mut self) {
+		if self.closed {
+			return;
 		}
-	}
-	
-	fn decode_raw_message(&self, slice: &[u8]) -> Result<RawMessage, Error> {
-		if slice.len() == 0 {
-			return Ok(RawMessage::KeepAlive);
-		}
-		match slice[0] {
-			0 => {
-				if slice.len() == 1 { 
-					Ok(RawMessage::Choke)
-				} else {
-					Err(Error::BadMessage)
-				}
-			}
-			1 => {
-				if slice.len() == 1 { 
-					Ok(RawMessage::Unchoke)
-				} else {
-					Err(Error::BadMessage)
-				}
-			}
-			2 => {
-				if slice.len() == 1 { 
-					Ok(RawMessage::Interested)
-				} else {
-					Err(Error::BadMessage)
-				}
-			}
-			3 => {
-				if slice.len() == 1 { 
-					Ok(RawMessage::NotInterested)
-				} else {
-					Err(Error::BadMessage)
-				}
-			}
-			4 => {
-				if slice.len() == 5 {
-					Ok(RawMessage::Have(usize_from_bytes(&slice[1..5])))
-				} else {
-					Err(Error::BadMessage)
-				}
-			}
-			5 => {
-				Ok(RawMessage::Bitfield(slice[1..].to_vec()))
-			}
-			6 => {
-				if slice.len() == 13 {
-					let piece = usize_from_bytes(&slice[1..5]);
-					let offset = usize_from_bytes(&slice[5..9]);
-					let length = usize_from_bytes(&slice[9..13]);
-					Ok(RawMessage::Request(piece, offset, length))
-				} else {
-					Err(Error::BadMessage)
-				}
-			}
-			7 => {
-				if slice.len() >= 9 {
-					let piece = usize_from_bytes(&slice[1..5]);
-					let offset = usize_from_bytes(&slice[5..9]);
-					let data = slice[9..].to_vec();
-					Ok(RawMessage::Piece(piece, offset, data))
-				} else {
-					Err(Error::BadMessage)
+		if !self.connected {
+			match self.stream.take_error() {
+				Ok(None) => {
+					debug!("Connected to peer: {:?}", self.peer);
+					self.connected = true;
 				}
-			}
-			8 => {
-				if slice.len() == 13 {
-					let piece = usize_from_bytes(&slice[1..5]);
-					let offset = usize_from_bytes(&slice[5..9]);
-					let length = usize_from_bytes(&slice[9..13]);
-					Ok(RawMessage::Cancel(piece, offset, length))
-				} else {
-					Err(Error::BadMessage)
+				Ok(Some(e)) | Err(e) => {
+					self.fail(Error::IoError(e));
+					return;
 				}
 			}
-			x => {
-				debug!("Received bad message from {}: type is {}", self.peer, x);
-				Err(Error::BadMessage)
-			}
+		}
+		let result = self.receive_bytes()
+			.and_then(|_| self.process_received())
+			.and_then(|_| self.flush());
+		if let Err(e) = result {
+			self.fail(e);
 		}
 	}
 
-	fn receive_bytes(&mut self) -> Result<(), Error> {
-		match self.stream.read_to_end(&mut self.recv_buffer) {
-			Ok(_) => {
-				// connection was closed
-				Err(Error::Closed)
-			}
-			Err(ref e) if
-				e.kind() == ErrorKind::TimedOut ||
-				e.kind() == ErrorKind::WouldBlock => {
-				// read timed out, everything is fine
-				Ok(())
-			}
-			Err(e) => {
-				debug!("Error while reading from {}: {:?}", self.peer, e);
-				Err(Error::IoError(e))
-			}
+	pub fn check_timeout(&mut self) {
+		let timeout = Duration::from_secs(HANDSHAKE_TIMEOUT);
+		if !self.closed && !self.handshake_done && self.started.elapsed() >= timeout {
+			self.fail(Error::NoHandshake);
 		}
 	}
 
-	fn remove_bytes(&mut self, count: usize) {
-		// drain leading bytes, after dropping iterator vector
-		// will push following bytes to front 
-		for _ in self.recv_buffer.drain(0..count) { }
+	pub fn fail(&mut self, e: Error) {
+		debug!("Connection to {:?} closed: {:?}", self.peer, e);
+		self.received.push_back(InMessage::Error(e));
+		self.close();
 	}
 
-	fn get_raw_message(&mut self) -> Result<Option<RawMessage>, Error> {
-		let len = match self.next_message_length() {
-			Some(len) if len < (1 << 20) => {
-				len as usize
-			}
-			Some(len) => {
-				debug!("Peer {} send too long message: {}", self.peer, len);
-				return Err(Error::BadMessage);
-			}
-			None => {
-				return Ok(None);
-			}
-		};
-
-		if self.recv_buffer.len() >= len + 4 {
-			let message = {
-				let message_data = &self.recv_buffer[4..(4 + len)];
-				try!(self.decode_raw_message(message_data))
-			};
-			// remove message that was just decoded
-			self.remove_bytes(len + 4);
-			Ok(Some(message))
-		} else {
-			Ok(None)
+	pub fn close(&mut self) {
+		if !self.closed {
+			self.closed = true;
+			let _ = self.stream.shutdown(Shutdown::Both);
 		}
 	}
 
-	fn write_bytes(&mut self, bytes: &[u8]) -> Result<(), Error> {
-		match self.stream.write_all(bytes) {
-			Ok(_) => Ok(()),
-			Err(e) => Err(Error::IoError(e)),
+	fn send(&mut self, msg: RawMessage) {
+		if self.closed {
+			return;
+		}
+		if !self.handshake_done {
+			self.queued.push(msg);
+			return;
+		}
+		encode_message(msg, &mut self.send_buffer);
+		if let Err(e) = self.flush() {
+			self.fail(e);
 		}
 	}
 
-	fn write_message(&mut self, msg: RawMessage) -> Result<(), Error> {
-		let write_result = match msg {
-			RawMessage::KeepAlive => {
-				self.write_bytes(&[0, 0, 0, 0])
-			}
-			RawMessage::Choke => {
-				self.write_bytes(&[0, 0, 0, 1, 0])
-			}
-			RawMessage::Unchoke => {
-				self.write_bytes(&[0, 0, 0, 1, 1])
-			}
-			RawMessage::Interested => {
-				self.write_bytes(&[0, 0, 0, 1, 2])
-			}
-			RawMessage::NotInterested => {
-				self.write_bytes(&[0, 0, 0, 1, 3])
-			}
-			RawMessage::Have(index) => {
-				let index = bytes_from_usize(index);
-				self.write_bytes(&bytes_from_u32(5))
-					.and_then(|_| self.write_bytes(&[4]))
-					.and_then(|_| self.write_bytes(&index))
-			}
-			RawMessage::Bitfield(bits) => {
-				let len = bits.len() + 1;
-				self.write_bytes(&bytes_from_usize(len))
-					.and_then(|_| self.write_bytes(&[5]))
-					.and_then(|_| self.write_bytes(&bits))
-			}
-			RawMessage::Request(piece, offset, len) => {
-				let piece = bytes_from_usize(piece);
-				let offset = bytes_from_usize(offset);
-				let len = bytes_from_usize(len);
-				self.write_bytes(&bytes_from_u32(13))
-					.and_then(|_| self.write_bytes(&[6]))
-					.and_then(|_| self.write_bytes(&piece))
-					.and_then(|_| self.write_bytes(&offset))
-					.and_then(|_| self.write_bytes(&len))
-			}
-			RawMessage::Piece(piece, offset, bytes) => {
-				let piece = bytes_from_usize(piece);
-				let offset = bytes_from_usize(offset);
-				let len = bytes.len() + 9;
-				self.write_bytes(&bytes_from_usize(len))
-					.and_then(|_| self.write_bytes(&[7]))
-					.and_then(|_| self.write_bytes(&piece))
-					.and_then(|_| self.write_bytes(&offset))
-					.and_then(|_| self.write_bytes(&bytes))
-			}
-			RawMessage::Cancel(piece, offset, len) => {
-				let piece = bytes_from_usize(piece);
-				let offset = bytes_from_usize(offset);
-				let len = bytes_from_usize(len);
-				self.write_bytes(&bytes_from_u32(13))
-					.and_then(|_| self.write_bytes(&[8]))
-					.and_then(|_| self.write_bytes(&piece))
-					.and_then(|_| self.write_bytes(&offset))
-					.and_then(|_| self.write_bytes(&len))
-			}
-		};
-		try!(write_result);
-		self.stream.flush().map_err(Error::IoError)
+	fn push_handshake(&mut self) {
+		self.send_buffer.extend_from_slice(b"\x13BitTorrent protocol");
+		self.send_buffer.extend_from_slice(&self.handshake.reserved);
+		self.send_buffer.extend_from_slice(&self.handshake.info_hash);
+		self.send_buffer.extend_from_slice(&self.handshake.id.0);
 	}
 
-	fn send_handshake(&mut self) -> Result<(), Error> {
-		let mut handshake = [0_u8; 68];
-		for i in 0..8_usize {
-			handshake[i + 20] = 0;
+	// writes as much as socket takes, the rest waits
+	// until it becomes writable again
+	fn flush(&mut self) -> Result<(), Error> {
+		if !self.connected {
+			return Ok(());
 		}
-		for i in 0..20_usize {
-			handshake[i] = b"\x13BitTorrent protocol"[i];
-			handshake[i + 28] = self.handshake.info_hash[i];
-			handshake[i + 48] = self.handshake.id.0[i];
+		while self.send_buffer.len() > 0 {
+			match self.stream.write(&self.send_buffer) {
+				Ok(0) => return Err(Error::Closed),
+				Ok(written) => {
+					self.send_buffer.drain(0..written);
+				}
+				Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+				Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+				Err(e) => return Err(Error::IoError(e)),
+			}
 		}
-		try!(self.write_bytes(&handshake));
-		try!(self.stream.flush().map_err(Error::IoError));
-		debug!("Sent handshake to {}", self.peer);
 		Ok(())
 	}
 
-	fn check_handshake(&mut self) -> Result<Option<HandshakeInfo>, Error> {
-		if self.recv_buffer.len() < 68 {
-			Ok(None)
-		} else {
-			if &self.recv_buffer[0..20] == b"\x13BitTorrent protocol" {
-				let mut hash = [0; 20];
-				let mut id = DownloaderId([0; 20]);
-				for i in 0..20 { hash[i] = self.recv_buffer[28 + i]; }
-				for i in 0..20 { id.0[i] = self.recv_buffer[48 + i]; }
-				self.remove_bytes(68);
-				debug!("Completed handshake with {}", self.peer);
-				Ok(Some(HandshakeInfo::new(hash, id)))
-			} else {
-				Err(Error::BadHandshake)
+	// socket won't tell again that there is something to read,
+	// so everything must be read now
+	fn receive_bytes(&mut self) -> Result<(), Error> {
+		let mut buffer = [0_u8; 0x4000];
+		loop {
+			match self.stream.read(&mut buffer) {
+				Ok(0) => return Err(Error::Closed),
+				Ok(size) => self.recv_buffer.extend_from_slice(&buffer[..size]),
+				Err(ref e) if e.kind() == ErrorKind::WouldBlock => return Ok(()),
+				Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+				Err(e) => {
+					debug!("Error while reading from {:?}: {:?}", self.peer, e);
+					return Err(Error::IoError(e));
+				}
 			}
 		}
 	}
 
-	fn run(&mut self) -> Result<(), Error> {
-		let read_timeout = Duration::from_millis(100);
-		try!(self.stream.set_read_timeout(Some(read_timeout))
-			.map_err(Error::IoError));
-
-		try!(self.send_handshake());
-
-		let mut checks = 0;
-		loop {
-			try!(self.receive_bytes());
+	fn process_received(&mut self) -> Result<(), Error> {
+		if !self.handshake_done {
 			match try!(self.check_handshake()) {
 				Some(handshake) => {
-					self.send(InMessage::Handshake(handshake));
-					break;
-				}
-				None => {
-					checks += 1;
-					if checks > 20 {
-						return Err(Error::NoHandshake);
+					if self.incoming {
+						if handshake.info_hash != self.handshake.info_hash {
+							debug!("Peer {:?} wants torrent we don't have", self.peer);
+							return Err(Error::BadHandshake);
+						}
+						self.push_handshake();
+					}
+					self.handshake_done = true;
+					self.received.push_back(InMessage::Handshake(handshake));
+					for msg in ::std::mem::replace(&mut self.queued, Vec::new()) {
+						encode_message(msg, &mut self.send_buffer);
 					}
 				}
+				None => return Ok(()),
 			}
-			thread::sleep(Duration::from_millis(1000));
 		}
 
-		loop {
-			try!(self.receive_bytes());
-			match try!(self.get_raw_message()) {
-				Some(RawMessage::KeepAlive) => {
-					debug!("Got KeepAlive from {}", self.peer);
-					// TODO: check for timeouts & shit
-					// also send my own keepalives
-				}
-				Some(RawMessage::Choke) => {
-					debug!("Got Choke from {}", self.peer);
-					self.send(InMessage::Normal(Message::Choke));
-				}
-				Some(RawMessage::Unchoke) => {
-					debug!("Got Unchoke from {}", self.peer);
-					self.send(InMessage::Normal(Message::Unchoke));
-				}
-				Some(RawMessage::Interested) => {
-					debug!("Got Interested from {}", self.peer);
-					self.send(InMessage::Normal(Message::Interested));
-				}
-				Some(RawMessage::NotInterested) => {
-					debug!("Got NotInterested from {}", self.peer);
-					self.send(InMessage::Normal(Message::NotInterested));
-				}
-				Some(RawMessage::Have(piece)) => {
-					debug!("Got Have({}) from {}", piece, self.peer);
-					self.send(InMessage::Normal(Message::Have(piece)));
-				}
-				Some(RawMessage::Bitfield(bits)) => {
-					debug!("Got Bitfield({} bits) from {}", bits.len() * 8, self.peer);
-					self.send(InMessage::Normal(Message::Bitfield(bits)));
-				}
-				Some(RawMessage::Request(piece, offset, len)) => {
-					debug!("Got Request({}, {}, {}) from {}", piece, offset, len, self.peer);
-					self.send(InMessage::Normal(Message::Request(piece, offset, len)));
-				}
-				Some(RawMessage::Piece(piece, offset, bytes)) => {
-					debug!("Got Piece({}, {}, {} bytes) from {}", piece, offset, bytes.len(), self.peer);
-					self.send(InMessage::Normal(Message::Piece(piece, offset, bytes)));
-				}
-				Some(RawMessage::Cancel(piece, offset, len)) => {
-					debug!("Got Cancel({}, {}, {}) from {}", piece, offset, len, self.peer);
-					self.send(InMessage::Normal(Message::Cancel(piece, offset, len)));
-				}
-				None => { }
+		while let Some(message) = try!(self.next_message()) {
+			if let Some(message) = message.into_message(&self.peer) {
+				self.received.push_back(InMessage::Normal(message));
 			}
+		}
+		Ok(())
+	}
 
-			match self.receive() {
-				Some(OutMessage::Close) => {
-					return Err(Error::Closed);
-				}
-				Some(OutMessage::Normal(msg)) => {
-					let raw = RawMessage::from_message(msg);
-					try!(self.write_message(raw));
-				}
-				None => { }
-			}
+	fn check_handshake(&mut self) -> Result<Option<HandshakeInfo>, Error> {
+		if self.recv_buffer.len() < 68 {
+			return Ok(None);
 		}
+		if &self.recv_buffer[0..20] != b"\x13BitTorrent protocol" {
+			return Err(Error::BadHandshake);
+		}
+		let mut hash = [0; 20];
+		let mut id = DownloaderId([0; 20]);
+		let mut reserved = [0; 8];
+		reserved.copy_from_slice(&self.recv_buffer[20..28]);
+		hash.copy_from_slice(&self.recv_buffer[28..48]);
+		id.0.copy_from_slice(&self.recv_buffer[48..68]);
+		self.recv_buffer.drain(0..68);
+		debug!("Completed handshake with {:?}", self.peer);
+		Ok(Some(HandshakeInfo {
+			info_hash: hash,
+			id: id,
+			reserved: reserved,
+		}))
 	}
+
+	fn next_message(&mut self) -> Result<Option<RawMessage>, Error> {
+		if self.recv_buffer.len() < 4 {
+			return Ok(None);
+		}
+		let len = usize_from_bytes(&self.recv_buffer[0..4]);
+		if len >= MAX_MESSAGE_LENGTH {
+			debug!("Peer {:?} send too long message: {}", self.peer, len);
+			return Err(Error::BadMessage);
+		}
+		if self.recv_buffer.len() < len + 4 {
+			return Ok(None);
+		}
+		let message = try!(decode_message(&self.recv_buffer[4..(4 + len)], &self.peer));
+		// remove message that was just decoded
+		self.recv_buffer.drain(0..(len + 4));
+		Ok(Some(message))
+	}
+}
+
+fn decode_message(slice: &[u8], peer: &PeerAddress) -> Result<RawMessage, Error> {
+	if slice.len() == 0 {
+		return Ok(RawMessage::KeepAlive);
+	}
+	let message = match (slice[0], slice.len()) {
+		(0, 1) => RawMessage::Choke,
+		(1, 1) => RawMessage::Unchoke,
+		(2, 1) => RawMessage::Interested,
+		(3, 1) => RawMessage::NotInterested,
+		(4, 5) => RawMessage::Have(usize_from_bytes(&slice[1..5])),
+		(5, _) => RawMessage::Bitfield(slice[1..].to_vec()),
+		(6, 13) => {
+			let piece = usize_from_bytes(&slice[1..5]);
+			let offset = usize_from_bytes(&slice[5..9]);
+			let length = usize_from_bytes(&slice[9..13]);
+			RawMessage::Request(piece, offset, length)
+		}
+		(7, len) if len >= 9 => {
+			let piece = usize_from_bytes(&slice[1..5]);
+			let offset = usize_from_bytes(&slice[5..9]);
+			RawMessage::Piece(piece, offset, slice[9..].to_vec())
+		}
+		(8, 13) => {
+			let piece = usize_from_bytes(&slice[1..5]);
+			let offset = usize_from_bytes(&slice[5..9]);
+			let length = usize_from_bytes(&slice[9..13]);
+			RawMessage::Cancel(piece, offset, length)
+		}
+		(13, 5) => RawMessage::SuggestPiece(usize_from_bytes(&slice[1..5])),
+		(14, 1) => RawMessage::HaveAll,
+		(15, 1) => RawMessage::HaveNone,
+		(16, 13) => {
+			let piece = usize_from_bytes(&slice[1..5]);
+			let offset = usize_from_bytes(&slice[5..9]);
+			let length = usize_from_bytes(&slice[9..13]);
+			RawMessage::RejectRequest(piece, offset, length)
+		}
+		(17, 5) => RawMessage::AllowedFast(usize_from_bytes(&slice[1..5])),
+		(20, len) if len >= 2 => RawMessage::Extended(slice[1], slice[2..].to_vec()),
+		(x, len) => {
+			debug!("Received bad message from {:?}: type is {}, length {}", peer, x, len);
+			return Err(Error::BadMessage);
+		}
+	};
+	Ok(message)
+}
+
+fn encode_message(msg: RawMessage, buffer: &mut Vec<u8>) {
+	match msg {
+		RawMessage::KeepAlive => {
+			buffer.extend_from_slice(&[0, 0, 0, 0]);
+		}
+		RawMessage::Choke => {
+			buffer.extend_from_slice(&[0, 0, 0, 1, 0]);
+		}
+		RawMessage::Unchoke => {
+			buffer.extend_from_slice(&[0, 0, 0, 1, 1]);
+		}
+		RawMessage::Interested => {
+			buffer.extend_from_slice(&[0, 0, 0, 1, 2]);
+		}
+		RawMessage::NotInterested => {
+			buffer.extend_from_slice(&[0, 0, 0, 1, 3]);
+		}
+		RawMessage::Have(index) => {
+			buffer.extend_from_slice(&bytes_from_u32(5));
+			buffer.push(4);
+			buffer.extend_from_slice(&bytes_from_usize(index));
+		}
+		RawMessage::Bitfield(bits) => {
+			buffer.extend_from_slice(&bytes_from_usize(bits.len() + 1));
+			buffer.push(5);
+			buffer.extend_from_slice(&bits);
+		}
+		RawMessage::Request(piece, offset, len) => {
+			encode_block_message(6, piece, offset, len, buffer);
+		}
+		RawMessage::Piece(piece, offset, bytes) => {
+			buffer.extend_from_slice(&bytes_from_usize(bytes.len() + 9));
+			buffer.push(7);
+			buffer.extend_from_slice(&bytes_from_usize(piece));
+			buffer.extend_from_slice(&bytes_from_usize(offset));
+			buffer.extend_from_slice(&bytes);
+		}
+		RawMessage::Cancel(piece, offset, len) => {
+			encode_block_message(8, piece, offset, len, buffer);
+		}
+		RawMessage::SuggestPiece(index) => {
+			buffer.extend_from_slice(&bytes_from_u32(5));
+			buffer.push(13);
+			buffer.extend_from_slice(&bytes_from_usize(index));
+		}
+		RawMessage::HaveAll => {
+			buffer.extend_from_slice(&[0, 0, 0, 1, 14]);
+		}
+		RawMessage::HaveNone => {
+			buffer.extend_from_slice(&[0, 0, 0, 1, 15]);
+		}
+		RawMessage::RejectRequest(piece, offset, len) => {
+			encode_block_message(16, piece, offset, len, buffer);
+		}
+		RawMessage::AllowedFast(index) => {
+			buffer.extend_from_slice(&bytes_from_u32(5));
+			buffer.push(17);
+			buffer.extend_from_slice(&bytes_from_usize(index));
+		}
+		RawMessage::Extended(id, payload) => {
+			buffer.extend_from_slice(&bytes_from_usize(payload.len() + 2));
+			buffer.push(20);
+			buffer.push(id);
+			buffer.extend_from_slice(&payload);
+		}
+	}
+}
+
+// request, cancel and reject all describe a block the same way
+fn encode_block_message(id: u8, piece: usize, offset: usize, len: usize, buffer: &mut Vec<u8>) {
+	buffer.extend_from_slice(&bytes_from_u32(13));
+	buffer.push(id);
+	buffer.extend_from_slice(&bytes_from_usize(piece));
+	buffer.extend_from_slice(&bytes_from_usize(offset));
+	buffer.extend_from_slice(&bytes_from_usize(len));
 }
 
 fn u32_from_bytes(slice: &[u8]) -> u32 {
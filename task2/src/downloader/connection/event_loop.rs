@@ -0,0 +1,247 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io;
+use std::rc::Rc;
+use std::time::Duration;
+use mio::{Poll, Events, Token, Ready, PollOpt, Evented, Registration, SetReadiness};
+use mio::net::TcpStream;
+use downloader::PeerAddress;
+use downloader::connection::{HandshakeInfo, Error};
+use downloader::connection::bt::{BtConnection, Socket};
+
+
+// shared by sockets that only need to wake the loop up,
+// their owners read them on their own
+const WAKE: Token = Token(0);
+
+// Wakes the loop up from other threads, e.g. when a worker has results
+// for us. Owner watches it with the loop and calls `reset` before taking
+// the results, so that anything sent after that point wakes the loop again.
+pub struct Waker {
+	registration: Registration,
+	readiness: SetReadiness,
+}
+
+impl Default for Waker {
+	fn default() -> Waker {
+		let (registration, readiness) = Registration::new2();
+		Waker {
+			registration: registration,
+			readiness: readiness,
+		}
+	}
+}
+
+impl Waker {
+	pub fn new() -> Waker {
+		Waker::default()
+	}
+
+	// for threads that send the results
+	pub fn handle(&self) -> WakeHandle {
+		WakeHandle(self.readiness.clone())
+	}
+
+	pub fn reset(&self) {
+		let _ = self.readiness.set_readiness(Ready::empty());
+	}
+}
+
+impl Evented for Waker {
+	fn register(&self, poll: &Poll, token: Token, interest: Ready, opts: PollOpt) -> io::Result<()> {
+		Evented::register(&self.registration, poll, token, interest, opts)
+	}
+
+	fn reregister(&self, poll: &Poll, token: Token, interest: Ready, opts: PollOpt) -> io::Result<()> {
+		Evented::reregister(&self.registration, poll, token, interest, opts)
+	}
+
+	fn deregister(&self, poll: &Poll) -> io::Result<()> {
+		Evented::deregister(&self.registration, poll)
+	}
+}
+
+#[derive(Clone)]
+pub struct WakeHandle(SetReadiness);
+
+impl WakeHandle {
+	pub fn wake(&self) {
+		let _ = self.0.set_readiness(Ready::readable());
+	}
+}
+
+// Waits for any of the peer connections (and other registered sockets)
+// to become ready and does the IO of peer connections, all on one thread.
+pub struct EventLoop {
+	poll: Poll,
+	events: Events,
+	sockets: HashMap<Token, Rc<RefCell<Socket>>>,
+	next_token: usize,
+}
+
+impl EventLoop {
+	pub fn new() -> io::Result<EventLoop> {
+		Ok(EventLoop {
+			poll: try!(Poll::new()),
+			events: Events::with_capacity(1024),
+			sockets: HashMap::new(),
+			next_token: WAKE.0 + 1,
+		})
+	}
+
+	pub fn connect(&mut self, handshake: HandshakeInfo, peer: PeerAddress) -> BtConnection {
+		debug!("Connecting to peer: {:?}", peer);
+		match TcpStream::connect(&peer.socket_addr()) {
+			Ok(stream) => self.add(Socket::new(stream, handshake, peer, false)),
+			Err(e) => {
+				debug!("Failed to connect to {:?}: {:?}", peer, e);
+				BtConnection::failed(Error::IoError(e))
+			}
+		}
+	}
+
+	pub fn accept(&mut self, handshake: HandshakeInfo, peer: PeerAddress, stream: TcpStream) -> BtConnection {
+		debug!("Accepted connection from peer: {:?}", peer);
+		self.add(Socket::new(stream, handshake, peer, true))
+	}
+
+	// Wakes the loop up when source becomes readable.
+	pub fn watch<E: Evented>(&self, source: &E) -> io::Result<()> {
+		self.poll.register(source, WAKE, Ready::readable(), PollOpt::level())
+	}
+
+	// Waits until something happens or timeout passes, and
+	// does all the IO peer connections are ready for.
+	pub fn poll(&mut self, timeout: Duration) {
+		if let Err(e) = self.poll.poll(&mut self.events, Some(timeout)) {
+			warn!("Failed to wait for network events: {:?}", e);
+			return;
+		}
+		for event in self.events.iter() {
+			if let Some(socket) = self.sockets.get(&event.token()) {
+				socket.borrow_mut().ready();
+			}
+		}
+		for socket in self.sockets.values() {
+			let mut socket = socket.borrow_mut();
+			socket.check_timeout();
+		}
+		// connection handle was dropped without closing it
+		for socket in self.sockets.values().filter(|socket| Rc::strong_count(socket) == 1) {
+			socket.borrow_mut().close();
+		}
+		self.sockets.retain(|_, socket| !socket.borrow().is_closed());
+	}
+
+	fn add(&mut self, socket: Socket) -> BtConnection {
+		let token = Token(self.next_token);
+		self.next_token += 1;
+		let socket = Rc::new(RefCell::new(socket));
+		let interest = Ready::readable() | Ready::writable();
+		let registered = self.poll.register(socket.borrow().stream(), token, interest, PollOpt::edge());
+		match registered {
+			Ok(()) => {
+				self.sockets.insert(token, socket.clone());
+			}
+			Err(e) => {
+				socket.borrow_mut().fail(Error::IoError(e));
+			}
+		}
+		BtConnection::new(socket)
+	}
+}
+
+
+#[cfg(test)]
+mod test {
+	use std::net;
+	use std::time::{Duration, Instant};
+	use mio::net::TcpListener;
+	use downloader::{DownloaderId, PeerAddress};
+	use downloader::connection::{Connection, HandshakeInfo, InMessage, Message, Error};
+	use downloader::connection::bt::BtConnection;
+	use super::*;
+
+	fn next_message(event_loop: &mut EventLoop, connection: &mut BtConnection) -> InMessage {
+		let deadline = Instant::now() + Duration::from_secs(5);
+		loop {
+			if let Some(msg) = connection.receive() {
+				return msg;
+			}
+			assert!(Instant::now() < deadline, "nothing was received");
+			event_loop.poll(Duration::from_millis(10));
+		}
+	}
+
+	// connects to a listener of the same loop, returns outgoing
+	// and accepted sides of the connection
+	fn pair(event_loop: &mut EventLoop, accepted_hash: [u8; 20]) -> (BtConnection, BtConnection) {
+		let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+		let address = listener.local_addr().unwrap();
+		let listener = TcpListener::from_std(listener).unwrap();
+		event_loop.watch(&listener).unwrap();
+
+		let info = HandshakeInfo::new([1; 20], DownloaderId([1; 20]));
+		let outgoing = event_loop.connect(info, PeerAddress::from(address));
+		let deadline = Instant::now() + Duration::from_secs(5);
+		loop {
+			assert!(Instant::now() < deadline, "connection was not accepted");
+			event_loop.poll(Duration::from_millis(10));
+			if let Ok((stream, from)) = listener.accept() {
+				let info = HandshakeInfo::new(accepted_hash, DownloaderId([2; 20]));
+				let incoming = event_loop.accept(info, PeerAddress::from(from), stream);
+				return (outgoing, incoming);
+			}
+		}
+	}
+
+	#[test]
+	fn exchanges_messages() {
+		let mut event_loop = EventLoop::new().unwrap();
+		let (mut outgoing, mut incoming) = pair(&mut event_loop, [1; 20]);
+		// waits until handshake is done
+		outgoing.send(Message::Have(5));
+
+		match next_message(&mut event_loop, &mut incoming) {
+			InMessage::Handshake(info) => assert_eq!(info.id, DownloaderId([1; 20])),
+			_ => panic!("expected handshake"),
+		}
+		match next_message(&mut event_loop, &mut outgoing) {
+			InMessage::Handshake(info) => {
+				assert_eq!(info.id, DownloaderId([2; 20]));
+				assert!(info.supports_extensions() && info.supports_fast());
+			}
+			_ => panic!("expected handshake"),
+		}
+		match next_message(&mut event_loop, &mut incoming) {
+			InMessage::Normal(Message::Have(5)) => {}
+			_ => panic!("expected have"),
+		}
+
+		incoming.send(Message::Piece(1, 0, vec![7; 0x4000]));
+		match next_message(&mut event_loop, &mut outgoing) {
+			InMessage::Normal(Message::Piece(1, 0, data)) => assert_eq!(data, vec![7; 0x4000]),
+			_ => panic!("expected piece"),
+		}
+
+		outgoing.close();
+		match next_message(&mut event_loop, &mut incoming) {
+			InMessage::Error(Error::Closed) => assert!(!incoming.is_alive()),
+			_ => panic!("expected connection to close"),
+		}
+	}
+
+	#[test]
+	fn rejects_other_torrents() {
+		let mut event_loop = EventLoop::new().unwrap();
+		let (mut outgoing, mut incoming) = pair(&mut event_loop, [9; 20]);
+		match next_message(&mut event_loop, &mut incoming) {
+			InMessage::Error(Error::BadHandshake) => {}
+			_ => panic!("expected bad handshake"),
+		}
+		match next_message(&mut event_loop, &mut outgoing) {
+			InMessage::Error(_) => {}
+			_ => panic!("expected connection to close"),
+		}
+	}
+}
@@ -0,0 +1,90 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::mem;
+use std::net::Ipv4Addr;
+use std::rc::Rc;
+use downloader::{DownloaderId, PeerAddress};
+use downloader::connection::{Connection, InMessage, Message, HandshakeInfo};
+use downloader::extension::ExtensionRegistry;
+use downloader::peer::{self, Peer};
+
+
+// Far end of a fake connection: tests queue messages that the peer
+// "sent" and look at what we sent to it.
+#[derive(Default)]
+pub struct Wire {
+	pub incoming: VecDeque<InMessage>,
+	pub sent: Vec<Message>,
+	// pretend that the socket doesn't take this much of what was sent
+	pub unsent_bytes: usize,
+	pub closed: bool,
+}
+
+impl Wire {
+	pub fn take_sent(&mut self) -> Vec<Message> {
+		mem::replace(&mut self.sent, Vec::new())
+	}
+}
+
+// Connection for tests that never touches the network.
+pub struct FakeConnection {
+	wire: Rc<RefCell<Wire>>,
+}
+
+impl FakeConnection {
+	pub fn new() -> (FakeConnection, Rc<RefCell<Wire>>) {
+		let wire = Rc::new(RefCell::new(Wire::default()));
+		(FakeConnection { wire: wire.clone() }, wire)
+	}
+}
+
+impl Connection for FakeConnection {
+	fn send(&mut self, msg: Message) {
+		let mut wire = self.wire.borrow_mut();
+		if !wire.closed {
+			wire.sent.push(msg);
+		}
+	}
+
+	fn receive(&mut self) -> Option<InMessage> {
+		self.wire.borrow_mut().incoming.pop_front()
+	}
+
+	fn close(&mut self) {
+		self.wire.borrow_mut().closed = true;
+	}
+
+	fn is_alive(&self) -> bool {
+		!self.wire.borrow().closed
+	}
+
+	fn pending_send_bytes(&self) -> usize {
+		self.wire.borrow().unsent_bytes
+	}
+}
+
+// Peer at 10.0.0.<last> that finished the handshake, with or without
+// fast extension.
+pub fn connected_peer(last: u8, piece_count: usize, fast: bool) -> (Peer, Rc<RefCell<Wire>>) {
+	let (connection, wire) = FakeConnection::new();
+	let info = HandshakeInfo::new([1; 20], DownloaderId([0; 20]));
+	let mut remote = HandshakeInfo::new([1; 20], DownloaderId([last; 20]));
+	if !fast {
+		remote.reserved = [0; 8];
+	}
+	let address = PeerAddress::new(Ipv4Addr::new(10, 0, 0, last), 6881);
+	let extensions = ExtensionRegistry::new().create();
+	let mut peer = Peer::new(Box::new(connection), address, piece_count, info, extensions);
+	wire.borrow_mut().incoming.push_back(InMessage::Handshake(remote));
+	match peer.receive() {
+		Some(peer::Message::Handshake) => {}
+		_ => panic!("handshake expected"),
+	}
+	(peer, wire)
+}
+
+// Passes message to peer as if it came from the network.
+pub fn receive(peer: &mut Peer, wire: &Rc<RefCell<Wire>>, msg: Message) -> Option<peer::Message> {
+	wire.borrow_mut().incoming.push_back(InMessage::Normal(msg));
+	peer.receive()
+}
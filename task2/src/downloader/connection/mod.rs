@@ -1,24 +1,49 @@
 pub mod bt;
+pub mod event_loop;
+#[cfg(test)]
+pub mod fake;
 
 use std::io;
 use downloader::DownloaderId;
 
 
+// bit in reserved handshake bytes telling that extension protocol
+// (BEP 10) is supported: byte 5, mask 0x10
+const EXTENSION_BYTE: usize = 5;
+const EXTENSION_BIT: u8 = 0x10;
+// fast extension (BEP 6): byte 7, mask 0x04
+const FAST_BYTE: usize = 7;
+const FAST_BIT: u8 = 0x04;
+
 #[derive(Debug, Clone)]
 pub struct HandshakeInfo {
 	pub info_hash: [u8; 20],
 	pub id: DownloaderId,
+	pub reserved: [u8; 8],
 }
 
 impl HandshakeInfo {
 	pub fn new(info_hash: [u8; 20], id: DownloaderId) -> HandshakeInfo {
+		let mut reserved = [0; 8];
+		reserved[EXTENSION_BYTE] |= EXTENSION_BIT;
+		reserved[FAST_BYTE] |= FAST_BIT;
 		HandshakeInfo {
 			info_hash: info_hash,
 			id: id,
+			reserved: reserved,
 		}
 	}
+
+	pub fn supports_extensions(&self) -> bool {
+		self.reserved[EXTENSION_BYTE] & EXTENSION_BIT != 0
+	}
+
+	pub fn supports_fast(&self) -> bool {
+		self.reserved[FAST_BYTE] & FAST_BIT != 0
+	}
 }
 
+#[derive(Debug, PartialEq)]
 pub enum Message {
 	Choke,
 	Unchoke,
@@ -29,6 +54,14 @@ pub enum Message {
 	Request(usize, usize, usize),
 	Piece(usize, usize, Vec<u8>),
 	Cancel(usize, usize, usize),
+	// fast extension messages
+	SuggestPiece(usize),
+	HaveAll,
+	HaveNone,
+	RejectRequest(usize, usize, usize),
+	AllowedFast(usize),
+	// extension message id (0 is the extended handshake) and payload
+	Extended(u8, Vec<u8>),
 }
 
 #[derive(Debug)]
@@ -51,4 +84,6 @@ pub trait Connection {
 	fn receive(&mut self) -> Option<InMessage>;
 	fn close(&mut self);
 	fn is_alive(&self) -> bool;
+	// bytes of sent messages that are not written to the socket yet
+	fn pending_send_bytes(&self) -> usize;
 }
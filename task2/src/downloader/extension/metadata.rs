@@ -0,0 +1,304 @@
+use std::cell::RefCell;
+use std::cmp;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+use bencode;
+use bencode::BValue;
+use downloader::extension::*;
+
+
+// metadata is transferred in pieces of this size (BEP 9)
+const PIECE_SIZE: usize = 0x4000;
+// refuse to download anything larger than this as metadata
+const MAX_METADATA_SIZE: usize = 0x1000000; // 16 mb
+const REQUEST_TIMEOUT: u64 = 30; // seconds
+
+const MSG_REQUEST: i64 = 0;
+const MSG_DATA: i64 = 1;
+const MSG_REJECT: i64 = 2;
+
+// Metadata being assembled from pieces sent by all peers. Its size is
+// taken from one of the peers, only peers that agree on it are used.
+pub struct MetadataState {
+	size: Option<usize>,
+	pieces: Vec<Option<Vec<u8>>>,
+	// how many peers are currently asked for every piece
+	in_flight: Vec<usize>,
+}
+
+impl Default for MetadataState {
+	fn default() -> MetadataState {
+		MetadataState {
+			size: None,
+			pieces: Vec::new(),
+			in_flight: Vec::new(),
+		}
+	}
+}
+
+impl MetadataState {
+	pub fn new() -> MetadataState {
+		MetadataState::default()
+	}
+
+	// Returns complete metadata once all pieces are here. Pieces are
+	// discarded, so if the metadata turns out to be bad it is downloaded
+	// again.
+	pub fn take(&mut self) -> Option<Vec<u8>> {
+		if self.pieces.len() == 0 || self.pieces.iter().any(Option::is_none) {
+			return None;
+		}
+		let mut metadata = Vec::new();
+		for piece in self.pieces.iter_mut() {
+			metadata.extend(piece.take().unwrap());
+		}
+		Some(metadata)
+	}
+
+	// Metadata did not match the info hash, size might have been
+	// wrong too, so it is taken from a peer again.
+	pub fn reset(&mut self) {
+		*self = MetadataState::default();
+	}
+
+	fn set_size(&mut self, size: usize) {
+		if self.size.is_none() {
+			let count = (size + PIECE_SIZE - 1) / PIECE_SIZE;
+			debug!("Metadata size is {} bytes ({} pieces)", size, count);
+			self.size = Some(size);
+			self.pieces = vec![None; count];
+			self.in_flight = vec![0; count];
+		}
+	}
+
+	// prefers pieces nobody is downloading, but asks for the
+	// same ones again when everything is already requested
+	fn next_piece(&self) -> Option<usize> {
+		(0..self.pieces.len())
+			.filter(|&piece| self.pieces[piece].is_none())
+			.min_by_key(|&piece| self.in_flight[piece])
+	}
+
+	fn piece_length(&self, piece: usize) -> Option<usize> {
+		match self.size {
+			Some(size) if piece < self.pieces.len() => {
+				Some(cmp::min(PIECE_SIZE, size - piece * PIECE_SIZE))
+			}
+			_ => None,
+		}
+	}
+}
+
+// ut_metadata extension (BEP 9), downloading metadata from a single peer.
+// We never have metadata to share, so all requests are rejected.
+pub struct MetadataExtension {
+	state: Rc<RefCell<MetadataState>>,
+	// metadata size this peer told us about
+	size: Option<usize>,
+	requested: Option<(usize, Instant)>,
+}
+
+impl MetadataExtension {
+	pub fn new(state: Rc<RefCell<MetadataState>>) -> MetadataExtension {
+		MetadataExtension {
+			state: state,
+			size: None,
+			requested: None,
+		}
+	}
+
+	// peer can only send pieces of metadata of the size we are assembling
+	fn agrees(&self) -> bool {
+		self.size.is_some() && self.state.borrow().size == self.size
+	}
+
+	fn finish_request(&mut self) {
+		if let Some((piece, _)) = self.requested.take() {
+			let mut state = self.state.borrow_mut();
+			if piece < state.in_flight.len() && state.in_flight[piece] > 0 {
+				state.in_flight[piece] -= 1;
+			}
+		}
+	}
+}
+
+impl Drop for MetadataExtension {
+	fn drop(&mut self) {
+		self.finish_request();
+	}
+}
+
+impl ExtensionHandler for MetadataExtension {
+	fn name(&self) -> &'static str {
+		"ut_metadata"
+	}
+
+	fn on_handshake(&mut self, handshake: &Dict, _out: &mut Vec<Vec<u8>>) -> ExtensionResult {
+		let size = try!(handshake.get(&b"metadata_size"[..])
+			.and_then(BValue::get_int)
+			.ok_or(ExtensionError::BadMessage("missing metadata size")));
+		if size <= 0 || size as usize > MAX_METADATA_SIZE {
+			return Err(ExtensionError::BadMessage("bad metadata size"));
+		}
+		self.size = Some(size as usize);
+		self.state.borrow_mut().set_size(size as usize);
+		Ok(())
+	}
+
+	fn on_message(&mut self, payload: &[u8], out: &mut Vec<Vec<u8>>) -> ExtensionResult {
+		// data of a piece follows the dictionary
+		let (dict, length) = match bencode::decode_prefix(payload) {
+			Ok((BValue::Dict(dict), length)) => (dict, length),
+			_ => return Err(ExtensionError::BadMessage("bad ut_metadata message")),
+		};
+		let msg_type = dict.get(&b"msg_type"[..]).and_then(BValue::get_int);
+		let piece = try!(dict.get(&b"piece"[..])
+			.and_then(BValue::get_int)
+			.ok_or(ExtensionError::BadMessage("missing metadata piece")));
+
+		match msg_type {
+			Some(MSG_REQUEST) => {
+				out.push(bencode::encode(&bdict!(
+					b"msg_type".to_vec() => BValue::Int(MSG_REJECT),
+					b"piece".to_vec() => BValue::Int(piece)
+				)));
+			}
+			Some(MSG_DATA) => {
+				let piece = piece as usize;
+				if self.requested.map(|(p, _)| p) == Some(piece) {
+					self.finish_request();
+				}
+				if !self.agrees() {
+					// answer to a request sent before metadata was reset
+					return Ok(());
+				}
+				let mut state = self.state.borrow_mut();
+				let data = &payload[length..];
+				if state.piece_length(piece) != Some(data.len()) {
+					return Err(ExtensionError::BadMessage("bad metadata piece"));
+				}
+				state.pieces[piece] = Some(data.to_vec());
+			}
+			Some(MSG_REJECT) => {
+				return Err(ExtensionError::BadMessage("metadata request rejected"));
+			}
+			_ => {
+				// unknown messages are ignored
+			}
+		}
+		Ok(())
+	}
+
+	fn tick(&mut self, out: &mut Vec<Vec<u8>>) -> ExtensionResult {
+		if let Some((_, time)) = self.requested {
+			if Instant::now() - time > Duration::from_secs(REQUEST_TIMEOUT) {
+				return Err(ExtensionError::BadMessage("metadata request timed out"));
+			}
+			return Ok(());
+		}
+
+		if let Some(size) = self.size {
+			self.state.borrow_mut().set_size(size);
+		}
+		if !self.agrees() {
+			return Ok(());
+		}
+		let piece = {
+			let mut state = self.state.borrow_mut();
+			match state.next_piece() {
+				Some(piece) => {
+					state.in_flight[piece] += 1;
+					piece
+				}
+				None => return Ok(()),
+			}
+		};
+		out.push(bencode::encode(&bdict!(
+			b"msg_type".to_vec() => BValue::Int(MSG_REQUEST),
+			b"piece".to_vec() => BValue::Int(piece as i64)
+		)));
+		self.requested = Some((piece, Instant::now()));
+		Ok(())
+	}
+}
+
+
+#[cfg(test)]
+mod test {
+	use std::cell::RefCell;
+	use std::rc::Rc;
+	use bencode;
+	use bencode::BValue;
+	use downloader::extension::ExtensionHandler;
+	use super::*;
+
+	#[test]
+	fn downloads_pieces() {
+		let state = Rc::new(RefCell::new(MetadataState::new()));
+		let mut extension = MetadataExtension::new(state.clone());
+		let handshake = bdict!(b"metadata_size".to_vec() => BValue::Int(PIECE_SIZE as i64 + 3))
+			.get_dict()
+			.unwrap();
+		let mut out = Vec::new();
+		extension.on_handshake(&handshake, &mut out).unwrap();
+
+		extension.tick(&mut out).unwrap();
+		assert_eq!(out, vec![b"d8:msg_typei0e5:piecei0ee".to_vec()]);
+		let mut data = b"d8:msg_typei1e5:piecei0e10:total_sizei16387ee".to_vec();
+		data.extend(vec![7; PIECE_SIZE]);
+		extension.on_message(&data, &mut out).unwrap();
+
+		out.clear();
+		extension.tick(&mut out).unwrap();
+		assert_eq!(out, vec![b"d8:msg_typei0e5:piecei1ee".to_vec()]);
+		assert!(extension.on_message(b"d8:msg_typei1e5:piecei1eeab", &mut out).is_err());
+		extension.on_message(b"d8:msg_typei1e5:piecei1eeabc", &mut out).unwrap();
+
+		let metadata = state.borrow_mut().take().unwrap();
+		assert_eq!(metadata.len(), PIECE_SIZE + 3);
+		assert_eq!(&metadata[PIECE_SIZE..], b"abc");
+	}
+
+	#[test]
+	fn only_peers_agreeing_on_size_are_asked() {
+		let state = Rc::new(RefCell::new(MetadataState::new()));
+		let mut first = MetadataExtension::new(state.clone());
+		let mut second = MetadataExtension::new(state.clone());
+		let handshake = |size: i64| bdict!(b"metadata_size".to_vec() => BValue::Int(size))
+			.get_dict()
+			.unwrap();
+		let mut out = Vec::new();
+		first.on_handshake(&handshake(3), &mut out).unwrap();
+		second.on_handshake(&handshake(5), &mut out).unwrap();
+
+		second.tick(&mut out).unwrap();
+		assert!(out.is_empty());
+		first.tick(&mut out).unwrap();
+		assert_eq!(out.len(), 1);
+		first.on_message(b"d8:msg_typei1e5:piecei0eeabc", &mut out).unwrap();
+		assert_eq!(state.borrow_mut().take(), Some(b"abc".to_vec()));
+
+		// metadata was bad, the other peer gets a chance
+		state.borrow_mut().reset();
+		out.clear();
+		second.tick(&mut out).unwrap();
+		assert_eq!(out, vec![b"d8:msg_typei0e5:piecei0ee".to_vec()]);
+		first.tick(&mut out).unwrap();
+		assert_eq!(out.len(), 1);
+		second.on_message(b"d8:msg_typei1e5:piecei0eeabcde", &mut out).unwrap();
+		assert_eq!(state.borrow_mut().take(), Some(b"abcde".to_vec()));
+	}
+
+	#[test]
+	fn rejects_requests() {
+		let state = Rc::new(RefCell::new(MetadataState::new()));
+		let mut extension = MetadataExtension::new(state);
+		let mut out = Vec::new();
+		let request = bencode::encode(&bdict!(
+			b"msg_type".to_vec() => BValue::Int(0),
+			b"piece".to_vec() => BValue::Int(3)
+		));
+		extension.on_message(&request, &mut out).unwrap();
+		assert_eq!(out, vec![b"d8:msg_typei2e5:piecei3ee".to_vec()]);
+	}
+}
@@ -0,0 +1,250 @@
+pub mod metadata;
+pub mod pex;
+
+use std::collections::BTreeMap;
+use bencode;
+use bencode::BValue;
+use downloader::connection::Message;
+
+
+// extension message id reserved for the extended handshake
+pub const HANDSHAKE_ID: u8 = 0;
+
+pub type Dict = BTreeMap<Vec<u8>, BValue>;
+
+#[derive(Debug)]
+pub enum ExtensionError {
+	BadHandshake,
+	UnknownMessage(u8),
+	BadMessage(&'static str),
+}
+
+pub type ExtensionResult = Result<(), ExtensionError>;
+
+// Single extension protocol (like ut_metadata) spoken with a single peer,
+// every peer gets its own handlers. Payloads pushed to `out` are sent to
+// the peer with the id it assigned to this extension.
+pub trait ExtensionHandler {
+	// name of the extension in "m" dictionary of the extended handshake
+	fn name(&self) -> &'static str;
+
+	// adds extension specific keys to our extended handshake
+	fn extend_handshake(&self, _handshake: &mut Dict) {
+	}
+
+	// called once peer's extended handshake says it supports this extension
+	fn on_handshake(&mut self, _handshake: &Dict, _out: &mut Vec<Vec<u8>>) -> ExtensionResult {
+		Ok(())
+	}
+
+	fn on_message(&mut self, payload: &[u8], out: &mut Vec<Vec<u8>>) -> ExtensionResult;
+
+	// called regularly after the handshake, for extensions that
+	// send messages on their own
+	fn tick(&mut self, _out: &mut Vec<Vec<u8>>) -> ExtensionResult {
+		Ok(())
+	}
+}
+
+// Set of extensions we support, used to create handlers for every peer.
+pub struct ExtensionRegistry {
+	factories: Vec<Box<Fn() -> Box<ExtensionHandler>>>,
+}
+
+impl Default for ExtensionRegistry {
+	fn default() -> ExtensionRegistry {
+		ExtensionRegistry {
+			factories: Vec::new(),
+		}
+	}
+}
+
+impl ExtensionRegistry {
+	pub fn new() -> ExtensionRegistry {
+		ExtensionRegistry::default()
+	}
+
+	pub fn register<F>(&mut self, factory: F)
+			where F: Fn() -> Box<ExtensionHandler> + 'static {
+		self.factories.push(Box::new(factory));
+	}
+
+	pub fn create(&self) -> Extensions {
+		let handlers = self.factories.iter()
+			.map(|factory| factory())
+			.collect::<Vec<_>>();
+		Extensions {
+			remote_ids: vec![None; handlers.len()],
+			handlers: handlers,
+			peer_handshake: None,
+		}
+	}
+}
+
+// Extension handlers of a single peer. Peer sends us messages using ids
+// from our handshake (position in registry, starting from 1), and we send
+// messages using ids from its handshake.
+pub struct Extensions {
+	handlers: Vec<Box<ExtensionHandler>>,
+	remote_ids: Vec<Option<u8>>,
+	peer_handshake: Option<Dict>,
+}
+
+impl Extensions {
+	pub fn handshake(&self) -> Message {
+		let mut m = BTreeMap::new();
+		for (index, handler) in self.handlers.iter().enumerate() {
+			m.insert(handler.name().as_bytes().to_vec(), BValue::Int(index as i64 + 1));
+		}
+		let mut handshake = BTreeMap::new();
+		for handler in &self.handlers {
+			handler.extend_handshake(&mut handshake);
+		}
+		handshake.insert(b"m".to_vec(), BValue::Dict(m));
+		Message::Extended(HANDSHAKE_ID, bencode::encode(&BValue::Dict(handshake)))
+	}
+
+	pub fn peer_handshake(&self) -> Option<&Dict> {
+		self.peer_handshake.as_ref()
+	}
+
+	pub fn peer_supports(&self, name: &str) -> bool {
+		self.handlers.iter()
+			.zip(self.remote_ids.iter())
+			.any(|(handler, id)| handler.name() == name && id.is_some())
+	}
+
+	// Handles extension message from the peer, returning messages that
+	// should be sent in response. Errors mean the peer should be dropped.
+	pub fn process(&mut self, id: u8, payload: &[u8]) -> Result<Vec<Message>, ExtensionError> {
+		let mut out = Vec::new();
+		if id == HANDSHAKE_ID {
+			try!(self.process_handshake(payload, &mut out));
+		} else {
+			let index = id as usize - 1;
+			if index >= self.handlers.len() {
+				return Err(ExtensionError::UnknownMessage(id));
+			}
+			let mut payloads = Vec::new();
+			try!(self.handlers[index].on_message(payload, &mut payloads));
+			self.wrap(index, payloads, &mut out);
+		}
+		Ok(out)
+	}
+
+	pub fn tick(&mut self) -> Result<Vec<Message>, ExtensionError> {
+		let mut out = Vec::new();
+		for index in 0..self.handlers.len() {
+			if self.remote_ids[index].is_some() {
+				let mut payloads = Vec::new();
+				try!(self.handlers[index].tick(&mut payloads));
+				self.wrap(index, payloads, &mut out);
+			}
+		}
+		Ok(out)
+	}
+
+	fn process_handshake(&mut self, payload: &[u8], out: &mut Vec<Message>) -> ExtensionResult {
+		let handshake = try!(bencode::decode(payload)
+			.ok()
+			.and_then(BValue::get_dict)
+			.ok_or(ExtensionError::BadHandshake));
+		{
+			// handshake may be repeated to update the ids,
+			// id 0 means extension was disabled
+			let m = try!(handshake.get(&b"m"[..])
+				.and_then(BValue::get_dict_ref)
+				.ok_or(ExtensionError::BadHandshake));
+			for index in 0..self.handlers.len() {
+				let was_supported = self.remote_ids[index].is_some();
+				self.remote_ids[index] = m.get(self.handlers[index].name().as_bytes())
+					.and_then(BValue::get_int)
+					.and_then(|id| if id > 0 && id < 256 { Some(id as u8) } else { None });
+				if !was_supported && self.remote_ids[index].is_some() {
+					let mut payloads = Vec::new();
+					try!(self.handlers[index].on_handshake(&handshake, &mut payloads));
+					self.wrap(index, payloads, out);
+				}
+			}
+		}
+		self.peer_handshake = Some(handshake);
+		Ok(())
+	}
+
+	fn wrap(&self, index: usize, payloads: Vec<Vec<u8>>, out: &mut Vec<Message>) {
+		if let Some(id) = self.remote_ids[index] {
+			for payload in payloads {
+				out.push(Message::Extended(id, payload));
+			}
+		}
+	}
+}
+
+
+#[cfg(test)]
+mod test {
+	use std::cell::RefCell;
+	use std::rc::Rc;
+	use bencode;
+	use bencode::BValue;
+	use downloader::connection::Message;
+	use super::*;
+
+	struct Echo {
+		received: Rc<RefCell<Vec<Vec<u8>>>>,
+	}
+
+	impl ExtensionHandler for Echo {
+		fn name(&self) -> &'static str {
+			"echo"
+		}
+
+		fn extend_handshake(&self, handshake: &mut Dict) {
+			handshake.insert(b"v".to_vec(), BValue::Str(b"test".to_vec()));
+		}
+
+		fn on_message(&mut self, payload: &[u8], out: &mut Vec<Vec<u8>>) -> ExtensionResult {
+			self.received.borrow_mut().push(payload.to_vec());
+			out.push(payload.to_vec());
+			Ok(())
+		}
+	}
+
+	fn registry(received: Rc<RefCell<Vec<Vec<u8>>>>) -> ExtensionRegistry {
+		let mut registry = ExtensionRegistry::new();
+		registry.register(move || Box::new(Echo { received: received.clone() }));
+		registry
+	}
+
+	#[test]
+	fn handshake_lists_extensions() {
+		let extensions = registry(Rc::new(RefCell::new(Vec::new()))).create();
+		match extensions.handshake() {
+			Message::Extended(0, payload) => {
+				assert_eq!(payload, b"d1:md4:echoi1ee1:v4:teste".to_vec());
+			}
+			_ => panic!("expected extended handshake"),
+		}
+	}
+
+	#[test]
+	fn messages_use_negotiated_ids() {
+		let received = Rc::new(RefCell::new(Vec::new()));
+		let mut extensions = registry(received.clone()).create();
+		assert!(!extensions.peer_supports("echo"));
+
+		let handshake = bencode::encode(&bdict!(
+			b"m".to_vec() => bdict!(b"echo".to_vec() => BValue::Int(7))
+		));
+		assert!(extensions.process(0, &handshake).unwrap().is_empty());
+		assert!(extensions.peer_supports("echo"));
+
+		let response = extensions.process(1, b"hello").unwrap();
+		assert_eq!(received.borrow().len(), 1);
+		match response.as_slice() {
+			[Message::Extended(7, ref payload)] => assert_eq!(payload, b"hello"),
+			_ => panic!("expected echoed message"),
+		}
+		assert!(extensions.process(2, b"hello").is_err());
+	}
+}
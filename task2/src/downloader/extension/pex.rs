@@ -0,0 +1,243 @@
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+use bencode;
+use bencode::BValue;
+use downloader::PeerAddress;
+use downloader::tracker::{decode_compact_peers, decode_compact_peers6, encode_compact_peer};
+use downloader::extension::*;
+
+
+// peers should not send PEX messages more often than once a minute
+const PEX_INTERVAL: u64 = 60; // seconds
+// BEP 11 limits how many peers can be added or dropped in one message
+const MAX_PEERS_PER_MESSAGE: usize = 50;
+// don't keep more learned peers than this waiting to be picked up
+const MAX_LEARNED_PEERS: usize = 200;
+
+// flags sent with every added peer
+pub const FLAG_SEED: u8 = 0x02;
+pub const FLAG_REACHABLE: u8 = 0x10;
+
+// Peers we share with the swarm and peers we learn from it,
+// shared between downloader and PEX handlers of all peers.
+pub struct PexState {
+	port: u16,
+	connected: Vec<(PeerAddress, u8)>,
+	learned: Vec<PeerAddress>,
+}
+
+impl PexState {
+	pub fn new(port: u16) -> PexState {
+		PexState {
+			port: port,
+			connected: Vec::new(),
+			learned: Vec::new(),
+		}
+	}
+
+	// peers we are connected to, with their flags
+	pub fn set_connected(&mut self, connected: Vec<(PeerAddress, u8)>) {
+		self.connected = connected;
+	}
+
+	pub fn take_learned(&mut self) -> Vec<PeerAddress> {
+		::std::mem::replace(&mut self.learned, Vec::new())
+	}
+
+	fn learn(&mut self, peers: Vec<PeerAddress>) {
+		for peer in peers {
+			if self.learned.len() >= MAX_LEARNED_PEERS {
+				break;
+			}
+			if !self.learned.contains(&peer) {
+				self.learned.push(peer);
+			}
+		}
+	}
+}
+
+// ut_pex extension (BEP 11), exchanging addresses of connected peers.
+pub struct PexExtension {
+	state: Rc<RefCell<PexState>>,
+	// peers this peer has already been told about
+	advertised: HashSet<PeerAddress>,
+	last_sent: Option<Instant>,
+	last_received: Option<Instant>,
+}
+
+impl PexExtension {
+	pub fn new(state: Rc<RefCell<PexState>>) -> PexExtension {
+		PexExtension {
+			state: state,
+			advertised: HashSet::new(),
+			last_sent: None,
+			last_received: None,
+		}
+	}
+}
+
+impl ExtensionHandler for PexExtension {
+	fn name(&self) -> &'static str {
+		"ut_pex"
+	}
+
+	fn extend_handshake(&self, handshake: &mut Dict) {
+		// lets peers advertise us with our listening port,
+		// not with the one we happened to connect from
+		handshake.insert(b"p".to_vec(), BValue::Int(self.state.borrow().port as i64));
+	}
+
+	fn on_message(&mut self, payload: &[u8], _out: &mut Vec<Vec<u8>>) -> ExtensionResult {
+		let now = Instant::now();
+		// allow some slack, timers of both sides are not exact
+		let interval = Duration::from_secs(PEX_INTERVAL / 2);
+		if let Some(last) = self.last_received {
+			if now - last < interval {
+				debug!("Got PEX message too early, ignoring");
+				return Ok(());
+			}
+		}
+		self.last_received = Some(now);
+
+		let dict = try!(bencode::decode(payload)
+			.ok()
+			.and_then(BValue::get_dict)
+			.ok_or(ExtensionError::BadMessage("bad ut_pex message")));
+		let mut added = match dict.get(&b"added"[..]).and_then(BValue::get_string_ref) {
+			Some(added) => try!(decode_compact_peers(added).map_err(ExtensionError::BadMessage)),
+			None => Vec::new(),
+		};
+		if let Some(added6) = dict.get(&b"added6"[..]).and_then(BValue::get_string_ref) {
+			added.extend(try!(decode_compact_peers6(added6).map_err(ExtensionError::BadMessage)));
+		}
+		debug!("Got {} peers through PEX", added.len());
+		self.state.borrow_mut().learn(added);
+		Ok(())
+	}
+
+	fn tick(&mut self, out: &mut Vec<Vec<u8>>) -> ExtensionResult {
+		let now = Instant::now();
+		if let Some(last) = self.last_sent {
+			if now - last < Duration::from_secs(PEX_INTERVAL) {
+				return Ok(());
+			}
+		}
+
+		let state = self.state.borrow();
+		let added = state.connected.iter()
+			.filter(|&&(ref peer, _)| !self.advertised.contains(peer))
+			.take(MAX_PEERS_PER_MESSAGE)
+			.cloned()
+			.collect::<Vec<_>>();
+		let dropped = self.advertised.iter()
+			.filter(|peer| !state.connected.iter().any(|&(ref p, _)| p == *peer))
+			.take(MAX_PEERS_PER_MESSAGE)
+			.cloned()
+			.collect::<Vec<_>>();
+		if added.len() == 0 && dropped.len() == 0 {
+			return Ok(());
+		}
+
+		// IPv4 and IPv6 peers go to separate lists
+		let mut added_peers = (Vec::new(), Vec::new());
+		let mut added_flags = (Vec::new(), Vec::new());
+		for &(ref peer, flags) in &added {
+			let (peers, peer_flags) = if peer.is_ipv6() {
+				(&mut added_peers.1, &mut added_flags.1)
+			} else {
+				(&mut added_peers.0, &mut added_flags.0)
+			};
+			peers.extend(encode_compact_peer(peer));
+			peer_flags.push(flags);
+			self.advertised.insert(peer.clone());
+		}
+		let mut dropped_peers = (Vec::new(), Vec::new());
+		for peer in &dropped {
+			let peers = if peer.is_ipv6() { &mut dropped_peers.1 } else { &mut dropped_peers.0 };
+			peers.extend(encode_compact_peer(peer));
+			self.advertised.remove(peer);
+		}
+		out.push(bencode::encode(&bdict!(
+			b"added".to_vec() => BValue::Str(added_peers.0),
+			b"added.f".to_vec() => BValue::Str(added_flags.0),
+			b"added6".to_vec() => BValue::Str(added_peers.1),
+			b"added6.f".to_vec() => BValue::Str(added_flags.1),
+			b"dropped".to_vec() => BValue::Str(dropped_peers.0),
+			b"dropped6".to_vec() => BValue::Str(dropped_peers.1)
+		)));
+		self.last_sent = Some(now);
+		Ok(())
+	}
+}
+
+
+#[cfg(test)]
+mod test {
+	use std::cell::RefCell;
+	use std::net::{Ipv4Addr, Ipv6Addr};
+	use std::rc::Rc;
+	use bencode;
+	use bencode::BValue;
+	use downloader::PeerAddress;
+	use downloader::extension::ExtensionHandler;
+	use super::*;
+
+	fn address(last: u8, port: u16) -> PeerAddress {
+		PeerAddress::new(Ipv4Addr::new(10, 0, 0, last), port)
+	}
+
+	#[test]
+	fn sends_added_and_dropped() {
+		let state = Rc::new(RefCell::new(PexState::new(6881)));
+		let mut pex = PexExtension::new(state.clone());
+		state.borrow_mut().set_connected(vec![
+			(address(1, 0x1234), FLAG_SEED),
+			(address(2, 80), 0),
+			(PeerAddress::new(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1), 80), 0),
+		]);
+		let mut out = Vec::new();
+		pex.tick(&mut out).unwrap();
+		let message = bencode::decode(&out[0]).unwrap().get_dict().unwrap();
+		let added = message.get(&b"added"[..]).and_then(BValue::get_string_ref).unwrap();
+		assert_eq!(added.len(), 12);
+		assert_eq!(&added[..6], &[10, 0, 0, 1, 0x12, 0x34]);
+		let flags = message.get(&b"added.f"[..]).and_then(BValue::get_string_ref).unwrap();
+		assert_eq!(flags, &[FLAG_SEED, 0]);
+		let added6 = message.get(&b"added6"[..]).and_then(BValue::get_string_ref).unwrap();
+		assert_eq!(added6.len(), 18);
+
+		// nothing is sent again before the interval passes
+		state.borrow_mut().set_connected(vec![(address(2, 80), 0)]);
+		out.clear();
+		pex.tick(&mut out).unwrap();
+		assert!(out.is_empty());
+
+		pex.last_sent = None;
+		pex.tick(&mut out).unwrap();
+		let message = bencode::decode(&out[0]).unwrap().get_dict().unwrap();
+		let dropped = message.get(&b"dropped"[..]).and_then(BValue::get_string_ref).unwrap();
+		assert_eq!(dropped, &[10, 0, 0, 1, 0x12, 0x34]);
+		let added = message.get(&b"added"[..]).and_then(BValue::get_string_ref).unwrap();
+		assert!(added.is_empty());
+	}
+
+	#[test]
+	fn learns_peers() {
+		let state = Rc::new(RefCell::new(PexState::new(6881)));
+		let mut pex = PexExtension::new(state.clone());
+		let message = bencode::encode(&bdict!(
+			b"added".to_vec() => BValue::Str(vec![10, 0, 0, 3, 0, 80])
+		));
+		let mut out = Vec::new();
+		pex.on_message(&message, &mut out).unwrap();
+		// too early, ignored
+		pex.on_message(&message, &mut out).unwrap();
+		assert_eq!(state.borrow_mut().take_learned(), vec![address(3, 80)]);
+		assert!(state.borrow_mut().take_learned().is_empty());
+
+		pex.last_received = None;
+		assert!(pex.on_message(b"d5:added3:abce", &mut out).is_err());
+	}
+}
@@ -0,0 +1,55 @@
+use std::net::Ipv4Addr;
+
+
+// Pieces that peer at given address may request from us even while choked,
+// computed the way BEP 6 describes so that every peer it reconnects to
+// (or every address in its /24 network) gets the same set.
+pub fn allowed_fast_set(ip: Ipv4Addr, info_hash: &[u8; 20], piece_count: usize, count: usize) -> Vec<usize> {
+	let count = ::std::cmp::min(count, piece_count);
+	let mut allowed = Vec::with_capacity(count);
+	let mut x = ip.octets().to_vec();
+	x[3] = 0;
+	x.extend_from_slice(info_hash);
+	while allowed.len() < count {
+		let mut hasher = ::sha1::Sha1::new();
+		hasher.update(&x);
+		x = hasher.digest().bytes().to_vec();
+		for chunk in x.chunks(4) {
+			if allowed.len() >= count {
+				break;
+			}
+			let y = ((chunk[0] as u32) << 24)
+				| ((chunk[1] as u32) << 16)
+				| ((chunk[2] as u32) << 8)
+				| chunk[3] as u32;
+			let index = y as usize % piece_count;
+			if !allowed.contains(&index) {
+				allowed.push(index);
+			}
+		}
+	}
+	allowed
+}
+
+
+#[cfg(test)]
+mod test {
+	use std::net::Ipv4Addr;
+	use super::*;
+
+	#[test]
+	fn spec_example() {
+		let ip = Ipv4Addr::new(80, 4, 4, 200);
+		assert_eq!(allowed_fast_set(ip, &[0xaa; 20], 1313, 7),
+			vec![1059, 431, 808, 1217, 287, 376, 1188]);
+		assert_eq!(allowed_fast_set(ip, &[0xaa; 20], 1313, 9),
+			vec![1059, 431, 808, 1217, 287, 376, 1188, 353, 508]);
+	}
+
+	#[test]
+	fn small_torrent() {
+		let mut allowed = allowed_fast_set(Ipv4Addr::new(10, 0, 0, 1), &[1; 20], 3, 10);
+		allowed.sort();
+		assert_eq!(allowed, vec![0, 1, 2]);
+	}
+}
@@ -0,0 +1,72 @@
+use std::io;
+use std::io::ErrorKind;
+use std::net;
+use mio::net::{TcpListener, TcpStream};
+use downloader::PeerAddress;
+use downloader::connection::event_loop::EventLoop;
+
+
+pub struct Listener {
+	listeners: Vec<TcpListener>,
+	port: u16,
+}
+
+impl Listener {
+	// Listens on IPv6 when possible. On most systems IPv6 socket
+	// accepts IPv4 connections too, otherwise IPv4 gets its own socket.
+	pub fn new(port: u16) -> Result<Listener, io::Error> {
+		let mut listeners = Vec::new();
+		let mut port = port;
+		if let Ok(listener) = net::TcpListener::bind(("::", port)) {
+			port = try!(listener.local_addr()).port();
+			listeners.push(try!(TcpListener::from_std(listener)));
+		}
+		match net::TcpListener::bind(("0.0.0.0", port)) {
+			Ok(listener) => listeners.push(try!(TcpListener::from_std(listener))),
+			// taken by our dual stack IPv6 socket
+			Err(_) if listeners.len() > 0 => {}
+			Err(e) => return Err(e),
+		}
+		info!("Listening for peers on port {}", port);
+		Ok(Listener {
+			listeners: listeners,
+			port: port,
+		})
+	}
+
+	pub fn port(&self) -> u16 {
+		self.port
+	}
+
+	// incoming connections wake the event loop up
+	pub fn register(&self, event_loop: &EventLoop) -> io::Result<()> {
+		for listener in &self.listeners {
+			try!(event_loop.watch(listener));
+		}
+		Ok(())
+	}
+
+	pub fn accept(&mut self) -> Option<(TcpStream, PeerAddress)> {
+		for listener in &self.listeners {
+			if let Some(accepted) = accept(listener) {
+				return Some(accepted);
+			}
+		}
+		None
+	}
+}
+
+fn accept(listener: &TcpListener) -> Option<(TcpStream, PeerAddress)> {
+	match listener.accept() {
+		Ok((stream, address)) => {
+			Some((stream, PeerAddress::from(address)))
+		}
+		Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
+			None
+		}
+		Err(e) => {
+			warn!("Failed to accept incoming connection: {:?}", e);
+			None
+		}
+	}
+}
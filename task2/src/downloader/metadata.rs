@@ -0,0 +1,226 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+use bencode;
+use bencode::BValue;
+use downloader::{PeerAddress, Config, generate_id, start_dht, save_dht_nodes, LISTEN_PORT, WANTED_PEERS, TICK};
+use downloader::tracker::{Tracker, TrackerArgs};
+use downloader::tracker::manager::TrackerManager;
+use downloader::connection::{Connection, HandshakeInfo, InMessage, Message};
+use downloader::connection::event_loop::EventLoop;
+use downloader::extension::{ExtensionRegistry, Extensions, ExtensionError};
+use downloader::extension::metadata::{MetadataExtension, MetadataState};
+use downloader::retry::RetryList;
+use dht::Dht;
+
+
+// give up if metadata could not be downloaded in this time
+const FETCH_TIMEOUT: u64 = 600; // seconds
+
+
+struct MetadataPeer {
+	address: PeerAddress,
+	connection: Box<Connection>,
+	extensions: Extensions,
+	handshake_done: bool,
+}
+
+// Fetches info dictionary of a torrent known only by its info hash (e.g.
+// from a magnet link) from peers supporting the ut_metadata extension.
+pub struct MetadataFetcher {
+	info: HandshakeInfo,
+	event_loop: EventLoop,
+	tracker: Box<Tracker>,
+	peers: Vec<MetadataPeer>,
+	retries: RetryList,
+	extensions: ExtensionRegistry,
+	state: Rc<RefCell<MetadataState>>,
+	dht: Option<Dht>,
+	dht_nodes_file: Option<::std::path::PathBuf>,
+	dht_peers: Vec<PeerAddress>,
+}
+
+impl MetadataFetcher {
+	pub fn new(info_hash: [u8; 20], trackers: Vec<Vec<String>>, config: &Config) -> MetadataFetcher {
+		let info = HandshakeInfo::new(info_hash, generate_id());
+		let tracker = TrackerManager::with_tiers(TrackerArgs {
+			tracker_url: String::new(),
+			id: info.id.clone(),
+			info_hash: info_hash,
+			port: LISTEN_PORT,
+		}, trackers);
+		let mut dht = start_dht(config, &[]);
+		if let Some(ref mut dht) = dht {
+			dht.add_torrent(info_hash, None);
+		}
+		let event_loop = EventLoop::new().expect("failed to set up event loop");
+		if let Some(Err(e)) = dht.as_ref().map(|dht| dht.register(&event_loop)) {
+			warn!("Failed to watch DHT socket for events: {:?}", e);
+		}
+		if let Err(e) = tracker.register(&event_loop) {
+			warn!("Failed to watch trackers for events: {:?}", e);
+		}
+		let state = Rc::new(RefCell::new(MetadataState::new()));
+		let mut extensions = ExtensionRegistry::new();
+		let shared = state.clone();
+		extensions.register(move || Box::new(MetadataExtension::new(shared.clone())));
+		MetadataFetcher {
+			info: info,
+			event_loop: event_loop,
+			tracker: Box::new(tracker),
+			peers: Vec::new(),
+			retries: RetryList::new(),
+			extensions: extensions,
+			state: state,
+			dht: dht,
+			dht_nodes_file: config.dht_nodes_file.clone(),
+			dht_peers: Vec::new(),
+		}
+	}
+
+	// Blocks until the metadata is downloaded and matches the info hash,
+	// fails if that does not happen in time.
+	pub fn run(&mut self) -> Result<BValue, &'static str> {
+		info!("Fetching metadata");
+		let deadline = Instant::now() + Duration::from_secs(FETCH_TIMEOUT);
+		loop {
+			if Instant::now() >= deadline {
+				self.stop();
+				return Err("timed out while fetching metadata");
+			}
+			// size of the torrent is not known yet, make sure
+			// tracker does not think we are a seed
+			self.tracker.update(0, 0, 1);
+			self.update_dht();
+			self.remove_dead_connections();
+			self.open_new_connections();
+			self.process_messages();
+			if let Some(metadata) = self.take_metadata() {
+				self.stop();
+				return Ok(metadata);
+			}
+			self.event_loop.poll(Duration::from_millis(TICK));
+		}
+	}
+
+	fn stop(&mut self) {
+		for peer in &mut self.peers {
+			peer.connection.close();
+		}
+		// downloader starts its own node on the same port
+		save_dht_nodes(&self.dht, &self.dht_nodes_file);
+		self.dht = None;
+	}
+
+	// peers are tried again after a while, they might have
+	// been busy or gone, or metadata they sent was bad
+	fn remove_dead_connections(&mut self) {
+		for peer in self.peers.iter().filter(|peer| !peer.connection.is_alive()) {
+			self.retries.connection_lost(&peer.address, peer.handshake_done);
+		}
+		self.peers.retain(|peer| peer.connection.is_alive());
+	}
+
+	fn update_dht(&mut self) {
+		if let Some(ref mut dht) = self.dht {
+			dht.update();
+			let found = dht.take_peers(&self.info.info_hash);
+			self.dht_peers.extend(found);
+		}
+	}
+
+	fn open_new_connections(&mut self) {
+		while self.peers.len() < WANTED_PEERS {
+			let address = {
+				let peers = &self.peers;
+				let retries = &self.retries;
+				self.tracker.peers()
+					.chain(self.dht_peers.iter())
+					.filter(|address| retries.may_connect(address))
+					.find(|address| !peers.iter().any(|peer| peer.address == **address))
+			};
+			let address = match address {
+				Some(address) => address.clone(),
+				None => break,
+			};
+			let connection = self.event_loop.connect(self.info.clone(), address.clone());
+			self.peers.push(MetadataPeer {
+				address: address,
+				connection: Box::new(connection),
+				extensions: self.extensions.create(),
+				handshake_done: false,
+			});
+		}
+	}
+
+	fn process_messages(&mut self) {
+		for peer in &mut self.peers {
+			while let Some(msg) = peer.connection.receive() {
+				let result = match msg {
+					InMessage::Error(_) => {
+						break;
+					}
+					InMessage::Handshake(handshake) => {
+						if handshake.supports_extensions() {
+							peer.handshake_done = true;
+							Ok(vec![peer.extensions.handshake()])
+						} else {
+							debug!("Peer {:?} does not support extensions", peer.address);
+							peer.connection.close();
+							break;
+						}
+					}
+					InMessage::Normal(Message::Extended(id, payload)) => {
+						peer.extensions.process(id, &payload)
+					}
+					InMessage::Normal(_) => {
+						Ok(Vec::new())
+					}
+				};
+				peer.send_or_close(result);
+			}
+			if peer.handshake_done && peer.connection.is_alive() {
+				let result = peer.extensions.tick();
+				peer.send_or_close(result);
+			}
+		}
+	}
+
+	fn take_metadata(&mut self) -> Option<BValue> {
+		let metadata = match self.state.borrow_mut().take() {
+			Some(metadata) => metadata,
+			None => return None,
+		};
+
+		let mut hasher = ::sha1::Sha1::new();
+		hasher.update(&metadata);
+		if hasher.digest().bytes() != self.info.info_hash {
+			warn!("Downloaded metadata does not match info hash, retrying");
+			self.state.borrow_mut().reset();
+			return None;
+		}
+		match bencode::decode(&metadata) {
+			Ok(value) => Some(value),
+			Err(e) => {
+				warn!("Failed to decode metadata: {:?}", e);
+				None
+			}
+		}
+	}
+}
+
+impl MetadataPeer {
+	fn send_or_close(&mut self, result: Result<Vec<Message>, ExtensionError>) {
+		match result {
+			Ok(messages) => {
+				for msg in messages {
+					self.connection.send(msg);
+				}
+			}
+			Err(e) => {
+				debug!("Peer {:?} failed to send metadata: {:?}", self.address, e);
+				self.connection.close();
+			}
+		}
+	}
+}
@@ -2,181 +2,787 @@ pub mod tracker;
 pub mod connection;
 pub mod request;
 pub mod peer;
+pub mod listener;
+pub mod picker;
+pub mod choker;
+pub mod metadata;
+pub mod extension;
+pub mod fast;
+pub mod ban;
+pub mod retry;
+pub mod selection;
+pub mod stream;
 
 use std::io;
 use std::fmt;
-use std::net::{Ipv4Addr, Ipv6Addr, ToSocketAddrs};
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::path::PathBuf;
+use rand::Rng;
 use std::time::{Duration, Instant};
 use torrent::Torrent;
-use storage::{Storage, Block};
+use storage::{Storage, Block, StoreError, BLOCK_SIZE, bitfield};
+use storage::resume::ResumeFile;
+use storage::hasher::{HashPool, verify_existing};
 use downloader::tracker::{Tracker, TrackerArgs};
+use downloader::tracker::manager::TrackerManager;
 use downloader::connection::HandshakeInfo;
+use downloader::connection::event_loop::EventLoop;
 use downloader::peer::{Peer, Message};
+use downloader::listener::Listener;
+use downloader::picker::{PiecePicker, Priority};
+use downloader::choker::Choker;
+use downloader::extension::ExtensionRegistry;
+use downloader::extension::pex::{PexExtension, PexState, FLAG_SEED, FLAG_REACHABLE};
+use downloader::request::Request;
+use downloader::fast::allowed_fast_set;
+use downloader::ban::BanList;
+use downloader::retry::RetryList;
+use downloader::selection::{FileRule, file_priorities, piece_priorities};
+use downloader::stream::{StreamServer, TorrentReader};
+use dht::Dht;
+use lsd::Lsd;
 
 
 const LISTEN_PORT: u16 = 6981;
-const REQUEST_SIZE: usize = 0x4000; // 16 kb
+const REQUEST_SIZE: usize = BLOCK_SIZE;
+const WANTED_PEERS: usize = 30;
+const MAX_PEERS: usize = 200;
+// longest time main loop waits for network events,
+// timers are checked at least this often
+const TICK: u64 = 100; // milliseconds
+const RESUME_SAVE_INTERVAL: u64 = 30; // seconds
+// peers learned from sources other than tracker
+const MAX_KNOWN_PEERS: usize = 500;
+// size of allowed fast set given to peers that have few pieces
+const ALLOWED_FAST_COUNT: usize = 10;
+// data after the position readers are at that is downloaded first
+const STREAM_WINDOW: u64 = 8 * 1024 * 1024; // bytes
 
-#[derive(Clone)]
-pub struct PeerAddress {
-	pub ip: Ipv6Addr,
-	pub port: u16,
-}
+// IPv4 peers are always kept as plain IPv4 addresses, even if they
+// were given as IPv4-mapped IPv6 ones, so the same peer compares equal
+// no matter where we learned about it.
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct PeerAddress(SocketAddr);
 
 impl fmt::Debug for PeerAddress {
 	fn fmt(&self, fmt: &mut fmt::Formatter) -> Result<(), fmt::Error> {
-		write!(fmt, "{:?}:{}", self.ip, self.port)
+		write!(fmt, "{}", self.0)
 	}
 }
 
 impl PeerAddress {
-	pub fn new(ip: Ipv6Addr, port: u16) -> PeerAddress {
-		PeerAddress {
-			ip: ip,
-			port: port,
-		}
+	pub fn new<I: Into<IpAddr>>(ip: I, port: u16) -> PeerAddress {
+		PeerAddress(SocketAddr::new(ip.into().to_canonical(), port))
+	}
+
+	pub fn ip(&self) -> IpAddr {
+		self.0.ip()
+	}
+
+	pub fn port(&self) -> u16 {
+		self.0.port()
+	}
+
+	pub fn is_ipv6(&self) -> bool {
+		self.0.is_ipv6()
+	}
+
+	pub fn socket_addr(&self) -> SocketAddr {
+		self.0
+	}
+}
+
+impl From<SocketAddr> for PeerAddress {
+	fn from(address: SocketAddr) -> PeerAddress {
+		PeerAddress::new(address.ip(), address.port())
 	}
 }
 
 impl ToSocketAddrs for PeerAddress {
-	type Iter = <(Ipv6Addr, u16) as ToSocketAddrs>::Iter;
+	type Iter = ::std::option::IntoIter<SocketAddr>;
 	fn to_socket_addrs(&self) -> io::Result<Self::Iter> {
-		let ip = self.ip.to_ipv4().expect("failed to convert ipv6 address to ipv4");
-		<(Ipv4Addr, u16) as ToSocketAddrs>::to_socket_addrs(&(ip, self.port))
+		Ok(Some(self.0).into_iter())
 	}
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct DownloaderId(pub [u8; 20]);
 
+pub struct Config {
+	pub resume_file: Option<PathBuf>,
+	// how many requests can be sent to a single peer at once
+	pub pipeline_depth: usize,
+	// requests that are not answered in time are given to other peers
+	pub request_timeout: Duration,
+	// how many peers are unchoked based on their rate,
+	// not counting the optimistic unchoke
+	pub upload_slots: usize,
+	// seeding stops when any of these goals is reached,
+	// if none are given we seed until stopped explicitly
+	pub seed_ratio: Option<f64>,
+	pub seed_time: Option<Duration>,
+	// DHT is used as another source of peers
+	pub dht: bool,
+	// DHT nodes are remembered here between runs
+	pub dht_nodes_file: Option<PathBuf>,
+	// nodes used to join DHT when we don't know any others
+	pub dht_bootstrap: Vec<String>,
+	// look for peers on the local network
+	pub lsd: bool,
+	// priorities of files, later rules override earlier ones
+	pub files: Vec<FileRule>,
+	// download pieces in order, e.g. to play media while downloading
+	pub sequential: bool,
+}
+
+impl Default for Config {
+	fn default() -> Config {
+		Config {
+			resume_file: None,
+			pipeline_depth: 10,
+			request_timeout: Duration::from_secs(30),
+			upload_slots: 4,
+			seed_ratio: None,
+			seed_time: None,
+			dht: true,
+			dht_nodes_file: None,
+			dht_bootstrap: vec![
+				"router.bittorrent.com:6881".to_string(),
+				"dht.transmissionbt.com:6881".to_string(),
+				"router.utorrent.com:6881".to_string(),
+			],
+			lsd: true,
+			files: Vec::new(),
+			sequential: false,
+		}
+	}
+}
+
+// Can be used from other threads to ask downloader to quit.
+#[derive(Clone)]
+pub struct StopHandle(Arc<AtomicBool>);
+
+impl StopHandle {
+	pub fn stop(&self) {
+		self.0.store(true, Ordering::SeqCst);
+	}
+
+	fn is_stopped(&self) -> bool {
+		self.0.load(Ordering::SeqCst)
+	}
+}
+
 pub struct Downloader<S: Storage> {
 	storage: S,
+	hasher: HashPool,
 	tracker: Box<Tracker>,
 	peers: Vec<Peer>,
 	downloaded: usize,
 	uploaded: usize,
 	info: HandshakeInfo,
-	listen_port: u16,
+	event_loop: EventLoop,
+	listener: Option<Listener>,
 	piece_count: usize,
-	last_request_time: Instant,
+	picker: PiecePicker,
+	pipeline_depth: usize,
+	request_timeout: Duration,
+	choker: Choker,
+	bans: BanList,
+	retries: RetryList,
+	streams: StreamServer,
+	// length of the window of pieces in front of readers
+	stream_window: usize,
+	endgame: bool,
+	total_size: usize,
+	seed_ratio: Option<f64>,
+	seed_time: Option<Duration>,
+	seeding_since: Option<Instant>,
+	stop: StopHandle,
+	resume: Option<ResumeFile>,
+	last_resume_save: Instant,
+	extensions: ExtensionRegistry,
+	pex: Rc<RefCell<PexState>>,
+	known_peers: Vec<PeerAddress>,
+	dht: Option<Dht>,
+	dht_nodes_file: Option<PathBuf>,
+	lsd: Option<Lsd>,
 }
 
 impl<S: Storage> Downloader<S> {
-	pub fn new(info_hash: [u8; 20], torrent: Torrent) -> Downloader<S> {
+	pub fn new(info_hash: [u8; 20], torrent: Torrent, config: Config) -> Downloader<S> {
 		let info = HandshakeInfo::new(info_hash, generate_id());
+		// private torrents only get peers from their trackers
+		let private = torrent.info.private;
+		if private {
+			info!("Torrent is private, DHT, PEX and local peer discovery are off");
+		}
+		let mut dht = if private { None } else { start_dht(&config, &torrent.nodes) };
 		let piece_count = torrent.info.pieces.len();
-		let storage = S::new(torrent.info);
-		let tracker = tracker::create_tracker(TrackerArgs {
-			tracker_url: torrent.tracker_url,
+		let total_size = torrent.info.files.iter()
+			.map(|file| file.length as usize)
+			.fold(0, |a, b| a + b);
+		let files_priority = file_priorities(&torrent.info, &config.files);
+		let priorities = piece_priorities(&torrent.info, &files_priority);
+		let selected = files_priority.iter().filter(|&&p| p != Priority::Skip).count();
+		if selected < files_priority.len() {
+			info!("Downloading {}/{} files", selected, files_priority.len());
+		}
+		let resume = config.resume_file.map(|path| {
+			ResumeFile::new(path, info_hash, &torrent.info)
+		});
+		let streams = StreamServer::new(&torrent.info);
+		let stream_window = ::std::cmp::max(STREAM_WINDOW / torrent.info.piece_length, 1) as usize;
+		let mut storage = S::new(torrent.info);
+		storage.set_wanted(&priorities.iter().map(|&p| p != Priority::Skip).collect::<Vec<_>>());
+		let resumed = resume.as_ref().and_then(|resume| resume.load(&mut storage));
+		let mut hasher = HashPool::new(::num_cpus::get());
+		match resumed {
+			Some(changed) => verify_existing(&mut storage, &mut hasher, changed),
+			None => verify_existing(&mut storage, &mut hasher, 0..piece_count),
+		}
+		// resumed partial pieces might have all their blocks already
+		for index in 0..piece_count {
+			if let Some((data, hash)) = storage.unverified_piece(index) {
+				hasher.submit(index, data, hash);
+			}
+		}
+		let mut picker = PiecePicker::new(piece_count);
+		picker.set_priorities(priorities);
+		picker.set_sequential(config.sequential);
+		let completed = (0..piece_count).filter(|&i| storage.has_piece(i)).count();
+		picker.set_completed(completed);
+		let listener = match Listener::new(LISTEN_PORT) {
+			Ok(listener) => Some(listener),
+			Err(e) => {
+				warn!("Failed to listen on port {}: {:?}", LISTEN_PORT, e);
+				None
+			}
+		};
+		let tracker = TrackerManager::with_tiers(TrackerArgs {
+			tracker_url: String::new(),
 			id: info.id.clone(),
 			info_hash: info.info_hash.clone(),
 			port: LISTEN_PORT,
-		});
+		}, torrent.trackers);
+		if let Some(ref mut dht) = dht {
+			// only announce ourselves if others can connect to us
+			let port = listener.as_ref().map(|_| LISTEN_PORT);
+			dht.add_torrent(info_hash, port);
+		}
+		// local peers can only use us if we accept connections
+		let lsd = if config.lsd && listener.is_some() && !private {
+			match Lsd::new(LISTEN_PORT) {
+				Ok(mut lsd) => {
+					lsd.add_torrent(info_hash);
+					Some(lsd)
+				}
+				Err(e) => {
+					warn!("Failed to start local service discovery: {:?}", e);
+					None
+				}
+			}
+		} else {
+			None
+		};
+		let event_loop = EventLoop::new().expect("failed to set up event loop");
+		let registered = listener.as_ref().map_or(Ok(()), |listener| listener.register(&event_loop))
+			.and_then(|_| dht.as_ref().map_or(Ok(()), |dht| dht.register(&event_loop)))
+			.and_then(|_| lsd.as_ref().map_or(Ok(()), |lsd| lsd.register(&event_loop)))
+			.and_then(|_| tracker.register(&event_loop))
+			.and_then(|_| hasher.register(&event_loop))
+			.and_then(|_| streams.register(&event_loop));
+		if let Err(e) = registered {
+			// they are still checked every tick
+			warn!("Failed to watch sockets for events: {:?}", e);
+		}
+		let pex = Rc::new(RefCell::new(PexState::new(LISTEN_PORT)));
+		let mut extensions = ExtensionRegistry::new();
+		if !private {
+			let shared = pex.clone();
+			extensions.register(move || Box::new(PexExtension::new(shared.clone())));
+		}
 		Downloader {
 			storage: storage,
-			tracker: tracker,
+			hasher: hasher,
+			tracker: Box::new(tracker),
 			peers: Vec::new(),
 			downloaded: 0,
 			uploaded: 0,
 			info: info,
-			listen_port: LISTEN_PORT, // TODO: actually listen
+			event_loop: event_loop,
+			listener: listener,
 			piece_count: piece_count,
-			last_request_time: Instant::now(),
+			picker: picker,
+			pipeline_depth: config.pipeline_depth,
+			request_timeout: config.request_timeout,
+			choker: Choker::new(config.upload_slots),
+			bans: BanList::new(),
+			retries: RetryList::new(),
+			streams: streams,
+			stream_window: stream_window,
+			endgame: false,
+			total_size: total_size,
+			seed_ratio: config.seed_ratio,
+			seed_time: config.seed_time,
+			seeding_since: None,
+			stop: StopHandle(Arc::new(AtomicBool::new(false))),
+			resume: resume,
+			last_resume_save: Instant::now(),
+			extensions: extensions,
+			pex: pex,
+			known_peers: Vec::new(),
+			dht: dht,
+			dht_nodes_file: config.dht_nodes_file,
+			lsd: lsd,
 		}
 	}
 
+	pub fn stop_handle(&self) -> StopHandle {
+		self.stop.clone()
+	}
+
+	// Reader of the whole torrent (files one after another) that can be
+	// used from other threads while the downloader is running.
+	pub fn reader(&self) -> TorrentReader {
+		self.streams.reader()
+	}
+
+	pub fn file_reader(&self, index: usize) -> Option<TorrentReader> {
+		self.streams.file_reader(index)
+	}
+
 	pub fn run(&mut self) {
 		info!("Running downloader");
-		while !self.storage.is_complete() {
+		while !self.should_stop() {
+			if self.seeding_since.is_none() && self.storage.is_complete() {
+				info!("Download complete, seeding");
+				self.seeding_since = Some(Instant::now());
+				self.save_resume_state(true);
+			}
+			if self.seeding_since.is_some() {
+				self.disconnect_seeds();
+			}
 			self.update_tracker();
+			self.update_dht();
+			self.update_lsd();
 			self.remove_dead_connections();
+			self.accept_connections();
 			self.open_new_connections();
 			self.process_messages();
+			self.process_hash_results();
+			self.serve_reads();
+			self.update_extensions();
+			self.update_pex();
+			self.update_choking();
+			self.serve_uploads();
 			self.request_pieces();
-			::std::thread::sleep(Duration::from_millis(500));
+			self.save_resume_state(false);
+			self.event_loop.poll(Duration::from_millis(TICK));
+		}
+		self.streams.close();
+		self.save_resume_state(true);
+		let left = self.storage.bytes_missing();
+		self.tracker.stop(self.downloaded, self.uploaded, left);
+		save_dht_nodes(&self.dht, &self.dht_nodes_file);
+		info!("Downloader stopped");
+	}
+
+	fn should_stop(&self) -> bool {
+		if self.stop.is_stopped() {
+			return true;
+		}
+		let seeding_since = match self.seeding_since {
+			Some(time) => time,
+			None => return false,
+		};
+		if let Some(ratio) = self.seed_ratio {
+			if self.uploaded as f64 >= ratio * self.total_size as f64 {
+				info!("Reached share ratio {}", ratio);
+				return true;
+			}
+		}
+		if let Some(time) = self.seed_time {
+			if Instant::now() - seeding_since >= time {
+				info!("Seeded for {} seconds", time.as_secs());
+				return true;
+			}
+		}
+		false
+	}
+
+	fn disconnect_seeds(&mut self) {
+		for peer in &mut self.peers {
+			if peer.is_seed() {
+				debug!("Both {:?} and us are seeds, disconnecting", peer.address());
+				peer.disconnect();
+			}
+		}
+	}
+
+	fn save_resume_state(&mut self, force: bool) {
+		let passed = Instant::now() - self.last_resume_save;
+		if !force && passed < Duration::from_secs(RESUME_SAVE_INTERVAL) {
+			return;
+		}
+		self.last_resume_save = Instant::now();
+
+		if let Some(ref resume) = self.resume {
+			if let Err(e) = resume.save(&mut self.storage) {
+				warn!("Failed to save resume state: {:?}", e);
+			}
 		}
-		info!("Download complete");
 	}
 
 	fn process_messages(&mut self) {
+		let mut received = Vec::new();
 		// TODO: too much nesting, refactor
 		for peer in &mut self.peers {
 			while let Some(msg) = peer.receive() {
 				match msg {
-					Message::Request(part, offset, length) => {
-						match self.storage.get_piece(part as usize) {
-							Some(piece) => {
-								let off = offset as usize;
-								let len = length as usize;
-								if off + len > piece.len() || len > REQUEST_SIZE {
-									// client sent bad request
-									peer.disconnect();
-								} else {
-									let data = &piece[off..(off + len)];
-									peer.send(Message::Piece(
-										part,
-										offset,
-										data.to_vec()));
-									self.uploaded += len;
-								}
-							}
-							None => {
-								// we don't have the piece :(
-							}
-						}
+					Message::Handshake => {
+						let bitfield = bitfield(&mut self.storage, self.piece_count);
+						peer.send(Message::Bitfield(bitfield));
 					}
 					Message::Piece(part, offset, payload) => {
-						let block = Block::new(part as usize, offset as usize, payload);
+						received.push(Request::new(part, offset, payload.len()));
+						let block = Block::from_peer(part, offset, payload, peer.address().clone());
 						match self.storage.store_block(block) {
 							Ok(new_bytes) => {
 								self.downloaded += new_bytes;
+								let unverified = if new_bytes > 0 {
+									self.storage.unverified_piece(part)
+								} else {
+									None
+								};
+								if let Some((data, hash)) = unverified {
+									self.hasher.submit(part, data, hash);
+								}
 							}
-							Err(_) => {
+							Err(StoreError::BadBlock) => {
 								// peer sent bad block
 								peer.disconnect();
 							}
+							Err(StoreError::Io(e)) => {
+								warn!("Failed to store block of piece #{}: {:?}, stopping", part, e);
+								self.stop.stop();
+							}
+						}
+					}
+					Message::Have(piece) => {
+						self.picker.peer_has(piece);
+						if self.picker.is_wanted(piece) && !self.storage.has_piece(piece) {
+							peer.set_interested(true);
+						}
+					}
+					Message::Bitfield(_) => {
+						let mut interesting = false;
+						for piece in peer.pieces() {
+							self.picker.peer_has(piece);
+							interesting = interesting
+								|| self.picker.is_wanted(piece) && !self.storage.has_piece(piece);
+						}
+						peer.set_interested(interesting);
+
+						// help peers that have almost nothing to get started
+						if !peer.fast() || peer.pieces().len() >= ALLOWED_FAST_COUNT {
+							continue;
+						}
+						if let IpAddr::V4(ip) = peer.address().ip() {
+							let info_hash = &self.info.info_hash;
+							let storage = &mut self.storage;
+							let allowed = allowed_fast_set(ip, info_hash, self.piece_count, ALLOWED_FAST_COUNT)
+								.into_iter()
+								.filter(|&piece| storage.has_piece(piece))
+								.collect();
+							peer.allow_fast(allowed);
+						}
+					}
+				}
+			}
+		}
+
+		if self.endgame {
+			// block arrived, other peers don't need to send it anymore
+			for request in &received {
+				for peer in &mut self.peers {
+					peer.cancel(request);
+				}
+			}
+		}
+	}
+
+	// Pieces that were hashed on the pool become available (or are
+	// downloaded again if their hash was wrong).
+	fn process_hash_results(&mut self) {
+		for result in self.hasher.results() {
+			let sources = self.storage.piece_sources(result.piece);
+			self.storage.piece_verified(result.piece, result.correct);
+			let banned = if result.correct {
+				self.bans.piece_passed(result.piece, sources)
+			} else {
+				self.bans.piece_failed(result.piece, sources)
+			};
+			for peer in self.peers.iter_mut().filter(|p| banned.contains(p.address())) {
+				peer.disconnect();
+			}
+			if !result.correct {
+				continue;
+			}
+			self.picker.piece_completed(result.piece);
+			for peer in self.peers.iter_mut().filter(|p| p.is_connected()) {
+				peer.send(Message::Have(result.piece));
+			}
+		}
+	}
+
+	// Gives downloaded pieces to readers and moves the window
+	// of pieces that are requested first to where they read.
+	fn serve_reads(&mut self) {
+		let waiting = self.streams.serve(&mut self.storage);
+		let skipped = waiting.into_iter()
+			.filter(|&piece| !self.picker.is_wanted(piece))
+			.collect::<Vec<_>>();
+		if skipped.len() > 0 {
+			// readers need pieces of skipped files too
+			for piece in skipped {
+				self.picker.set_priority(piece, Priority::Normal);
+			}
+			self.storage.set_wanted(&self.picker.wanted());
+			self.seeding_since = None;
+			self.update_interest();
+		}
+		if let Some(position) = self.streams.position() {
+			self.picker.set_window(position, self.stream_window);
+		}
+	}
+
+	fn serve_uploads(&mut self) {
+		for peer in &mut self.peers {
+			while let Some(r) = peer.next_upload() {
+				match self.storage.get_piece(r.piece) {
+					Some(piece) => {
+						if r.offset + r.length > piece.len() || r.length > REQUEST_SIZE {
+							// client sent bad request
+							peer.disconnect();
+							break;
+						} else {
+							let data = &piece[r.offset..(r.offset + r.length)];
+							peer.send(Message::Piece(r.piece, r.offset, data.to_vec()));
+							self.uploaded += r.length;
 						}
 					}
+					None => {
+						// we don't have the piece :(
+						peer.reject(&r);
+					}
 				}
 			}
 		}
 	}
 
+	fn update_choking(&mut self) {
+		let seeding = self.storage.is_complete();
+		if self.choker.update(&mut self.peers, seeding) {
+			// peers might no longer have anything we need
+			self.update_interest();
+		}
+	}
+
+	fn update_interest(&mut self) {
+		for peer in &mut self.peers {
+			let storage = &mut self.storage;
+			let picker = &self.picker;
+			let interesting = peer.pieces()
+				.into_iter()
+				.any(|p| picker.is_wanted(p) && !storage.has_piece(p));
+			peer.set_interested(interesting);
+		}
+	}
+
 	fn request_pieces(&mut self) {
-		let now = Instant::now();
-		let passed = now - self.last_request_time;
-		if passed < Duration::from_secs(5) {
-			return;
+		let timeout = self.request_timeout;
+		for peer in &mut self.peers {
+			let timed_out = peer.take_timed_out(timeout);
+			if timed_out.len() > 0 {
+				debug!("{} requests to {:?} timed out", timed_out.len(), peer.address());
+				self.bans.release(peer.address());
+			}
 		}
-		self.last_request_time = Instant::now();
 
-		let requests = self.storage
-			.requests()
-			.flat_map(|r| r.split_request(REQUEST_SIZE))
-			// TODO: figure out how many
-			.take(100)
+		let depth = self.pipeline_depth;
+		let mut free_peers = (0..self.peers.len())
+			.filter(|&i| {
+				let peer = &self.peers[i];
+				(!peer.choked() || peer.has_allowed_fast()) && peer.pending_requests() < depth
+			})
 			.collect::<Vec<_>>();
+		if free_peers.len() == 0 {
+			return;
+		}
+		// don't always favor peers that connected first
+		::rand::thread_rng().shuffle(&mut free_peers);
 
-		for r in requests {
-			if let Some(peer) = self.pick_peer_for_request(r.piece) {
-				peer.send(Message::Request(r.piece, r.offset, r.length));
+		let mut outstanding: HashMap<usize, Vec<Request>> = HashMap::new();
+		for peer in &self.peers {
+			for r in peer.outstanding_requests() {
+				outstanding.entry(r.piece).or_insert_with(Vec::new).push(r.clone());
 			}
 		}
+
+		let mut all_requested = true;
+		for requests in self.picker.order(self.storage.requests().collect()) {
+			if free_peers.len() == 0 {
+				all_requested = false;
+				break;
+			}
+			let blocks = requests.iter()
+				.flat_map(|r| r.split_request(REQUEST_SIZE))
+				.filter(|r| {
+					outstanding.get(&r.piece)
+						.map(|requests| !requests.iter().any(|o| o.intersects(r)))
+						.unwrap_or(true)
+				});
+			for r in blocks {
+				let position = free_peers.iter().position(|&i| {
+					let peer = &self.peers[i];
+					peer.does_have(r.piece) && peer.can_request(r.piece)
+						&& self.bans.may_request(r.piece, peer.address())
+				});
+				match position {
+					Some(position) => {
+						let index = free_peers[position];
+						self.picker.piece_requested(r.piece);
+						self.bans.piece_requested(r.piece, self.peers[index].address());
+						self.peers[index].request(r);
+						if self.peers[index].pending_requests() >= depth {
+							free_peers.remove(position);
+						}
+					}
+					None => {
+						// nobody free can take rest of the piece either
+						all_requested = false;
+						break;
+					}
+				}
+			}
+		}
+
+		// with nothing requested there is nothing to duplicate, pieces
+		// are just not available from anyone right now
+		let endgame = all_requested && self.peers.iter().any(|peer| peer.pending_requests() > 0);
+		if endgame != self.endgame {
+			self.endgame = endgame;
+			if self.endgame {
+				info!("Entering endgame mode");
+			}
+		}
+		if self.endgame {
+			self.request_endgame(free_peers);
+		}
 	}
 
-	fn pick_peer_for_request(&mut self, piece: usize) -> Option<&mut Peer> {
-		if self.peers.len() == 0 {
-			return None;
+	// Every missing block is already requested from someone, so ask other
+	// peers for them too - whichever answers first wins, and rest of
+	// the requests are cancelled.
+	fn request_endgame(&mut self, free_peers: Vec<usize>) {
+		let mut remaining = Vec::new();
+		for peer in &self.peers {
+			for r in peer.outstanding_requests() {
+				if !remaining.contains(r) {
+					remaining.push(r.clone());
+				}
+			}
 		}
-		let range = (0..(self.peers.len())).into_iter().cycle();
-		let start_with = ::rand::random::<usize>() % self.peers.len();
-		let range = range.skip(start_with).take(self.peers.len());
-		for i in range {
-			if self.peers[i].does_have(piece) {
-				return Some(&mut self.peers[i]);
+
+		let depth = self.pipeline_depth;
+		for index in free_peers {
+			let peer = &mut self.peers[index];
+			for r in &remaining {
+				if peer.pending_requests() >= depth {
+					break;
+				}
+				let allowed = peer.can_request(r.piece) && self.bans.may_request(r.piece, peer.address());
+				if peer.does_have(r.piece) && allowed && !peer.has_requested(r) {
+					peer.request(r.clone());
+				}
+			}
+		}
+	}
+
+	fn update_extensions(&mut self) {
+		for peer in &mut self.peers {
+			peer.update_extensions();
+		}
+	}
+
+	// Shares our connections with PEX handlers and picks up
+	// the peers they learned about.
+	fn update_pex(&mut self) {
+		let connected = self.peers.iter()
+			.filter(|peer| peer.is_connected())
+			.filter_map(|peer| {
+				let flags = if peer.is_seed() { FLAG_SEED } else { 0 };
+				if !peer.is_incoming() {
+					return Some((peer.address().clone(), flags | FLAG_REACHABLE));
+				}
+				// incoming peers can only be advertised if they
+				// told us on which port they listen
+				peer.extensions().peer_handshake()
+					.and_then(|handshake| handshake.get(&b"p"[..]))
+					.and_then(|port| port.get_int())
+					.filter(|&port| port > 0 && port <= 0xFFFF)
+					.map(|port| (PeerAddress::new(peer.address().ip(), port as u16), flags))
+			})
+			.collect();
+		let learned = {
+			let mut pex = self.pex.borrow_mut();
+			pex.set_connected(connected);
+			pex.take_learned()
+		};
+		for address in learned {
+			self.add_known_peer(address);
+		}
+	}
+
+	fn add_known_peer(&mut self, address: PeerAddress) {
+		if self.known_peers.contains(&address) {
+			return;
+		}
+		if self.known_peers.len() >= MAX_KNOWN_PEERS {
+			self.known_peers.remove(0);
+		}
+		self.known_peers.push(address);
+	}
+
+	fn update_dht(&mut self) {
+		let found = match self.dht {
+			Some(ref mut dht) => {
+				dht.update();
+				dht.take_peers(&self.info.info_hash)
 			}
+			None => return,
+		};
+		for address in found {
+			self.add_known_peer(address);
+		}
+	}
+
+	fn update_lsd(&mut self) {
+		let found = match self.lsd {
+			Some(ref mut lsd) => {
+				lsd.update();
+				lsd.take_peers(&self.info.info_hash)
+			}
+			None => return,
+		};
+		for address in found {
+			self.add_known_peer(address);
 		}
-		None
 	}
 
 	fn update_tracker(&mut self) {
@@ -187,23 +793,55 @@ impl<S: Storage> Downloader<S> {
 	}
 
 	fn remove_dead_connections(&mut self) {
+		for peer in self.peers.iter().filter(|peer| !peer.is_alive()) {
+			for piece in peer.pieces() {
+				self.picker.peer_lost(piece);
+			}
+			self.bans.release(peer.address());
+			if !peer.is_incoming() {
+				self.retries.connection_lost(peer.address(), peer.handshake_done());
+			}
+		}
 		self.peers.retain(|ref peer| peer.is_alive());
 	}
 
+	fn accept_connections(&mut self) {
+		loop {
+			let (stream, address) = match self.listener.as_mut().and_then(Listener::accept) {
+				Some(incoming) => incoming,
+				None => break,
+			};
+			if self.peers.len() >= MAX_PEERS {
+				debug!("Too many peers, rejecting {:?}", address);
+				continue;
+			}
+			if self.bans.is_banned(&address) {
+				debug!("Rejecting banned peer {:?}", address);
+				continue;
+			}
+			let connection = self.event_loop.accept(self.info.clone(), address.clone(), stream);
+			let mut peer = Peer::new(
+				Box::new(connection),
+				address,
+				self.piece_count,
+				self.info.clone(),
+				self.extensions.create());
+			peer.set_incoming();
+			self.peers.push(peer);
+		}
+	}
+
 	fn open_new_connections(&mut self) {
-		while self.peers.len() < 8 {
+		while self.peers.len() < WANTED_PEERS {
 			match self.pick_peer() {
 				Some(address) => {
-					let connection = connection::bt::BtConnection::new(self.info.clone(), address.clone());
-					let mut peer = Peer::new(
+					let connection = self.event_loop.connect(self.info.clone(), address.clone());
+					let peer = Peer::new(
 						Box::new(connection),
 						address,
 						self.piece_count,
-						self.info.clone());
-					// TODO: properly maintain and change state
-					// this is for debugging only
-					peer.set_choking(false);
-					peer.set_interested(true);
+						self.info.clone(),
+						self.extensions.create());
 					self.peers.push(peer);
 				}
 				None => {
@@ -215,12 +853,51 @@ impl<S: Storage> Downloader<S> {
 	}
 
 	fn pick_peer(&self) -> Option<PeerAddress> {
-		let count = self.tracker.peers().count();
-		if count == 0 {
+		let bans = &self.bans;
+		let retries = &self.retries;
+		let peers = &self.peers;
+		// tracker and other sources may know the same peer
+		let mut seen = HashSet::new();
+		let candidates = self.tracker.peers()
+			.chain(self.known_peers.iter())
+			.filter(|address| seen.insert(*address))
+			.filter(|address| !bans.is_banned(address) && retries.may_connect(address))
+			.filter(|address| !peers.iter().any(|peer| peer.address() == *address))
+			.collect::<Vec<_>>();
+		if candidates.is_empty() {
 			None
 		} else {
-			let index = ::rand::random::<usize>() % count;
-			self.tracker.peers().nth(index).cloned()
+			let index = ::rand::random::<usize>() % candidates.len();
+			Some(candidates[index].clone())
+		}
+	}
+}
+
+// DHT node uses the same port number as peer connections, only over UDP
+fn start_dht(config: &Config, nodes: &[String]) -> Option<Dht> {
+	if !config.dht {
+		return None;
+	}
+	let mut dht = match Dht::new(("0.0.0.0", LISTEN_PORT)) {
+		Ok(dht) => dht,
+		Err(e) => {
+			warn!("Failed to start DHT on port {}: {:?}", LISTEN_PORT, e);
+			return None;
+		}
+	};
+	if let Some(ref path) = config.dht_nodes_file {
+		dht.load_nodes(path);
+	}
+	for node in nodes.iter().chain(config.dht_bootstrap.iter()) {
+		dht.add_bootstrap(node.as_str());
+	}
+	Some(dht)
+}
+
+fn save_dht_nodes(dht: &Option<Dht>, path: &Option<PathBuf>) {
+	if let (&Some(ref dht), &Some(ref path)) = (dht, path) {
+		if let Err(e) = dht.save_nodes(path) {
+			warn!("Failed to save DHT nodes: {:?}", e);
 		}
 	}
 }
@@ -240,3 +917,199 @@ fn generate_id() -> DownloaderId {
 	}
 	DownloaderId(id)
 }
+
+
+#[cfg(test)]
+mod test {
+	use std::cell::RefCell;
+	use std::net::Ipv4Addr;
+	use std::path::PathBuf;
+	use std::rc::Rc;
+	use std::thread;
+	use std::time::{Duration, Instant};
+	use downloader::connection::{self, HandshakeInfo, InMessage};
+	use downloader::connection::fake::{FakeConnection, Wire};
+	use downloader::peer::Peer;
+	use downloader::request::Request;
+	use storage::{Storage, Block};
+	use storage::piece_hash as hash;
+	use storage::memory::MemoryStorage;
+	use torrent::{Torrent, TorrentInfo, File};
+	use super::{Downloader, DownloaderId, PeerAddress, Config, REQUEST_SIZE};
+
+	// every piece is two requests long and filled with its index
+	fn piece_data(piece: usize) -> Vec<u8> {
+		vec![piece as u8; 2 * REQUEST_SIZE]
+	}
+
+	fn downloader(piece_count: usize, config: Config) -> Downloader<MemoryStorage> {
+		let torrent = Torrent {
+			trackers: Vec::new(),
+			nodes: Vec::new(),
+			info: TorrentInfo {
+				root: PathBuf::from("root"),
+				piece_length: 2 * REQUEST_SIZE as u64,
+				pieces: (0..piece_count).map(|piece| hash(&piece_data(piece))).collect(),
+				files: vec![File { path: PathBuf::from("x"), length: (piece_count * 2 * REQUEST_SIZE) as u64 }],
+				private: false,
+				single_file: false,
+			},
+		};
+		Downloader::new([1; 20], torrent, Config { dht: false, lsd: false, ..config })
+	}
+
+	// Connects a peer that has all pieces and unchokes us, messages we
+	// sent during the handshake are left on the wire.
+	fn add_peer(downloader: &mut Downloader<MemoryStorage>, last: u8) -> Rc<RefCell<Wire>> {
+		add_peer_with(downloader, last, connection::Message::HaveAll)
+	}
+
+	fn add_peer_with(downloader: &mut Downloader<MemoryStorage>, last: u8, pieces: connection::Message) -> Rc<RefCell<Wire>> {
+		let (conn, wire) = FakeConnection::new();
+		let address = PeerAddress::new(Ipv4Addr::new(10, 0, 0, last), 6881);
+		let peer = Peer::new(
+			Box::new(conn),
+			address,
+			downloader.piece_count,
+			downloader.info.clone(),
+			downloader.extensions.create());
+		downloader.peers.push(peer);
+		{
+			let mut wire = wire.borrow_mut();
+			let remote = HandshakeInfo::new(downloader.info.info_hash, DownloaderId([last; 20]));
+			wire.incoming.push_back(InMessage::Handshake(remote));
+			wire.incoming.push_back(InMessage::Normal(pieces));
+			wire.incoming.push_back(InMessage::Normal(connection::Message::Unchoke));
+		}
+		downloader.process_messages();
+		wire
+	}
+
+	fn requests(wire: &Rc<RefCell<Wire>>) -> Vec<Request> {
+		wire.borrow_mut().take_sent().into_iter()
+			.filter_map(|msg| match msg {
+				connection::Message::Request(piece, offset, length) => Some(Request::new(piece, offset, length)),
+				_ => None,
+			})
+			.collect()
+	}
+
+	#[test]
+	fn requests_are_pipelined() {
+		let mut downloader = downloader(8, Config { pipeline_depth: 3, ..Config::default() });
+		let first = add_peer(&mut downloader, 1);
+		let second = add_peer(&mut downloader, 2);
+		downloader.request_pieces();
+		let (first, second) = (requests(&first), requests(&second));
+		assert_eq!((first.len(), second.len()), (3, 3));
+		assert!(first.iter().all(|r| r.length == REQUEST_SIZE && !second.contains(r)));
+
+		// nothing more until some requests are answered
+		downloader.request_pieces();
+		assert!(downloader.peers.iter().all(|peer| peer.pending_requests() == 3));
+	}
+
+	#[test]
+	fn timed_out_requests_go_to_others() {
+		let config = Config { pipeline_depth: 2, request_timeout: Duration::from_secs(0), ..Config::default() };
+		let mut downloader = downloader(2, config);
+		let slow = add_peer(&mut downloader, 1);
+		downloader.request_pieces();
+		let requested = requests(&slow);
+		assert_eq!(requested.len(), 2);
+		// with fast extension choking keeps the requests, they just never come
+		slow.borrow_mut().incoming.push_back(InMessage::Normal(connection::Message::Choke));
+		downloader.process_messages();
+
+		let other = add_peer(&mut downloader, 2);
+		downloader.request_pieces();
+		assert_eq!(requests(&other), requested);
+		assert_eq!(downloader.peers[0].pending_requests(), 0);
+	}
+
+	#[test]
+	fn endgame_requests_and_cancels_last_blocks() {
+		let mut downloader = downloader(1, Config { pipeline_depth: 2, ..Config::default() });
+		let first = add_peer(&mut downloader, 1);
+		let second = add_peer(&mut downloader, 2);
+		downloader.request_pieces();
+		assert!(downloader.endgame);
+		let all = vec![Request::new(0, 0, REQUEST_SIZE), Request::new(0, REQUEST_SIZE, REQUEST_SIZE)];
+		assert_eq!(requests(&first), all);
+		assert_eq!(requests(&second), all);
+
+		let data = piece_data(0);
+		let block = connection::Message::Piece(0, 0, data[..REQUEST_SIZE].to_vec());
+		first.borrow_mut().incoming.push_back(InMessage::Normal(block));
+		downloader.process_messages();
+		assert_eq!(second.borrow_mut().take_sent(), vec![connection::Message::Cancel(0, 0, REQUEST_SIZE)]);
+		assert!(first.borrow_mut().take_sent().is_empty());
+		assert_eq!(downloader.peers[1].pending_requests(), 1);
+	}
+
+	#[test]
+	fn no_endgame_without_requests() {
+		let mut downloader = downloader(2, Config::default());
+		add_peer_with(&mut downloader, 1, connection::Message::HaveNone);
+		downloader.request_pieces();
+		assert!(!downloader.endgame);
+	}
+
+	#[test]
+	fn bitfield_after_handshake() {
+		let mut downloader = downloader(9, Config::default());
+		downloader.storage.store_block(Block::new(0, 0, piece_data(0))).ok().unwrap();
+		downloader.storage.piece_verified(0, true);
+		let wire = add_peer_with(&mut downloader, 1, connection::Message::HaveNone);
+		assert_eq!(wire.borrow().sent[0], connection::Message::Bitfield(vec![0x80, 0]));
+	}
+
+	#[test]
+	fn verified_pieces_are_announced() {
+		let mut downloader = downloader(1, Config::default());
+		let seed = add_peer(&mut downloader, 1);
+		let leech = add_peer_with(&mut downloader, 2, connection::Message::HaveNone);
+		assert_eq!(leech.borrow_mut().take_sent(), vec![connection::Message::HaveNone]);
+		downloader.request_pieces();
+		let data = piece_data(0);
+		for r in requests(&seed) {
+			let block = data[r.offset..(r.offset + r.length)].to_vec();
+			seed.borrow_mut().incoming.push_back(InMessage::Normal(connection::Message::Piece(0, r.offset, block)));
+		}
+		downloader.process_messages();
+
+		// hashing happens on other threads
+		let started = Instant::now();
+		while !downloader.storage.has_piece(0) && started.elapsed() < Duration::from_secs(5) {
+			thread::sleep(Duration::from_millis(10));
+			downloader.process_hash_results();
+		}
+		assert!(downloader.storage.has_piece(0));
+		assert_eq!(leech.borrow_mut().take_sent(), vec![connection::Message::Have(0)]);
+	}
+
+	#[test]
+	fn seeding_goals() {
+		let mut ratio = downloader(2, Config { seed_ratio: Some(1.5), ..Config::default() });
+		ratio.uploaded = 2 * ratio.total_size;
+		// goals only count once we are seeding
+		assert!(!ratio.should_stop());
+		ratio.seeding_since = Some(Instant::now());
+		ratio.uploaded = ratio.total_size;
+		assert!(!ratio.should_stop());
+		ratio.uploaded = ratio.total_size * 3 / 2;
+		assert!(ratio.should_stop());
+
+		let mut timed = downloader(2, Config { seed_time: Some(Duration::from_secs(60)), ..Config::default() });
+		timed.seeding_since = Some(Instant::now());
+		assert!(!timed.should_stop());
+		timed.seeding_since = Some(Instant::now() - Duration::from_secs(60));
+		assert!(timed.should_stop());
+
+		// without goals we seed until stopped
+		let unlimited = downloader(2, Config::default());
+		assert!(!unlimited.should_stop());
+		unlimited.stop_handle().stop();
+		assert!(unlimited.should_stop());
+	}
+}
@@ -1,11 +1,25 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
 use downloader::PeerAddress;
 use downloader::connection;
+use downloader::request::Request;
 use downloader::connection::{Connection, InMessage, HandshakeInfo};
+use downloader::extension::{Extensions, ExtensionError};
 
 
+// requests that peer can have queued to us at once,
+// anything above that is dropped
+const MAX_UPLOAD_QUEUE: usize = 250;
+// uploads are only taken from the queue while less than this is waiting
+// to be written, so peers that don't read cannot make us buffer everything
+const MAX_UNSENT_BYTES: usize = 256 * 1024;
+
 pub enum Message {
-	Request(usize, usize, usize),
+	// handshake is complete, we can start sending messages
+	Handshake,
 	Piece(usize, usize, Vec<u8>),
+	Have(usize),
+	Bitfield(Vec<u8>),
 }
 
 pub struct Peer {
@@ -15,10 +29,23 @@ pub struct Peer {
 	peer_info: Option<HandshakeInfo>,
 	piece_count: usize,
 	have: Vec<u8>,
+	bitfield_allowed: bool,
+	requests: Vec<(Request, Instant)>,
+	upload_queue: VecDeque<Request>,
+	downloaded: usize,
+	uploaded: usize,
 	self_choked: bool,
 	self_interested: bool,
 	peer_choked: bool,
 	peer_interested: bool,
+	extensions: Extensions,
+	// pieces peer may request from us while choked, and the other way around
+	allowed_fast_out: Vec<usize>,
+	allowed_fast_in: Vec<usize>,
+	// extended handshake is sent after our bitfield
+	extensions_pending: bool,
+	// peer connected to us, its address has no listening port
+	incoming: bool,
 }
 
 impl Peer {
@@ -26,7 +53,8 @@ impl Peer {
 			connection: Box<Connection>,
 			peer: PeerAddress,
 			piece_count: usize,
-			info: HandshakeInfo) -> Peer {
+			info: HandshakeInfo,
+			extensions: Extensions) -> Peer {
 		let bitfield_bytes = (piece_count + 7) / 8;
 		Peer {
 			connection: connection,
@@ -35,19 +63,59 @@ impl Peer {
 			peer_info: None,
 			piece_count: piece_count,
 			have: vec![0; bitfield_bytes],
+			bitfield_allowed: true,
+			requests: Vec::new(),
+			upload_queue: VecDeque::new(),
+			downloaded: 0,
+			uploaded: 0,
 			self_choked: true,
 			peer_choked: true,
 			self_interested: false,
 			peer_interested: false,
+			extensions: extensions,
+			allowed_fast_out: Vec::new(),
+			allowed_fast_in: Vec::new(),
+			extensions_pending: false,
+			incoming: false,
 		}
 	}
 
+	pub fn set_incoming(&mut self) {
+		self.incoming = true;
+	}
+
+	pub fn is_incoming(&self) -> bool {
+		self.incoming
+	}
+
 	pub fn send(&mut self, msg: Message) {
 		let msg = match msg {
-			Message::Request(piece, off, len) =>
-				connection::Message::Request(piece, off, len),
-			Message::Piece(piece, off, data) =>
-				connection::Message::Piece(piece, off, data),
+			Message::Piece(piece, off, data) => {
+				self.uploaded += data.len();
+				connection::Message::Piece(piece, off, data)
+			}
+			Message::Have(piece) =>
+				connection::Message::Have(piece),
+			Message::Bitfield(bits) => {
+				let count = (0..self.piece_count).filter(|&piece| bit_set(&bits, piece)).count();
+				if !self.fast() {
+					// peers assume we have nothing if bitfield is not sent
+					if count == 0 {
+						return;
+					}
+					connection::Message::Bitfield(bits)
+				} else if count == 0 {
+					connection::Message::HaveNone
+				} else if count == self.piece_count {
+					connection::Message::HaveAll
+				} else {
+					connection::Message::Bitfield(bits)
+				}
+			}
+			Message::Handshake => {
+				// connection sends handshake on its own
+				return;
+			}
 		};
 		self.connection.send(msg);
 	}
@@ -70,7 +138,9 @@ impl Peer {
 						debug!("Peer {:?} is me, disconnecting", self.peer);
 						self.connection.close();
 					} else {
+						self.extensions_pending = peer.supports_extensions();
 						self.peer_info = Some(peer);
+						return Some(Message::Handshake);
 					}
 				}
 				InMessage::Normal(msg) => {
@@ -83,6 +153,156 @@ impl Peer {
 		}
 	}
 
+	// Sends our extended handshake once the handshake is done, and
+	// lets extensions send their own messages.
+	pub fn update_extensions(&mut self) {
+		if !self.is_connected() {
+			return;
+		}
+		if self.extensions_pending {
+			self.extensions_pending = false;
+			let handshake = self.extensions.handshake();
+			self.connection.send(handshake);
+		}
+		if self.extensions.peer_handshake().is_some() {
+			let result = self.extensions.tick();
+			self.send_extension_messages(result);
+		}
+	}
+
+	pub fn extensions(&self) -> &Extensions {
+		&self.extensions
+	}
+
+	fn send_extension_messages(&mut self, result: Result<Vec<connection::Message>, ExtensionError>) {
+		match result {
+			Ok(messages) => {
+				for msg in messages {
+					self.connection.send(msg);
+				}
+			}
+			Err(e) => {
+				debug!("Peer {:?} sent bad extension message: {:?}, disconnecting", self.peer, e);
+				self.connection.close();
+			}
+		}
+	}
+
+	// both sides support the fast extension
+	pub fn fast(&self) -> bool {
+		self.info.supports_fast() &&
+			self.peer_info.as_ref().map(|info| info.supports_fast()).unwrap_or(false)
+	}
+
+	// Lets peer request these pieces even while we choke it.
+	pub fn allow_fast(&mut self, pieces: Vec<usize>) {
+		if !self.fast() {
+			return;
+		}
+		for piece in pieces {
+			if !self.allowed_fast_out.contains(&piece) {
+				self.allowed_fast_out.push(piece);
+				self.connection.send(connection::Message::AllowedFast(piece));
+			}
+		}
+	}
+
+	// whether piece can be requested from peer right now
+	pub fn can_request(&self, piece: usize) -> bool {
+		!self.peer_choked || self.allowed_fast_in.contains(&piece)
+	}
+
+	// peer allows requesting some pieces while choked
+	pub fn has_allowed_fast(&self) -> bool {
+		self.allowed_fast_in.len() > 0
+	}
+
+	// Tells peer its request won't be served. Without fast extension
+	// peer only finds out by timing out.
+	pub fn reject(&mut self, request: &Request) {
+		if self.fast() {
+			self.connection.send(connection::Message::RejectRequest(
+				request.piece,
+				request.offset,
+				request.length));
+		}
+	}
+
+	pub fn request(&mut self, request: Request) {
+		self.connection.send(connection::Message::Request(
+			request.piece,
+			request.offset,
+			request.length));
+		self.requests.push((request, Instant::now()));
+	}
+
+	pub fn has_requested(&self, request: &Request) -> bool {
+		self.requests.iter().any(|&(ref r, _)| r == request)
+	}
+
+	pub fn cancel(&mut self, request: &Request) {
+		if self.has_requested(request) {
+			self.requests.retain(|&(ref r, _)| r != request);
+			self.connection.send(connection::Message::Cancel(
+				request.piece,
+				request.offset,
+				request.length));
+		}
+	}
+
+	// next request that peer wants us to serve
+	pub fn next_upload(&mut self) -> Option<Request> {
+		if self.connection.pending_send_bytes() >= MAX_UNSENT_BYTES {
+			return None;
+		}
+		// while choked only allowed fast requests stay queued
+		self.upload_queue.pop_front()
+	}
+
+	pub fn pending_requests(&self) -> usize {
+		self.requests.len()
+	}
+
+	pub fn outstanding_requests<'a>(&'a self) -> Box<Iterator<Item=&'a Request> + 'a> {
+		Box::new(self.requests.iter().map(|&(ref request, _)| request))
+	}
+
+	pub fn take_timed_out(&mut self, timeout: Duration) -> Vec<Request> {
+		let now = Instant::now();
+		let mut timed_out = Vec::new();
+		let mut i = 0;
+		while i < self.requests.len() {
+			if now - self.requests[i].1 >= timeout {
+				timed_out.push(self.requests.remove(i).0);
+			} else {
+				i += 1;
+			}
+		}
+		timed_out
+	}
+
+	// bytes downloaded from and uploaded to this peer
+	// since the last time this was called
+	pub fn take_transfer_stats(&mut self) -> (usize, usize) {
+		let stats = (self.downloaded, self.uploaded);
+		self.downloaded = 0;
+		self.uploaded = 0;
+		stats
+	}
+
+	pub fn address(&self) -> &PeerAddress {
+		&self.peer
+	}
+
+	// handshake was received, even if connection is closed by now
+	pub fn handshake_done(&self) -> bool {
+		self.peer_info.is_some()
+	}
+
+	pub fn is_connected(&self) -> bool {
+		self.peer_info.is_some() && self.is_alive()
+	}
+
 	pub fn is_alive(&self) -> bool {
 		self.connection.is_alive()
 	}
@@ -92,7 +312,7 @@ impl Peer {
 	}
 
 	pub fn does_have(&self, piece: usize) -> bool {
-		if piece > self.piece_count {
+		if piece >= self.piece_count {
 			false
 		} else {
 			let byte = piece / 8;
@@ -101,14 +321,34 @@ impl Peer {
 		}
 	}
 
-	fn store_bitfield(&mut self, bitfield: Vec<u8>) {
+	pub fn is_seed(&self) -> bool {
+		(0..self.piece_count).all(|piece| self.does_have(piece))
+	}
+
+	pub fn pieces(&self) -> Vec<usize> {
+		(0..self.piece_count).filter(|&piece| self.does_have(piece)).collect()
+	}
+
+	fn set_all(&mut self, have: bool) {
+		for piece in 0..self.piece_count {
+			let byte = piece / 8;
+			let bit = 7 - piece % 8;
+			if have {
+				self.have[byte] |= 1 << bit;
+			} else {
+				self.have[byte] &= !(1 << bit);
+			}
+		}
+	}
+
+	fn store_bitfield(&mut self, bitfield: Vec<u8>) -> bool {
 		if bitfield.len() != self.have.len() {
 			debug!("Peer {:?} sent bad bitfield, length: {}, expected: {}",
 				self.peer,
 				bitfield.len(),
 				self.have.len());
 			self.connection.close();
-			return;
+			return false;
 		}
 
 		let spare_bits = (8 - self.piece_count % 8) % 8;
@@ -119,17 +359,28 @@ impl Peer {
 				debug!("Last byte: {}", last_byte);
 				debug!("Piece count: {}", self.piece_count);
 				self.connection.close();
-				return;
+				return false;
 			}
 		}
 
 		self.have = bitfield;
+		true
 	}
 
 	fn process_message(&mut self, msg: connection::Message) -> Option<Message> {
+		// bitfield can only be the first message after handshake
+		let bitfield_allowed = self.bitfield_allowed;
+		self.bitfield_allowed = false;
+
 		match msg {
-			connection::Message::Choke =>
-				self.peer_choked = true,
+			connection::Message::Choke => {
+				self.peer_choked = true;
+				// with fast extension peer rejects requests explicitly,
+				// otherwise it discards them all
+				if !self.fast() {
+					self.requests.clear();
+				}
+			}
 			connection::Message::Unchoke =>
 				self.peer_choked = false,
 			connection::Message::Interested =>
@@ -142,22 +393,88 @@ impl Peer {
 						self.peer,
 						piece);
 					self.connection.close();
-				} else {
+				} else if !self.does_have(piece) {
 					let byte = piece / 8;
 					let bit = 7 - piece % 8;
 					self.have[byte] |= 1 << bit;
+					return Some(Message::Have(piece));
 				}
 			}
 			connection::Message::Bitfield(bits) => {
-				self.store_bitfield(bits)
+				if !bitfield_allowed {
+					debug!("Peer {:?} sent bitfield too late, disconnecting", self.peer);
+					self.connection.close();
+				} else if self.store_bitfield(bits.clone()) {
+					return Some(Message::Bitfield(bits));
+				}
+			}
+			connection::Message::Request(piece, off, len) => {
+				let request = Request::new(piece, off, len);
+				if self.self_choked && !self.allowed_fast_out.contains(&piece) {
+					debug!("Peer {:?} sent request while choked, ignoring", self.peer);
+					self.reject(&request);
+				} else if self.upload_queue.len() >= MAX_UPLOAD_QUEUE {
+					debug!("Peer {:?} has too many queued requests, ignoring", self.peer);
+					self.reject(&request);
+				} else {
+					self.upload_queue.push_back(request);
+				}
+			}
+			connection::Message::Piece(piece, off, data) => {
+				self.requests.retain(|&(ref r, _)| r.piece != piece || r.offset != off);
+				self.downloaded += data.len();
+				return Some(Message::Piece(piece, off, data));
+			}
+			connection::Message::Cancel(piece, off, len) => {
+				let request = Request::new(piece, off, len);
+				if self.upload_queue.contains(&request) {
+					self.upload_queue.retain(|r| *r != request);
+					// fast extension wants every request answered
+					self.reject(&request);
+				}
+			}
+			connection::Message::HaveAll | connection::Message::HaveNone if !self.fast() => {
+				debug!("Peer {:?} sent fast message without negotiating it, disconnecting", self.peer);
+				self.connection.close();
+			}
+			connection::Message::HaveAll | connection::Message::HaveNone if !bitfield_allowed => {
+				debug!("Peer {:?} sent bitfield too late, disconnecting", self.peer);
+				self.connection.close();
+			}
+			connection::Message::HaveAll => {
+				self.set_all(true);
+				return Some(Message::Bitfield(self.have.clone()));
+			}
+			connection::Message::HaveNone => {
+				self.set_all(false);
+				return Some(Message::Bitfield(self.have.clone()));
+			}
+			connection::Message::SuggestPiece(piece) => {
+				// picker knows better what we need
+				debug!("Peer {:?} suggested piece {}, ignoring", self.peer, piece);
+			}
+			connection::Message::RejectRequest(piece, off, len) => {
+				if !self.fast() {
+					debug!("Peer {:?} sent fast message without negotiating it, disconnecting", self.peer);
+					self.connection.close();
+				} else {
+					let request = Request::new(piece, off, len);
+					self.requests.retain(|&(ref r, _)| *r != request);
+				}
+			}
+			connection::Message::AllowedFast(piece) => {
+				if !self.fast() {
+					debug!("Peer {:?} sent fast message without negotiating it, disconnecting", self.peer);
+					self.connection.close();
+				} else if piece < self.piece_count && !self.allowed_fast_in.contains(&piece) {
+					self.allowed_fast_in.push(piece);
+				}
 			}
-			connection::Message::Request(piece, off, len) =>
-				return Some(Message::Request(piece, off, len)),
-			connection::Message::Piece(piece, off, data) =>
-				return Some(Message::Piece(piece, off, data)),
-			connection::Message::Cancel(_, _, _) => {
-				// maybe some day this client will be
-				// smart enough to make use of this.
+			connection::Message::Extended(id, payload) => {
+				// some clients send extended handshake before the bitfield
+				self.bitfield_allowed = bitfield_allowed;
+				let result = self.extensions.process(id, &payload);
+				self.send_extension_messages(result);
 			}
 		}
 
@@ -185,6 +502,21 @@ impl Peer {
 			self.self_choked = choked;
 			if choked {
 				self.connection.send(connection::Message::Choke);
+				if self.fast() {
+					// requests are rejected explicitly, allowed fast ones
+					// are still served
+					let queue = ::std::mem::replace(&mut self.upload_queue, VecDeque::new());
+					for request in queue {
+						if self.allowed_fast_out.contains(&request.piece) {
+							self.upload_queue.push_back(request);
+						} else {
+							self.reject(&request);
+						}
+					}
+				} else {
+					// peer knows that choking discards its requests
+					self.upload_queue.clear();
+				}
 			} else {
 				self.connection.send(connection::Message::Unchoke);
 			}
@@ -201,4 +533,154 @@ impl Peer {
 			}
 		}
 	}
-}
\ No newline at end of file
+}
+
+fn bit_set(bits: &[u8], piece: usize) -> bool {
+	bits.get(piece / 8).map(|byte| byte & (1 << (7 - piece % 8)) != 0).unwrap_or(false)
+}
+
+
+#[cfg(test)]
+mod test {
+	use std::time::Duration;
+	use downloader::connection;
+	use downloader::connection::fake::{connected_peer, receive};
+	use downloader::request::Request;
+	use super::{Message, MAX_UNSENT_BYTES};
+
+	#[test]
+	fn pieces_answer_requests() {
+		let (mut peer, wire) = connected_peer(1, 4, false);
+		peer.request(Request::new(0, 0, 10));
+		peer.request(Request::new(1, 0, 10));
+		assert_eq!(peer.pending_requests(), 2);
+		assert_eq!(wire.borrow_mut().take_sent(), vec![
+			connection::Message::Request(0, 0, 10),
+			connection::Message::Request(1, 0, 10),
+		]);
+
+		match receive(&mut peer, &wire, connection::Message::Piece(1, 0, vec![0; 10])) {
+			Some(Message::Piece(1, 0, ref data)) if data.len() == 10 => {}
+			_ => panic!("piece expected"),
+		}
+		assert!(peer.has_requested(&Request::new(0, 0, 10)));
+		assert!(!peer.has_requested(&Request::new(1, 0, 10)));
+		assert_eq!(peer.take_transfer_stats(), (10, 0));
+
+		// without fast extension choking discards all requests
+		receive(&mut peer, &wire, connection::Message::Choke);
+		assert_eq!(peer.pending_requests(), 0);
+	}
+
+	#[test]
+	fn requests_time_out() {
+		let (mut peer, _wire) = connected_peer(1, 4, false);
+		peer.request(Request::new(0, 0, 10));
+		peer.request(Request::new(2, 0, 10));
+		assert!(peer.take_timed_out(Duration::from_secs(60)).is_empty());
+		assert_eq!(peer.take_timed_out(Duration::from_secs(0)), vec![
+			Request::new(0, 0, 10),
+			Request::new(2, 0, 10),
+		]);
+		assert_eq!(peer.pending_requests(), 0);
+	}
+
+	#[test]
+	fn upload_queue() {
+		let (mut peer, wire) = connected_peer(1, 4, false);
+		// requests while choked are dropped
+		receive(&mut peer, &wire, connection::Message::Request(0, 0, 10));
+		assert!(peer.next_upload().is_none());
+
+		peer.set_choking(false);
+		for offset in 0..3 {
+			receive(&mut peer, &wire, connection::Message::Request(0, offset * 10, 10));
+		}
+		receive(&mut peer, &wire, connection::Message::Cancel(0, 10, 10));
+		assert_eq!(peer.next_upload(), Some(Request::new(0, 0, 10)));
+
+		// nothing is taken while the peer doesn't read what we sent
+		wire.borrow_mut().unsent_bytes = MAX_UNSENT_BYTES;
+		assert!(peer.next_upload().is_none());
+		wire.borrow_mut().unsent_bytes = 0;
+		assert_eq!(peer.next_upload(), Some(Request::new(0, 20, 10)));
+
+		// choking throws away the rest
+		receive(&mut peer, &wire, connection::Message::Request(1, 0, 10));
+		peer.set_choking(true);
+		assert!(peer.next_upload().is_none());
+		assert!(!wire.borrow().sent.iter().any(|msg| match *msg {
+			connection::Message::RejectRequest(..) => true,
+			_ => false,
+		}));
+	}
+
+	#[test]
+	fn fast_upload_queue() {
+		let (mut peer, wire) = connected_peer(1, 4, true);
+		peer.allow_fast(vec![2]);
+		receive(&mut peer, &wire, connection::Message::Request(0, 0, 10));
+		receive(&mut peer, &wire, connection::Message::Request(2, 0, 10));
+		assert_eq!(wire.borrow_mut().take_sent(), vec![
+			connection::Message::AllowedFast(2),
+			connection::Message::RejectRequest(0, 0, 10),
+		]);
+
+		peer.set_choking(false);
+		receive(&mut peer, &wire, connection::Message::Request(1, 0, 10));
+		receive(&mut peer, &wire, connection::Message::Request(3, 0, 10));
+		receive(&mut peer, &wire, connection::Message::Cancel(3, 0, 10));
+		peer.set_choking(true);
+		// every request is answered, allowed fast ones are still served
+		assert_eq!(wire.borrow_mut().take_sent(), vec![
+			connection::Message::Unchoke,
+			connection::Message::RejectRequest(3, 0, 10),
+			connection::Message::Choke,
+			connection::Message::RejectRequest(1, 0, 10),
+		]);
+		assert_eq!(peer.next_upload(), Some(Request::new(2, 0, 10)));
+		assert!(peer.next_upload().is_none());
+	}
+
+	#[test]
+	fn our_bitfield() {
+		let (mut peer, wire) = connected_peer(1, 10, false);
+		// peer assumes we have nothing anyway
+		peer.send(Message::Bitfield(vec![0, 0]));
+		assert!(wire.borrow_mut().take_sent().is_empty());
+		peer.send(Message::Bitfield(vec![0x80, 0]));
+		assert_eq!(wire.borrow_mut().take_sent(), vec![connection::Message::Bitfield(vec![0x80, 0])]);
+
+		let (mut peer, wire) = connected_peer(1, 10, true);
+		peer.send(Message::Bitfield(vec![0, 0]));
+		peer.send(Message::Bitfield(vec![0xff, 0xc0]));
+		peer.send(Message::Have(3));
+		assert_eq!(wire.borrow_mut().take_sent(), vec![
+			connection::Message::HaveNone,
+			connection::Message::HaveAll,
+			connection::Message::Have(3),
+		]);
+	}
+
+	#[test]
+	fn peer_pieces() {
+		let (mut peer, wire) = connected_peer(1, 10, false);
+		match receive(&mut peer, &wire, connection::Message::Bitfield(vec![0x80, 0x40])) {
+			Some(Message::Bitfield(_)) => {}
+			_ => panic!("bitfield expected"),
+		}
+		assert_eq!(peer.pieces(), vec![0, 9]);
+		match receive(&mut peer, &wire, connection::Message::Have(4)) {
+			Some(Message::Have(4)) => {}
+			_ => panic!("have expected"),
+		}
+		// pieces are only announced once
+		assert!(receive(&mut peer, &wire, connection::Message::Have(4)).is_none());
+		assert_eq!(peer.pieces(), vec![0, 4, 9]);
+		assert!(peer.is_alive());
+
+		// only allowed as the first message
+		receive(&mut peer, &wire, connection::Message::Bitfield(vec![0xff, 0xc0]));
+		assert!(!peer.is_alive());
+	}
+}
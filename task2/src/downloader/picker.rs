@@ -0,0 +1,250 @@
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use downloader::request::Request;
+
+
+// until we have this many pieces we pick randomly instead of rarest
+// first - common pieces arrive faster, and we need something to share
+const RANDOM_FIRST_PIECES: usize = 4;
+
+// pieces with higher priority are requested first, skipped ones never
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+	Skip,
+	Low,
+	Normal,
+	High,
+}
+
+pub struct PiecePicker {
+	availability: Vec<usize>,
+	priorities: Vec<Priority>,
+	in_progress: HashSet<usize>,
+	completed: usize,
+	// pieces in order, instead of rarest first
+	sequential: bool,
+	// start and length of the range of pieces needed soon (e.g. because
+	// they are being played), it goes before everything else
+	window: Option<(usize, usize)>,
+}
+
+impl PiecePicker {
+	pub fn new(piece_count: usize) -> PiecePicker {
+		PiecePicker {
+			availability: vec![0; piece_count],
+			priorities: vec![Priority::Normal; piece_count],
+			in_progress: HashSet::new(),
+			completed: 0,
+			sequential: false,
+			window: None,
+		}
+	}
+
+	pub fn peer_has(&mut self, piece: usize) {
+		if let Some(count) = self.availability.get_mut(piece) {
+			*count += 1;
+		}
+	}
+
+	pub fn peer_lost(&mut self, piece: usize) {
+		if let Some(count) = self.availability.get_mut(piece) {
+			if *count > 0 {
+				*count -= 1;
+			}
+		}
+	}
+
+	pub fn availability(&self, piece: usize) -> usize {
+		self.availability.get(piece).cloned().unwrap_or(0)
+	}
+
+	pub fn piece_requested(&mut self, piece: usize) {
+		self.in_progress.insert(piece);
+	}
+
+	pub fn piece_completed(&mut self, piece: usize) {
+		if self.in_progress.remove(&piece) {
+			self.completed += 1;
+		}
+	}
+
+	pub fn set_completed(&mut self, completed: usize) {
+		self.completed = completed;
+	}
+
+	pub fn set_priorities(&mut self, priorities: Vec<Priority>) {
+		self.priorities = priorities;
+	}
+
+	pub fn set_priority(&mut self, piece: usize, priority: Priority) {
+		if let Some(current) = self.priorities.get_mut(piece) {
+			*current = priority;
+		}
+	}
+
+	pub fn wanted(&self) -> Vec<bool> {
+		self.priorities.iter().map(|&priority| priority != Priority::Skip).collect()
+	}
+
+	pub fn set_sequential(&mut self, sequential: bool) {
+		self.sequential = sequential;
+	}
+
+	pub fn set_window(&mut self, start: usize, length: usize) {
+		self.window = Some((start, length));
+	}
+
+	pub fn is_wanted(&self, piece: usize) -> bool {
+		self.priority(piece) != Priority::Skip
+	}
+
+	fn priority(&self, piece: usize) -> Priority {
+		self.priorities.get(piece).cloned().unwrap_or(Priority::Skip)
+	}
+
+	// Orders requests so that pieces in the window come first (in order),
+	// then pieces with higher priority, blocks of started pieces and the
+	// rarest pieces (or just next ones in sequential mode). Pieces that
+	// nobody has or that are skipped are dropped.
+	pub fn order(&self, requests: Vec<Request>) -> Picked {
+		let mut by_piece: HashMap<usize, Vec<Request>> = HashMap::new();
+		for request in requests {
+			if self.availability(request.piece) > 0 && self.is_wanted(request.piece) {
+				by_piece.entry(request.piece).or_insert_with(Vec::new).push(request);
+			}
+		}
+
+		let random_first = self.completed < RANDOM_FIRST_PIECES && !self.sequential;
+		let pieces = by_piece.keys()
+			.map(|&piece| {
+				let window = match self.window {
+					Some((start, length)) if piece >= start && piece < start + length => piece - start,
+					_ => ::std::usize::MAX,
+				};
+				let priority = Reverse(self.priority(piece));
+				let started = self.in_progress.contains(&piece);
+				let rarity = if self.sequential {
+					piece
+				} else if random_first {
+					0
+				} else {
+					self.availability(piece)
+				};
+				// random tie breaker, so that peers with the same
+				// view of the swarm would not all pick the same pieces
+				let key = (window, priority, !started, rarity, ::rand::random::<u32>());
+				Reverse((key, piece))
+			})
+			.collect();
+
+		Picked {
+			pieces: pieces,
+			requests: by_piece,
+		}
+	}
+}
+
+type PickKey = (usize, Reverse<Priority>, bool, usize, u32);
+
+// Requests of one piece at a time, best piece first. Pieces are only
+// sorted as they are taken, callers that stop early don't pay for the rest.
+pub struct Picked {
+	pieces: BinaryHeap<Reverse<(PickKey, usize)>>,
+	requests: HashMap<usize, Vec<Request>>,
+}
+
+impl Iterator for Picked {
+	type Item = Vec<Request>;
+
+	fn next(&mut self) -> Option<Vec<Request>> {
+		self.pieces.pop().map(|Reverse((_, piece))| {
+			let mut requests = self.requests.remove(&piece).unwrap_or_default();
+			requests.sort();
+			requests
+		})
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use downloader::request::Request;
+	use super::{PiecePicker, Picked, Priority};
+
+	fn pieces(picked: Picked) -> Vec<usize> {
+		picked.map(|requests| requests[0].piece).collect()
+	}
+
+	#[test]
+	fn rarest_first() {
+		let mut picker = PiecePicker::new(4);
+		picker.set_completed(10);
+		for &(piece, count) in &[(0, 3), (1, 1), (2, 2), (3, 0)] {
+			for _ in 0..count {
+				picker.peer_has(piece);
+			}
+		}
+
+		let requests = (0..4).map(|p| Request::new(p, 0, 10)).collect();
+		assert_eq!(pieces(picker.order(requests)), vec![1, 2, 0]);
+	}
+
+	#[test]
+	fn started_pieces_first() {
+		let mut picker = PiecePicker::new(3);
+		picker.set_completed(10);
+		picker.peer_has(0);
+		picker.peer_has(1);
+		picker.peer_has(2);
+		picker.peer_has(2);
+		picker.peer_has(2);
+		picker.piece_requested(2);
+
+		let requests = vec![
+			Request::new(0, 0, 10),
+			Request::new(1, 0, 10),
+			Request::new(2, 20, 10),
+			Request::new(2, 10, 10),
+		];
+		let first = picker.order(requests).next().unwrap();
+		assert_eq!(first, vec![Request::new(2, 10, 10), Request::new(2, 20, 10)]);
+	}
+
+	#[test]
+	fn priorities_come_first() {
+		let mut picker = PiecePicker::new(4);
+		picker.set_completed(10);
+		for piece in 0..4 {
+			picker.peer_has(piece);
+		}
+		picker.piece_requested(0);
+		picker.set_priorities(vec![Priority::Normal, Priority::Skip, Priority::High, Priority::Low]);
+
+		let requests = (0..4).map(|p| Request::new(p, 0, 10)).collect();
+		assert_eq!(pieces(picker.order(requests)), vec![2, 0, 3]);
+		assert!(!picker.is_wanted(1) && picker.is_wanted(3));
+	}
+
+	#[test]
+	fn window_and_sequential_order() {
+		let mut picker = PiecePicker::new(6);
+		for piece in 0..6 {
+			picker.peer_has(piece);
+		}
+		picker.set_sequential(true);
+		picker.set_priorities(vec![Priority::Normal; 6]);
+		picker.set_window(3, 2);
+
+		let requests = (0..6).map(|p| Request::new(p, 0, 10)).collect();
+		assert_eq!(pieces(picker.order(requests)), vec![3, 4, 0, 1, 2, 5]);
+	}
+
+	#[test]
+	fn lost_peer_reduces_availability() {
+		let mut picker = PiecePicker::new(2);
+		picker.peer_has(1);
+		picker.peer_lost(1);
+		picker.peer_lost(1);
+		assert_eq!(picker.availability(1), 0);
+		assert!(picker.order(vec![Request::new(1, 0, 1)]).next().is_none());
+	}
+}
@@ -0,0 +1,78 @@
+use std::cmp;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use downloader::PeerAddress;
+
+
+// peer that could not be reached is tried again after this long,
+// twice as long after every further failure
+const RETRY_DELAY: u64 = 30; // seconds
+const MAX_SHIFT: u32 = 7;
+
+// Keeps peers we connected to from being dialed again right away after
+// the connection is lost, so that unreachable addresses learned from
+// trackers, DHT, PEX or LSD are not hammered every tick.
+#[derive(Default)]
+pub struct RetryList {
+	// failures in a row and time peer may be dialed again
+	peers: HashMap<PeerAddress, (u32, Instant)>,
+}
+
+impl RetryList {
+	pub fn new() -> RetryList {
+		RetryList::default()
+	}
+
+	// Connection we opened is gone, `handshake_done` tells if the peer
+	// was reachable at all: if it was, it is not blamed for earlier failures.
+	pub fn connection_lost(&mut self, peer: &PeerAddress, handshake_done: bool) {
+		let failures = match self.peers.get(peer) {
+			Some(&(failures, _)) if !handshake_done => failures + 1,
+			_ => 1,
+		};
+		let delay = RETRY_DELAY * (1 << cmp::min(failures - 1, MAX_SHIFT));
+		self.peers.insert(peer.clone(), (failures, Instant::now() + Duration::from_secs(delay)));
+	}
+
+	pub fn may_connect(&self, peer: &PeerAddress) -> bool {
+		match self.peers.get(peer) {
+			Some(&(_, retry_at)) => Instant::now() >= retry_at,
+			None => true,
+		}
+	}
+}
+
+
+#[cfg(test)]
+mod test {
+	use std::net::Ipv4Addr;
+	use std::time::{Duration, Instant};
+	use downloader::PeerAddress;
+	use super::{RetryList, RETRY_DELAY};
+
+	fn peer(last: u8) -> PeerAddress {
+		PeerAddress::new(Ipv4Addr::new(10, 0, 0, last), 6881)
+	}
+
+	fn delay(retries: &RetryList, peer: &PeerAddress) -> u64 {
+		let retry_at = retries.peers[peer].1;
+		(retry_at - Instant::now() + Duration::from_millis(500)).as_secs()
+	}
+
+	#[test]
+	fn unreachable_peers_wait_longer() {
+		let mut retries = RetryList::new();
+		retries.connection_lost(&peer(1), false);
+		assert!(!retries.may_connect(&peer(1)));
+		assert!(retries.may_connect(&peer(2)));
+		assert_eq!(delay(&retries, &peer(1)), RETRY_DELAY);
+
+		retries.connection_lost(&peer(1), false);
+		retries.connection_lost(&peer(1), false);
+		assert_eq!(delay(&retries, &peer(1)), 4 * RETRY_DELAY);
+
+		// peer was reachable after all, start over
+		retries.connection_lost(&peer(1), true);
+		assert_eq!(delay(&retries, &peer(1)), RETRY_DELAY);
+	}
+}
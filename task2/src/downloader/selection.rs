@@ -0,0 +1,132 @@
+use torrent::TorrentInfo;
+use downloader::picker::Priority;
+
+
+// Gives priority to files that match the selector: comma separated
+// list of file indexes (in the order files are listed in torrent)
+// and glob patterns of their paths, e.g. "0,3,*.mkv".
+#[derive(Debug, Clone)]
+pub struct FileRule {
+	pub selector: String,
+	pub priority: Priority,
+}
+
+impl FileRule {
+	pub fn new<S: Into<String>>(selector: S, priority: Priority) -> FileRule {
+		FileRule {
+			selector: selector.into(),
+			priority: priority,
+		}
+	}
+
+	fn matches(&self, index: usize, path: &str) -> bool {
+		self.selector.split(',')
+			.map(|part| part.trim())
+			.any(|part| match part.parse::<usize>() {
+				Ok(selected) => selected == index,
+				Err(_) => glob_match(part.as_bytes(), path.as_bytes()),
+			})
+	}
+}
+
+// Priority of every file, later rules override earlier ones and
+// files that no rule matches have normal priority.
+pub fn file_priorities(info: &TorrentInfo, rules: &[FileRule]) -> Vec<Priority> {
+	info.files.iter()
+		.enumerate()
+		.map(|(index, file)| {
+			let path = file.path.to_string_lossy();
+			rules.iter()
+				.rev()
+				.find(|rule| rule.matches(index, &path))
+				.map(|rule| rule.priority)
+				.unwrap_or(Priority::Normal)
+		})
+		.collect()
+}
+
+// Piece gets the highest priority of the files it has data of, so it is
+// only skipped if all of them are.
+pub fn piece_priorities(info: &TorrentInfo, file_priorities: &[Priority]) -> Vec<Priority> {
+	let mut priorities = vec![Priority::Skip; info.pieces.len()];
+	let mut start = 0;
+	for (file, &priority) in info.files.iter().zip(file_priorities) {
+		let end = start + file.length;
+		if file.length > 0 {
+			let first = (start / info.piece_length) as usize;
+			let last = ((end - 1) / info.piece_length) as usize;
+			for piece in &mut priorities[first..(last + 1)] {
+				*piece = ::std::cmp::max(*piece, priority);
+			}
+		}
+		start = end;
+	}
+	priorities
+}
+
+// '*' matches any number of characters (path separators too), '?' any one
+fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+	match pattern.first() {
+		None => text.is_empty(),
+		Some(&b'*') => (0..(text.len() + 1)).any(|skip| glob_match(&pattern[1..], &text[skip..])),
+		Some(&b'?') => !text.is_empty() && glob_match(&pattern[1..], &text[1..]),
+		Some(&ch) => text.first() == Some(&ch) && glob_match(&pattern[1..], &text[1..]),
+	}
+}
+
+
+#[cfg(test)]
+mod test {
+	use std::path::PathBuf;
+	use torrent::{TorrentInfo, File};
+	use downloader::picker::Priority;
+	use super::*;
+
+	fn info() -> TorrentInfo {
+		let file = |path: &str, length| File { path: PathBuf::from(path), length: length };
+		TorrentInfo {
+			root: PathBuf::from("root"),
+			piece_length: 10,
+			pieces: vec![[0; 20]; 4],
+			files: vec![
+				file("movie.mkv", 15),
+				file("subs/en.srt", 5),
+				file("empty", 0),
+				file("extras/making of.mkv", 12),
+			],
+			private: false,
+			single_file: false,
+		}
+	}
+
+	#[test]
+	fn globs() {
+		assert!(glob_match(b"*.mkv", b"extras/making of.mkv"));
+		assert!(glob_match(b"subs/??.srt", b"subs/en.srt"));
+		assert!(!glob_match(b"subs/?.srt", b"subs/en.srt"));
+		assert!(!glob_match(b"*.mkv", b"movie.mkv.part"));
+	}
+
+	#[test]
+	fn rules_select_files() {
+		let rules = vec![
+			FileRule::new("*", Priority::Skip),
+			FileRule::new("0, subs/*", Priority::Normal),
+			FileRule::new("*.srt", Priority::High),
+		];
+		let files = file_priorities(&info(), &rules);
+		assert_eq!(files, vec![Priority::Normal, Priority::High, Priority::Skip, Priority::Skip]);
+		assert_eq!(file_priorities(&info(), &[]), vec![Priority::Normal; 4]);
+	}
+
+	#[test]
+	fn pieces_take_highest_priority() {
+		let files = vec![Priority::Low, Priority::Skip, Priority::High, Priority::Skip];
+		// pieces: [movie], [movie, subs], [extras], [extras]
+		assert_eq!(piece_priorities(&info(), &files),
+			vec![Priority::Low, Priority::Low, Priority::Skip, Priority::Skip]);
+		let files = vec![Priority::Skip, Priority::High, Priority::Skip, Priority::Normal];
+		assert_eq!(piece_priorities(&info(), &files),
+			vec![Priority::Skip, Priority::High, Priority::Normal, Priority::Normal]);
+	}
+}
@@ -0,0 +1,293 @@
+use std::cmp;
+use std::io;
+use std::io::{Read, Seek, SeekFrom};
+use std::sync::mpsc::{channel, Sender, Receiver};
+use downloader::connection::event_loop::{EventLoop, Waker, WakeHandle};
+use storage::Storage;
+use torrent::TorrentInfo;
+
+
+struct ReadRequest {
+	piece: usize,
+	reply: Sender<Vec<u8>>,
+}
+
+// Downloader side of the readers: answers their requests for
+// pieces as soon as the pieces are downloaded and verified.
+pub struct StreamServer {
+	// None once the downloader has stopped
+	requests: Option<Receiver<ReadRequest>>,
+	sender: Sender<ReadRequest>,
+	waker: Waker,
+	pending: Vec<ReadRequest>,
+	// piece that readers asked for most recently
+	position: Option<usize>,
+	piece_length: u64,
+	total_size: u64,
+	// start and length of every file in the torrent's byte stream
+	files: Vec<(u64, u64)>,
+}
+
+impl StreamServer {
+	pub fn new(info: &TorrentInfo) -> StreamServer {
+		let (sender, requests) = channel();
+		let mut files = Vec::new();
+		let mut start = 0;
+		for file in &info.files {
+			files.push((start, file.length));
+			start += file.length;
+		}
+		StreamServer {
+			requests: Some(requests),
+			sender: sender,
+			waker: Waker::new(),
+			pending: Vec::new(),
+			position: None,
+			piece_length: info.piece_length,
+			total_size: start,
+			files: files,
+		}
+	}
+
+	pub fn register(&self, event_loop: &EventLoop) -> io::Result<()> {
+		event_loop.watch(&self.waker)
+	}
+
+	// Reader of all the data of the torrent, as if files were concatenated.
+	pub fn reader(&self) -> TorrentReader {
+		self.range_reader(0, self.total_size)
+	}
+
+	pub fn file_reader(&self, index: usize) -> Option<TorrentReader> {
+		self.files.get(index).map(|&(start, length)| self.range_reader(start, length))
+	}
+
+	fn range_reader(&self, start: u64, length: u64) -> TorrentReader {
+		TorrentReader {
+			requests: self.sender.clone(),
+			wake: self.waker.handle(),
+			piece_length: self.piece_length,
+			start: start,
+			length: length,
+			position: 0,
+			cached: None,
+		}
+	}
+
+	pub fn position(&self) -> Option<usize> {
+		self.position
+	}
+
+	// Answers reads of pieces that storage has, returns pieces
+	// that readers are still waiting for.
+	pub fn serve<S: Storage>(&mut self, storage: &mut S) -> Vec<usize> {
+		self.waker.reset();
+		if let Some(ref requests) = self.requests {
+			for request in requests.try_iter() {
+				self.position = Some(request.piece);
+				self.pending.push(request);
+			}
+		}
+
+		let mut waiting = Vec::new();
+		for request in self.pending.drain(..) {
+			match storage.get_piece(request.piece) {
+				// reader might be gone already, that's fine
+				Some(data) => {
+					let _ = request.reply.send(data.to_vec());
+				}
+				None => waiting.push(request),
+			}
+		}
+		self.pending = waiting;
+		self.pending.iter().map(|request| request.piece).collect()
+	}
+
+	// Downloader stopped: reads that are waiting and all later ones fail,
+	// since nobody is going to answer them anymore.
+	pub fn close(&mut self) {
+		self.requests = None;
+		self.pending.clear();
+	}
+}
+
+// Reads torrent data (or data of a single file) while it is being
+// downloaded, blocking until needed pieces are downloaded and verified.
+// Readers can be moved to other threads, the downloader has to keep
+// running for them to get anything: once it stops, reads fail.
+pub struct TorrentReader {
+	requests: Sender<ReadRequest>,
+	wake: WakeHandle,
+	piece_length: u64,
+	start: u64,
+	length: u64,
+	position: u64,
+	cached: Option<(usize, Vec<u8>)>,
+}
+
+impl TorrentReader {
+	pub fn len(&self) -> u64 {
+		self.length
+	}
+
+	fn piece(&mut self, index: usize) -> io::Result<&[u8]> {
+		let cached = match self.cached {
+			Some((cached, _)) => cached == index,
+			None => false,
+		};
+		if !cached {
+			let (reply, data) = channel();
+			let request = ReadRequest {
+				piece: index,
+				reply: reply,
+			};
+			let stopped = || io::Error::new(io::ErrorKind::BrokenPipe, "downloader has stopped");
+			try!(self.requests.send(request).map_err(|_| stopped()));
+			self.wake.wake();
+			let data = try!(data.recv().map_err(|_| stopped()));
+			self.cached = Some((index, data));
+		}
+		Ok(self.cached.as_ref().map(|&(_, ref data)| data.as_slice()).unwrap())
+	}
+}
+
+impl Read for TorrentReader {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		if self.position >= self.length || buf.len() == 0 {
+			return Ok(0);
+		}
+		let offset = self.start + self.position;
+		let index = (offset / self.piece_length) as usize;
+		let piece_offset = (offset % self.piece_length) as usize;
+		let remaining = self.length - self.position;
+		let count = {
+			let piece = try!(self.piece(index));
+			if piece_offset >= piece.len() {
+				return Err(io::Error::new(io::ErrorKind::InvalidData, "piece is too short"));
+			}
+			let count = cmp::min(buf.len(), piece.len() - piece_offset);
+			let count = cmp::min(count as u64, remaining) as usize;
+			buf[..count].copy_from_slice(&piece[piece_offset..(piece_offset + count)]);
+			count
+		};
+		self.position += count as u64;
+		Ok(count)
+	}
+}
+
+impl Seek for TorrentReader {
+	fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+		let position = match pos {
+			SeekFrom::Start(offset) => offset as i64,
+			SeekFrom::End(offset) => self.length as i64 + offset,
+			SeekFrom::Current(offset) => self.position as i64 + offset,
+		};
+		if position < 0 {
+			return Err(io::Error::new(io::ErrorKind::InvalidInput, "seek before start"));
+		}
+		self.position = position as u64;
+		Ok(self.position)
+	}
+}
+
+
+#[cfg(test)]
+mod test {
+	use std::io::{Read, Seek, SeekFrom, ErrorKind};
+	use std::path::PathBuf;
+	use std::sync::mpsc::channel;
+	use std::thread;
+	use std::time::{Duration, Instant};
+	use storage::{Storage, Block};
+	use storage::piece_hash as hash;
+	use storage::memory::MemoryStorage;
+	use torrent::{TorrentInfo, File};
+	use super::StreamServer;
+
+	fn store<S: Storage>(storage: &mut S, data: &[u8], piece: usize) {
+		let start = piece * 16;
+		storage.store_block(Block::new(piece, 0, data[start..(start + 16)].to_vec())).ok().unwrap();
+		storage.piece_verified(piece, true);
+	}
+
+	fn info(data: &[u8]) -> TorrentInfo {
+		TorrentInfo {
+			root: PathBuf::from("root"),
+			piece_length: 16,
+			pieces: data.chunks(16).map(hash).collect(),
+			files: vec![
+				File { path: PathBuf::from("a"), length: 10 },
+				File { path: PathBuf::from("b"), length: 30 },
+				File { path: PathBuf::from("c"), length: 24 },
+			],
+			private: false,
+			single_file: false,
+		}
+	}
+
+	#[test]
+	fn reads_pieces_when_they_arrive() {
+		// last piece only has data of file "c" and is never downloaded
+		let data = (0..64).collect::<Vec<u8>>();
+		let info = info(&data);
+		let mut server = StreamServer::new(&info);
+		let mut storage = MemoryStorage::new(info);
+		store(&mut storage, &data, 1);
+		store(&mut storage, &data, 2);
+
+		let mut reader = server.file_reader(1).unwrap();
+		assert_eq!(reader.len(), 30);
+		let expected = data.clone();
+		let (done, finished) = channel();
+		thread::spawn(move || {
+			let mut start = [0; 8];
+			reader.read_exact(&mut start).unwrap();
+			assert_eq!(start, &expected[10..18]);
+			assert_eq!(reader.seek(SeekFrom::End(-4)).unwrap(), 26);
+			let mut end = Vec::new();
+			reader.read_to_end(&mut end).unwrap();
+			assert_eq!(end, &expected[36..40]);
+			done.send(()).unwrap();
+		});
+
+		// reader has to wait until the first piece is there
+		let deadline = Instant::now() + Duration::from_secs(5);
+		while server.serve(&mut storage) != vec![0] {
+			assert!(Instant::now() < deadline, "nothing was requested");
+			thread::sleep(Duration::from_millis(1));
+		}
+		assert_eq!(server.position(), Some(0));
+		store(&mut storage, &data, 0);
+		while finished.try_recv().is_err() {
+			assert!(Instant::now() < deadline, "reading did not finish");
+			assert!(server.serve(&mut storage).is_empty());
+			thread::sleep(Duration::from_millis(1));
+		}
+		assert_eq!(server.position(), Some(2));
+	}
+
+	#[test]
+	fn reads_fail_once_closed() {
+		let data = (0..64).collect::<Vec<u8>>();
+		let mut server = StreamServer::new(&info(&data));
+		let mut storage = MemoryStorage::new(info(&data));
+
+		let mut reader = server.reader();
+		let (done, finished) = channel();
+		thread::spawn(move || {
+			let mut buf = [0; 8];
+			done.send(reader.read(&mut buf).map_err(|e| e.kind())).unwrap();
+		});
+		let deadline = Instant::now() + Duration::from_secs(5);
+		while server.serve(&mut storage).is_empty() {
+			assert!(Instant::now() < deadline, "nothing was requested");
+			thread::sleep(Duration::from_millis(1));
+		}
+
+		server.close();
+		let result = finished.recv_timeout(Duration::from_secs(5)).unwrap();
+		assert_eq!(result, Err(ErrorKind::BrokenPipe));
+		let mut buf = [0; 8];
+		assert_eq!(server.reader().read(&mut buf).unwrap_err().kind(), ErrorKind::BrokenPipe);
+	}
+}
@@ -1,71 +1,142 @@
+use ::std::io;
 use ::std::io::Read;
+use ::std::sync::Arc;
+use ::std::sync::mpsc::{channel, Sender, Receiver};
+use ::std::thread;
 use ::hyper::client::{Client, IntoUrl};
 use ::hyper::status::StatusCode;
 use ::hyper::Url;
 use ::std::time::{Instant, Duration};
-use ::std::net::{Ipv4Addr, Ipv6Addr};
+use ::std::net::{IpAddr, Ipv6Addr, UdpSocket};
 use bencode::*;
 use downloader::PeerAddress;
+use downloader::connection::event_loop::{EventLoop, Waker};
 use downloader::tracker::*;
 
 
+// for reading and writing request data, tracker that takes
+// longer than that counts as failing
+const REQUEST_TIMEOUT: u64 = 30; // seconds
+
+// body of the response, or description of what went wrong
+type Reply = Result<Vec<u8>, String>;
+
 struct Response {
 	peers: Vec<PeerAddress>,
 	interval: u64,
 }
 
+// Requests are sent from a worker thread, so that slow trackers do not
+// stall the network thread. Replies wake up the event loop the tracker
+// is registered with.
 pub struct HttpTracker {
-	client: Client,
+	client: Arc<Client>,
+	replies: Receiver<Reply>,
+	reply_sender: Sender<Reply>,
+	waker: Waker,
+	// event of the request that is being sent
+	pending: Option<Option<&'static str>>,
 	args: TrackerArgs,
 	sent_started: bool,
+	// completed event is only sent if we were downloading
+	was_incomplete: bool,
+	sent_completed: bool,
 	peers: Vec<PeerAddress>,
 	next_announce: Instant,
 	failures: u32,
+	// told to tracker, so that peers can reach us over IPv6
+	// even if we talk to tracker over IPv4
+	ipv6: Option<Ipv6Addr>,
 }
 
 impl Tracker for HttpTracker {
 	fn new(args: TrackerArgs) -> Self {
-		let client = Client::new();
+		let mut client = Client::new();
+		client.set_read_timeout(Some(Duration::from_secs(REQUEST_TIMEOUT)));
+		client.set_write_timeout(Some(Duration::from_secs(REQUEST_TIMEOUT)));
+		let (reply_sender, replies) = channel();
 		HttpTracker {
-			client: client,
+			client: Arc::new(client),
+			replies: replies,
+			reply_sender: reply_sender,
+			waker: Waker::new(),
+			pending: None,
 			args: args,
 			sent_started: false,
+			was_incomplete: false,
+			sent_completed: false,
 			peers: Vec::new(),
 			next_announce: Instant::now(),
 			failures: 0,
+			ipv6: global_ipv6(),
 		}
 	}
 
 	fn update(&mut self, down: usize, up: usize, left: usize) {
-		if self.can_send_request() {
+		if left > 0 {
+			self.was_incomplete = true;
+		}
+		self.receive();
+		if self.pending.is_some() {
+			return;
+		}
+		let event = if !self.sent_started {
+			Some("started")
+		} else if left == 0 && self.was_incomplete && !self.sent_completed {
+			Some("completed")
+		} else {
+			None
+		};
+
+		// tracker should learn about completion right away
+		let completing = event == Some("completed") && self.failures == 0;
+		if self.can_send_request() || completing {
 			let retry_after = Duration::from_secs(10 * (1 << self.failures));
 			self.next_announce = Instant::now() + retry_after;
 		} else {
 			return;
 		}
-		let url = self.build_request(down, up, left);
-		let response = self.client.get(url).send();
-		match response {
-			Ok(response) => {
-				if response.status == StatusCode::Ok {
-					self.sent_started = true;
-					self.failures = 0;
-					self.parse_response(response);
-				} else {
-					warn!("Tracker response status: {}", response.status);
-					self.failures += 1;
-				}
-			}
-			Err(error) => {
-				warn!("Tracker request failed: {}", error);
-				self.failures += 1;
+		let url = self.build_request(down, up, left, event);
+		self.pending = Some(event);
+		let client = self.client.clone();
+		let replies = self.reply_sender.clone();
+		let wake = self.waker.handle();
+		thread::spawn(move || {
+			// tracker might be gone already
+			if replies.send(send_request(&client, url)).is_ok() {
+				wake.wake();
 			}
+		});
+	}
+
+	fn stop(&mut self, down: usize, up: usize, left: usize) {
+		if !self.sent_started {
+			return;
+		}
+		// we are leaving anyway, no reason not to wait here
+		let url = self.build_request(down, up, left, Some("stopped"));
+		if let Err(error) = send_request(&self.client, url) {
+			warn!("Tracker request failed: {}", error);
 		}
 	}
 
+	fn register(&self, event_loop: &EventLoop) -> io::Result<()> {
+		event_loop.watch(&self.waker)
+	}
+
 	fn peers<'a>(&'a self) -> Box<Iterator<Item=&'a PeerAddress> + 'a> {
 		Box::new(self.peers.iter())
 	}
+
+	fn status(&self) -> Status {
+		if self.failures > 0 {
+			Status::Failing
+		} else if self.sent_started {
+			Status::Working
+		} else {
+			Status::Unknown
+		}
+	}
 }
 
 impl HttpTracker {
@@ -73,17 +144,32 @@ impl HttpTracker {
 		Instant::now() >= self.next_announce
 	}
 
-	fn parse_response(&mut self, mut response: ::hyper::client::Response) {
-		let mut body = Vec::new();
-		match response.read_to_end(&mut body) {
-			Ok(_) => {}
-			Err(e) => {
-				warn!("Failed to read tracker response body: {}", e);
+	fn receive(&mut self) {
+		self.waker.reset();
+		let reply = match self.replies.try_recv() {
+			Ok(reply) => reply,
+			Err(_) => return,
+		};
+		let event = self.pending.take().unwrap_or(None);
+		match reply {
+			Ok(body) => {
+				match event {
+					Some("started") => self.sent_started = true,
+					Some("completed") => self.sent_completed = true,
+					_ => {}
+				}
+				self.failures = 0;
+				self.parse_response(&body);
+			}
+			Err(error) => {
+				warn!("Tracker request failed: {}", error);
 				self.failures += 1;
-				return;
 			}
 		}
-		let bvalue = match decode(&body) {
+	}
+
+	fn parse_response(&mut self, body: &[u8]) {
+		let bvalue = match decode(body) {
 			Ok(value) => value,
 			Err(e) => {
 				warn!("Tracker response is malformed: {:?}", e);
@@ -109,7 +195,12 @@ impl HttpTracker {
 		self.peers = response.peers;
 	}
 
-	fn build_request(&mut self, down: usize, up: usize, left: usize) -> Url {
+	fn build_request(
+			&mut self,
+			down: usize,
+			up: usize,
+			left: usize,
+			event: Option<&str>) -> Url {
 		fn nibble_to_char(nibble: u8) -> char {
 			if nibble < 10 {
 				('0' as u8 + nibble) as char
@@ -133,14 +224,30 @@ impl HttpTracker {
 		push_url_arg(&mut url, "downloaded", &down.to_string());
 		push_url_arg(&mut url, "left", &left.to_string());
 		push_url_arg(&mut url, "compact", "1");
-		if !self.sent_started {
-			push_url_arg(&mut url, "event", "started");
+		if let Some(ip) = self.ipv6 {
+			// colons must be escaped
+			push_url_arg(&mut url, "ipv6", &ip.to_string().replace(":", "%3A"));
+		}
+		if let Some(event) = event {
+			push_url_arg(&mut url, "event", event);
 		}
 		// please?
 		url.into_url().unwrap()
 	}
 }
 
+// runs on worker threads, blocks until the whole response is read
+fn send_request(client: &Client, url: Url) -> Reply {
+	let mut response = try!(client.get(url).send().map_err(|error| error.to_string()));
+	if response.status != StatusCode::Ok {
+		return Err(format!("response status: {}", response.status));
+	}
+	let mut body = Vec::new();
+	try!(response.read_to_end(&mut body)
+		.map_err(|error| format!("failed to read response body: {}", error)));
+	Ok(body)
+}
+
 fn push_url_arg(url: &mut String, name: &str, value: &str) {
 	url.push('&');
 	url.push_str(name);
@@ -161,10 +268,14 @@ fn decode_response(value: BValue) -> Result<Response, &'static str> {
 			Err("negative interval")
 		}));
 
-	let peers = try!(dict
+	let mut peers = try!(dict
 		.remove(&b"peers"[..])
 		.ok_or("missing peers")
 		.and_then(decode_peers));
+	// IPv6 peers come separately (BEP 7)
+	if let Some(peers6) = dict.remove(&b"peers6"[..]).and_then(BValue::get_string) {
+		peers.extend(try!(decode_compact_peers6(&peers6)));
+	}
 
 	Ok(Response {
 		interval: interval,
@@ -182,24 +293,7 @@ fn decode_peers(value: BValue) -> Result<Vec<PeerAddress>, &'static str> {
 			Ok(peers)
 		}
 		BValue::Str(s) => {
-			if s.len() % 6 != 0 {
-				return Err("bad packed peer list string length");
-			}
-			let peer_count = s.len() / 6;
-			let mut peers = Vec::new();
-			for i in 0..peer_count {
-				let ip1 = s[i * 6 + 0] as u32;
-				let ip2 = s[i * 6 + 1] as u32;
-				let ip3 = s[i * 6 + 2] as u32;
-				let ip4 = s[i * 6 + 3] as u32;
-				let port1 = s[i * 6 + 4] as u16;
-				let port2 = s[i * 6 + 5] as u16;
-				let ip = (ip1 << 24) | (ip2 << 16) | (ip3 << 8) | ip4;
-				let ip = Ipv4Addr::from(ip).to_ipv6_mapped();
-				let port = (port1 << 8) | port2;
-				peers.push(PeerAddress::new(ip, port));
-			}
-			Ok(peers)
+			decode_compact_peers(&s)
 		}
 		_ => {
 			Err("bad peer list format")
@@ -217,9 +311,7 @@ fn decode_peer(value: BValue) -> Result<PeerAddress, &'static str> {
 		.and_then(|ip| {
 			use std::str::FromStr;
 			let ip_string = String::from_utf8_lossy(&ip);
-			let ipv4: Option<Ipv4Addr> = Ipv4Addr::from_str(&ip_string).ok();
-			let ipv6: Option<Ipv6Addr> = Ipv6Addr::from_str(&ip_string).ok();
-			ipv4.map(|x| x.to_ipv6_mapped()).or(ipv6).ok_or("bad peer ip")
+			IpAddr::from_str(&ip_string).map_err(|_| "bad peer ip")
 		}));
 
 	let port = try!(dict
@@ -234,3 +326,63 @@ fn decode_peer(value: BValue) -> Result<PeerAddress, &'static str> {
 
 	Ok(PeerAddress::new(ip, port))
 }
+
+// Finds address we would use to reach IPv6 internet. Nothing is sent,
+// connecting UDP socket only picks the route.
+fn global_ipv6() -> Option<Ipv6Addr> {
+	let socket = match UdpSocket::bind("[::]:0") {
+		Ok(socket) => socket,
+		Err(_) => return None,
+	};
+	// any global address will do, this one belongs to a public DNS server
+	if socket.connect("[2001:4860:4860::8888]:53").is_err() {
+		return None;
+	}
+	match socket.local_addr().map(|address| address.ip()) {
+		Ok(IpAddr::V6(ip)) if is_global(&ip) => Some(ip),
+		_ => None,
+	}
+}
+
+fn is_global(ip: &Ipv6Addr) -> bool {
+	let first = ip.segments()[0];
+	// loopback, link-local and unique local addresses are no use to others
+	!ip.is_loopback() && !ip.is_unspecified() &&
+		first & 0xffc0 != 0xfe80 && first & 0xfe00 != 0xfc00
+}
+
+
+#[cfg(test)]
+mod test {
+	use std::net::{Ipv4Addr, Ipv6Addr};
+	use bencode;
+	use downloader::PeerAddress;
+	use super::*;
+
+	#[test]
+	fn decodes_ipv6_peers() {
+		let mut peers6 = vec![0; 16];
+		peers6[0] = 0x20;
+		peers6[1] = 0x01;
+		peers6[15] = 1;
+		peers6.extend_from_slice(&[0x1a, 0xe1]);
+		let response = bdict!(
+			b"interval".to_vec() => BValue::Int(900),
+			b"peers".to_vec() => BValue::Str(vec![10, 0, 0, 1, 0x1a, 0xe1]),
+			b"peers6".to_vec() => BValue::Str(peers6)
+		);
+		let response = decode_response(bencode::decode(&bencode::encode(&response)).unwrap()).unwrap();
+		assert_eq!(response.peers, vec![
+			PeerAddress::new(Ipv4Addr::new(10, 0, 0, 1), 6881),
+			PeerAddress::new(Ipv6Addr::new(0x2001, 0, 0, 0, 0, 0, 0, 1), 6881),
+		]);
+	}
+
+	#[test]
+	fn global_addresses() {
+		assert!(is_global(&"2001:db8::1".parse().unwrap()));
+		assert!(!is_global(&"fe80::1".parse().unwrap()));
+		assert!(!is_global(&"fd00::1".parse().unwrap()));
+		assert!(!is_global(&"::1".parse().unwrap()));
+	}
+}
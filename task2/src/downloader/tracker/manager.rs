@@ -0,0 +1,224 @@
+use std::io;
+use std::collections::HashSet;
+use rand::Rng;
+use downloader::PeerAddress;
+use downloader::connection::event_loop::EventLoop;
+use downloader::tracker::*;
+
+
+// Announces to trackers from announce-list as described in BEP 12: trackers
+// are tried in order (shuffled within each tier) starting from the first tier
+// every time, we move to the next one while current fails, and working
+// tracker is moved to the front of its tier. Failing trackers keep being
+// updated, so they retry on their own and take over again once they recover.
+// Peers received from every tracker are kept.
+pub struct TrackerManager {
+	tiers: Vec<Vec<Box<Tracker>>>,
+}
+
+impl Tracker for TrackerManager {
+	fn new(args: TrackerArgs) -> Self {
+		let url = args.tracker_url.clone();
+		TrackerManager::with_tiers(args, vec![vec![url]])
+	}
+
+	fn update(&mut self, down: usize, up: usize, left: usize) {
+		for tier in 0..self.tiers.len() {
+			for index in 0..self.tiers[tier].len() {
+				let status = {
+					let tracker = &mut self.tiers[tier][index];
+					tracker.update(down, up, left);
+					tracker.status()
+				};
+				match status {
+					Status::Working => {
+						self.promote(tier, index);
+						return;
+					}
+					// still waiting for this one
+					Status::Unknown => return,
+					Status::Failing => {}
+				}
+			}
+		}
+	}
+
+	fn stop(&mut self, down: usize, up: usize, left: usize) {
+		for tier in &mut self.tiers {
+			for tracker in tier {
+				tracker.stop(down, up, left);
+			}
+		}
+	}
+
+	fn peers<'a>(&'a self) -> Box<Iterator<Item=&'a PeerAddress> + 'a> {
+		let mut seen = HashSet::new();
+		let mut peers = Vec::new();
+		for tier in &self.tiers {
+			for tracker in tier {
+				for peer in tracker.peers() {
+					if seen.insert(peer) {
+						peers.push(peer);
+					}
+				}
+			}
+		}
+		Box::new(peers.into_iter())
+	}
+
+	fn status(&self) -> Status {
+		let statuses = self.tiers.iter()
+			.flat_map(|tier| tier.iter().map(|tracker| tracker.status()))
+			.collect::<Vec<_>>();
+		if statuses.contains(&Status::Working) {
+			Status::Working
+		} else if statuses.len() > 0 && statuses.iter().all(|&s| s == Status::Failing) {
+			Status::Failing
+		} else {
+			Status::Unknown
+		}
+	}
+
+	fn register(&self, event_loop: &EventLoop) -> io::Result<()> {
+		for tier in &self.tiers {
+			for tracker in tier {
+				try!(tracker.register(event_loop));
+			}
+		}
+		Ok(())
+	}
+}
+
+impl TrackerManager {
+	pub fn with_tiers(args: TrackerArgs, tiers: Vec<Vec<String>>) -> TrackerManager {
+		let mut rng = ::rand::thread_rng();
+		let tiers = tiers.into_iter()
+			.filter(|tier| tier.len() > 0)
+			.map(|mut tier| {
+				rng.shuffle(&mut tier);
+				tier.into_iter()
+					.map(|url| {
+						let mut args = args.clone();
+						args.tracker_url = url;
+						create_tracker(args)
+					})
+					.collect()
+			})
+			.collect();
+		TrackerManager::from_trackers(tiers)
+	}
+
+	pub fn from_trackers(tiers: Vec<Vec<Box<Tracker>>>) -> TrackerManager {
+		TrackerManager {
+			tiers: tiers,
+		}
+	}
+
+	fn promote(&mut self, tier: usize, index: usize) {
+		if index > 0 {
+			debug!("Switching to tracker #{} in tier #{}", index, tier);
+			let tracker = self.tiers[tier].remove(index);
+			self.tiers[tier].insert(0, tracker);
+		}
+	}
+}
+
+
+#[cfg(test)]
+mod test {
+	use std::cell::Cell;
+	use std::net::Ipv6Addr;
+	use std::rc::Rc;
+	use downloader::{DownloaderId, PeerAddress};
+	use downloader::tracker::*;
+	use super::TrackerManager;
+
+	struct FakeTracker {
+		status: Rc<Cell<Status>>,
+		peers: Vec<PeerAddress>,
+		updates: Rc<Cell<usize>>,
+	}
+
+	impl Tracker for FakeTracker {
+		fn new(_: TrackerArgs) -> Self {
+			FakeTracker {
+				status: Rc::new(Cell::new(Status::Unknown)),
+				peers: Vec::new(),
+				updates: Rc::new(Cell::new(0)),
+			}
+		}
+
+		fn update(&mut self, _: usize, _: usize, _: usize) {
+			self.updates.set(self.updates.get() + 1);
+		}
+
+		fn stop(&mut self, _: usize, _: usize, _: usize) {
+		}
+
+		fn peers<'a>(&'a self) -> Box<Iterator<Item=&'a PeerAddress> + 'a> {
+			Box::new(self.peers.iter())
+		}
+
+		fn status(&self) -> Status {
+			self.status.get()
+		}
+	}
+
+	fn fake(status: Status, port: u16) -> (Box<Tracker>, Rc<Cell<usize>>, Rc<Cell<Status>>) {
+		let mut tracker = FakeTracker::new(TrackerArgs {
+			tracker_url: format!("http://127.0.0.1:{}/announce", port),
+			info_hash: [0; 20],
+			id: DownloaderId([0; 20]),
+			port: 6981,
+		});
+		tracker.status.set(status);
+		tracker.peers.push(PeerAddress::new(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1), port));
+		let (updates, status) = (tracker.updates.clone(), tracker.status.clone());
+		(Box::new(tracker), updates, status)
+	}
+
+	#[test]
+	fn fails_over_and_promotes() {
+		let (dead1, dead1_updates, _) = fake(Status::Failing, 1);
+		let (dead2, _, _) = fake(Status::Failing, 2);
+		let (alive, alive_updates, _) = fake(Status::Working, 3);
+		let (backup, backup_updates, _) = fake(Status::Working, 4);
+		let mut manager = TrackerManager::from_trackers(vec![
+			vec![dead1, dead2, alive],
+			vec![backup],
+		]);
+
+		manager.update(0, 0, 0);
+		// working tracker is now first in its tier
+		assert_eq!(manager.tiers[0][0].peers().next().unwrap().port(), 3);
+		for _ in 0..4 {
+			manager.update(0, 0, 0);
+		}
+		assert_eq!(dead1_updates.get(), 1);
+		assert_eq!(alive_updates.get(), 5);
+		assert_eq!(backup_updates.get(), 0);
+		assert_eq!(manager.status(), Status::Working);
+		assert_eq!(manager.peers().count(), 4);
+	}
+
+	#[test]
+	fn first_tier_takes_over_again() {
+		let (primary, primary_updates, primary_status) = fake(Status::Unknown, 1);
+		let (backup, backup_updates, _) = fake(Status::Working, 2);
+		let mut manager = TrackerManager::from_trackers(vec![vec![primary], vec![backup]]);
+
+		// waiting for the first tracker to respond
+		manager.update(0, 0, 0);
+		assert_eq!((primary_updates.get(), backup_updates.get()), (1, 0));
+
+		primary_status.set(Status::Failing);
+		manager.update(0, 0, 0);
+		manager.update(0, 0, 0);
+		// failing tracker is still updated, so that it can retry
+		assert_eq!((primary_updates.get(), backup_updates.get()), (3, 2));
+
+		primary_status.set(Status::Working);
+		manager.update(0, 0, 0);
+		assert_eq!((primary_updates.get(), backup_updates.get()), (4, 2));
+	}
+}
@@ -1,8 +1,14 @@
 pub mod http;
+pub mod udp;
+pub mod manager;
 
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 use downloader::{DownloaderId, PeerAddress};
+use downloader::connection::event_loop::EventLoop;
 
 
+#[derive(Clone)]
 pub struct TrackerArgs {
 	pub tracker_url: String,
 	pub info_hash: [u8; 20],
@@ -10,14 +16,97 @@ pub struct TrackerArgs {
 	pub port: u16,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+	// tracker was not contacted yet, or we are waiting for response
+	Unknown,
+	Working,
+	// last request failed
+	Failing,
+}
+
 pub trait Tracker {
 	fn new(args: TrackerArgs) -> Self where Self: Sized;
 	fn update(&mut self, down: usize, up: usize, left: usize);
+	// tells tracker that we are leaving the swarm
+	fn stop(&mut self, down: usize, up: usize, left: usize);
 	fn peers<'a>(&'a self) -> Box<Iterator<Item=&'a PeerAddress> + 'a>;
+	fn status(&self) -> Status;
+	// trackers that wait for responses in the background
+	// wake up the event loop when they get one
+	fn register(&self, _event_loop: &EventLoop) -> io::Result<()> {
+		Ok(())
+	}
 }
 
 pub fn create_tracker(args: TrackerArgs) -> Box<Tracker> {
-	// TODO: check tracker url and return udp tracker client if needed
-	// right now just always try to use http tracker client
-	Box::new(http::HttpTracker::new(args))
-}
\ No newline at end of file
+	if args.tracker_url.starts_with("udp://") {
+		Box::new(udp::UdpTracker::new(args))
+	} else {
+		Box::new(http::HttpTracker::new(args))
+	}
+}
+
+// decodes peer list where every peer takes 6 bytes - 4 for ip and 2 for port
+pub fn decode_compact_peers(s: &[u8]) -> Result<Vec<PeerAddress>, &'static str> {
+	if s.len() % 6 != 0 {
+		return Err("bad packed peer list string length");
+	}
+	let peers = s.chunks(6)
+		.map(|peer| {
+			let ip = Ipv4Addr::new(peer[0], peer[1], peer[2], peer[3]);
+			PeerAddress::new(ip, read_port(&peer[4..6]))
+		})
+		.collect();
+	Ok(peers)
+}
+
+// same as above for IPv6 peers (BEP 7), 16 bytes of ip and 2 of port
+pub fn decode_compact_peers6(s: &[u8]) -> Result<Vec<PeerAddress>, &'static str> {
+	if s.len() % 18 != 0 {
+		return Err("bad packed IPv6 peer list string length");
+	}
+	let peers = s.chunks(18)
+		.map(|peer| {
+			let mut ip = [0; 16];
+			ip.copy_from_slice(&peer[..16]);
+			PeerAddress::new(Ipv6Addr::from(ip), read_port(&peer[16..18]))
+		})
+		.collect();
+	Ok(peers)
+}
+
+// 6 bytes for IPv4 peers, 18 for IPv6 ones
+pub fn encode_compact_peer(peer: &PeerAddress) -> Vec<u8> {
+	let mut encoded = match peer.ip() {
+		IpAddr::V4(ip) => ip.octets().to_vec(),
+		IpAddr::V6(ip) => ip.octets().to_vec(),
+	};
+	encoded.push((peer.port() >> 8) as u8);
+	encoded.push(peer.port() as u8);
+	encoded
+}
+
+fn read_port(bytes: &[u8]) -> u16 {
+	((bytes[0] as u16) << 8) | bytes[1] as u16
+}
+
+
+#[cfg(test)]
+mod test {
+	use std::net::{Ipv4Addr, Ipv6Addr};
+	use downloader::PeerAddress;
+	use super::*;
+
+	#[test]
+	fn compact_peers() {
+		let v4 = PeerAddress::new(Ipv4Addr::new(10, 0, 0, 1), 0x1234);
+		let v6 = PeerAddress::new(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1), 80);
+		assert_eq!(encode_compact_peer(&v4), vec![10, 0, 0, 1, 0x12, 0x34]);
+		assert_eq!(decode_compact_peers(&encode_compact_peer(&v4)), Ok(vec![v4]));
+		let encoded = encode_compact_peer(&v6);
+		assert_eq!(encoded.len(), 18);
+		assert_eq!(decode_compact_peers6(&encoded), Ok(vec![v6]));
+		assert!(decode_compact_peers6(&encoded[..17]).is_err());
+	}
+}
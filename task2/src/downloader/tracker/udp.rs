@@ -0,0 +1,660 @@
+use std::io;
+use std::io::ErrorKind;
+use std::net;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::mpsc::{channel, Sender, Receiver};
+use std::thread;
+use std::time::{Duration, Instant};
+use mio::net::UdpSocket;
+use downloader::PeerAddress;
+use downloader::connection::event_loop::{EventLoop, Waker};
+use downloader::tracker::*;
+
+
+const PROTOCOL_ID: u64 = 0x41727101980;
+const ACTION_CONNECT: u32 = 0;
+const ACTION_ANNOUNCE: u32 = 1;
+const ACTION_SCRAPE: u32 = 2;
+const ACTION_ERROR: u32 = 3;
+const EVENT_NONE: u32 = 0;
+const EVENT_COMPLETED: u32 = 1;
+const EVENT_STARTED: u32 = 2;
+const EVENT_STOPPED: u32 = 3;
+// request is sent again after 15 * 2 ^ n seconds, n going up to 8
+const RETRANSMIT_TIMEOUT: u64 = 15; // seconds
+const MAX_RETRANSMISSIONS: u32 = 8;
+const CONNECTION_ID_LIFETIME: u64 = 60; // seconds
+const STOP_TIMEOUT: u64 = 2; // seconds
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScrapeResult {
+	pub seeders: u32,
+	pub completed: u32,
+	pub leechers: u32,
+}
+
+#[derive(Clone, Copy)]
+enum Request {
+	Announce(u32),
+	Scrape,
+}
+
+struct Pending {
+	request: Request,
+	// connection id is requested first if we don't have a valid one
+	connecting: bool,
+	transaction: u32,
+	packet: Vec<u8>,
+	sent_at: Instant,
+	attempt: u32,
+}
+
+pub struct UdpTracker {
+	args: TrackerArgs,
+	// bound right away so that the event loop can watch them,
+	// address of the tracker decides which one is used
+	socket4: Option<UdpSocket>,
+	socket6: Option<UdpSocket>,
+	address: Option<SocketAddr>,
+	// name is resolved on a worker thread, as it can take a while,
+	// request waits here until the address is known
+	resolving: Option<Request>,
+	resolved: Receiver<Option<SocketAddr>>,
+	resolved_sender: Sender<Option<SocketAddr>>,
+	waker: Waker,
+	connection: Option<(u64, Instant)>,
+	pending: Option<Pending>,
+	retransmit_timeout: Duration,
+	key: u32,
+	sent_started: bool,
+	was_incomplete: bool,
+	sent_completed: bool,
+	stats: (usize, usize, usize),
+	peers: Vec<PeerAddress>,
+	next_announce: Instant,
+	failures: u32,
+	scrape_wanted: bool,
+	last_scrape: Option<ScrapeResult>,
+}
+
+impl Tracker for UdpTracker {
+	fn new(args: TrackerArgs) -> Self {
+		let (resolved_sender, resolved) = channel();
+		UdpTracker {
+			args: args,
+			socket4: bind("0.0.0.0:0"),
+			socket6: bind("[::]:0"),
+			address: None,
+			resolving: None,
+			resolved: resolved,
+			resolved_sender: resolved_sender,
+			waker: Waker::new(),
+			connection: None,
+			pending: None,
+			retransmit_timeout: Duration::from_secs(RETRANSMIT_TIMEOUT),
+			key: ::rand::random(),
+			sent_started: false,
+			was_incomplete: false,
+			sent_completed: false,
+			stats: (0, 0, 0),
+			peers: Vec::new(),
+			next_announce: Instant::now(),
+			failures: 0,
+			scrape_wanted: false,
+			last_scrape: None,
+		}
+	}
+
+	fn update(&mut self, down: usize, up: usize, left: usize) {
+		self.stats = (down, up, left);
+		if left > 0 {
+			self.was_incomplete = true;
+		}
+
+		self.check_resolved();
+		self.receive();
+		self.check_timeout();
+
+		if self.pending.is_some() || self.resolving.is_some() {
+			return;
+		}
+		let event = if !self.sent_started {
+			EVENT_STARTED
+		} else if left == 0 && self.was_incomplete && !self.sent_completed {
+			EVENT_COMPLETED
+		} else {
+			EVENT_NONE
+		};
+		// tracker should learn about completion right away
+		let completing = event == EVENT_COMPLETED && self.failures == 0;
+		if Instant::now() >= self.next_announce || completing {
+			self.start(Request::Announce(event));
+		} else if self.scrape_wanted {
+			self.scrape_wanted = false;
+			self.start(Request::Scrape);
+		}
+	}
+
+	fn stop(&mut self, down: usize, up: usize, left: usize) {
+		if !self.sent_started {
+			return;
+		}
+		self.stats = (down, up, left);
+		self.pending = None;
+		self.start(Request::Announce(EVENT_STOPPED));
+		let deadline = Instant::now() + Duration::from_secs(STOP_TIMEOUT);
+		while self.pending.is_some() && Instant::now() < deadline {
+			self.receive();
+			::std::thread::sleep(Duration::from_millis(10));
+		}
+	}
+
+	fn peers<'a>(&'a self) -> Box<Iterator<Item=&'a PeerAddress> + 'a> {
+		Box::new(self.peers.iter())
+	}
+
+	fn register(&self, event_loop: &EventLoop) -> io::Result<()> {
+		try!(event_loop.watch(&self.waker));
+		for socket in self.socket4.iter().chain(self.socket6.iter()) {
+			try!(event_loop.watch(socket));
+		}
+		Ok(())
+	}
+
+	fn status(&self) -> Status {
+		// whole retransmission schedule takes hours, so tracker
+		// that did not answer the first attempt counts as failing
+		let retransmitting = self.pending.as_ref().map(|pending| pending.attempt > 0).unwrap_or(false);
+		if self.failures > 0 || retransmitting {
+			Status::Failing
+		} else if self.sent_started {
+			Status::Working
+		} else {
+			Status::Unknown
+		}
+	}
+}
+
+impl UdpTracker {
+	// asks tracker for swarm statistics, result will
+	// be available from `last_scrape` once it arrives
+	pub fn scrape(&mut self) {
+		self.scrape_wanted = true;
+	}
+
+	pub fn last_scrape(&self) -> Option<&ScrapeResult> {
+		self.last_scrape.as_ref()
+	}
+
+	fn connection_id(&self) -> Option<u64> {
+		match self.connection {
+			Some((id, time)) => {
+				if Instant::now() - time < Duration::from_secs(CONNECTION_ID_LIFETIME) {
+					Some(id)
+				} else {
+					None
+				}
+			}
+			None => None,
+		}
+	}
+
+	fn socket(&self) -> Option<&UdpSocket> {
+		match self.address {
+			Some(SocketAddr::V4(_)) => self.socket4.as_ref(),
+			Some(SocketAddr::V6(_)) => self.socket6.as_ref(),
+			None => None,
+		}
+	}
+
+	fn resolve(&mut self, request: Request) {
+		self.resolving = Some(request);
+		let url = self.args.tracker_url.clone();
+		let resolved = self.resolved_sender.clone();
+		let wake = self.waker.handle();
+		thread::spawn(move || {
+			// tracker might be gone already
+			if resolved.send(resolve(&url)).is_ok() {
+				wake.wake();
+			}
+		});
+	}
+
+	fn check_resolved(&mut self) {
+		self.waker.reset();
+		let address = match self.resolved.try_recv() {
+			Ok(address) => address,
+			Err(_) => return,
+		};
+		let request = match self.resolving.take() {
+			Some(request) => request,
+			None => return,
+		};
+		match address {
+			Some(address) => {
+				self.address = Some(address);
+				self.start(request);
+			}
+			None => {
+				warn!("Failed to resolve tracker address: {}", self.args.tracker_url);
+				self.fail();
+			}
+		}
+	}
+
+	fn start(&mut self, request: Request) {
+		if self.address.is_none() {
+			self.resolve(request);
+			return;
+		}
+		if self.socket().is_none() {
+			warn!("No socket to reach tracker {} with", self.args.tracker_url);
+			self.fail();
+			return;
+		}
+		let transaction = ::rand::random();
+		let (connecting, packet) = match self.connection_id() {
+			Some(id) => (false, self.build_request(request, id, transaction)),
+			None => (true, build_connect(transaction)),
+		};
+		self.pending = Some(Pending {
+			request: request,
+			connecting: connecting,
+			transaction: transaction,
+			packet: packet,
+			sent_at: Instant::now(),
+			attempt: 0,
+		});
+		self.send_pending();
+	}
+
+	fn send_pending(&mut self) {
+		let result = match (self.socket(), &self.address, &self.pending) {
+			(Some(socket), &Some(ref address), &Some(ref pending)) => {
+				socket.send_to(&pending.packet, address)
+			}
+			_ => return,
+		};
+		if let Err(e) = result {
+			debug!("Failed to send tracker request: {:?}", e);
+		}
+	}
+
+	fn check_timeout(&mut self) {
+		let timed_out = match self.pending {
+			Some(ref pending) => {
+				let timeout = self.retransmit_timeout * (1 << pending.attempt);
+				Instant::now() - pending.sent_at >= timeout
+			}
+			None => false,
+		};
+		if !timed_out {
+			return;
+		}
+
+		let (request, attempt) = {
+			let pending = self.pending.as_ref().unwrap();
+			(pending.request, pending.attempt)
+		};
+		if attempt >= MAX_RETRANSMISSIONS {
+			warn!("Tracker did not respond");
+			self.fail();
+			return;
+		}
+
+		let transaction = ::rand::random();
+		// connection id might have expired while we were waiting
+		let (connecting, packet) = match self.connection_id() {
+			Some(id) => {
+				let connecting = self.pending.as_ref().unwrap().connecting;
+				if connecting {
+					(true, build_connect(transaction))
+				} else {
+					(false, self.build_request(request, id, transaction))
+				}
+			}
+			None => (true, build_connect(transaction)),
+		};
+		debug!("Retransmitting tracker request, attempt {}", attempt + 1);
+		self.pending = Some(Pending {
+			request: request,
+			connecting: connecting,
+			transaction: transaction,
+			packet: packet,
+			sent_at: Instant::now(),
+			attempt: attempt + 1,
+		});
+		self.send_pending();
+	}
+
+	fn fail(&mut self) {
+		self.pending = None;
+		self.failures += 1;
+		let shift = ::std::cmp::min(self.failures, MAX_RETRANSMISSIONS);
+		self.next_announce = Instant::now() + Duration::from_secs(10 * (1 << shift));
+	}
+
+	fn receive(&mut self) {
+		let mut buffer = [0_u8; 2048];
+		loop {
+			let result = match self.socket() {
+				Some(socket) => socket.recv_from(&mut buffer),
+				None => return,
+			};
+			match result {
+				Ok((size, from)) => {
+					if Some(from) == self.address {
+						self.handle_packet(&buffer[..size]);
+					}
+				}
+				Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
+					return;
+				}
+				Err(e) => {
+					debug!("Failed to receive tracker response: {:?}", e);
+					return;
+				}
+			}
+		}
+	}
+
+	fn handle_packet(&mut self, packet: &[u8]) {
+		if packet.len() < 8 {
+			return;
+		}
+		let action = read_u32(&packet[0..4]);
+		let transaction = read_u32(&packet[4..8]);
+		let (request, connecting) = match self.pending {
+			Some(ref pending) if pending.transaction == transaction => {
+				(pending.request, pending.connecting)
+			}
+			_ => {
+				debug!("Got tracker response with unknown transaction id");
+				return;
+			}
+		};
+
+		match (action, request) {
+			(ACTION_ERROR, _) => {
+				let message = String::from_utf8_lossy(&packet[8..]);
+				warn!("Tracker returned error: {}", message);
+				self.fail();
+			}
+			(ACTION_CONNECT, _) if connecting && packet.len() >= 16 => {
+				let id = read_u64(&packet[8..16]);
+				self.connection = Some((id, Instant::now()));
+				self.pending = None;
+				self.start(request);
+			}
+			(ACTION_ANNOUNCE, Request::Announce(event)) if !connecting && packet.len() >= 20 => {
+				let interval = read_u32(&packet[8..12]);
+				// trackers reached over IPv6 send IPv6 peers
+				let ipv6 = self.address.map(|address| address.is_ipv6()).unwrap_or(false);
+				let size = if ipv6 { 18 } else { 6 };
+				// compact peers must be whole, ignore trailing garbage
+				let end = 20 + (packet.len() - 20) / size * size;
+				let peers = if ipv6 {
+					decode_compact_peers6(&packet[20..end])
+				} else {
+					decode_compact_peers(&packet[20..end])
+				};
+				let peers = peers.unwrap_or_default();
+				debug!("Got {} peers", peers.len());
+				match event {
+					EVENT_STARTED => self.sent_started = true,
+					EVENT_COMPLETED => self.sent_completed = true,
+					_ => {}
+				}
+				self.pending = None;
+				self.failures = 0;
+				self.next_announce = Instant::now() + Duration::from_secs(interval as u64);
+				if event != EVENT_STOPPED {
+					self.peers = peers;
+				}
+			}
+			(ACTION_SCRAPE, Request::Scrape) if !connecting && packet.len() >= 20 => {
+				self.pending = None;
+				self.last_scrape = Some(ScrapeResult {
+					seeders: read_u32(&packet[8..12]),
+					completed: read_u32(&packet[12..16]),
+					leechers: read_u32(&packet[16..20]),
+				});
+			}
+			_ => {
+				debug!("Got unexpected tracker response, action: {}", action);
+			}
+		}
+	}
+
+	fn build_request(&self, request: Request, connection: u64, transaction: u32) -> Vec<u8> {
+		let mut packet = Vec::new();
+		write_u64(&mut packet, connection);
+		match request {
+			Request::Announce(event) => {
+				let (down, up, left) = self.stats;
+				write_u32(&mut packet, ACTION_ANNOUNCE);
+				write_u32(&mut packet, transaction);
+				packet.extend_from_slice(&self.args.info_hash);
+				packet.extend_from_slice(&self.args.id.0);
+				write_u64(&mut packet, down as u64);
+				write_u64(&mut packet, left as u64);
+				write_u64(&mut packet, up as u64);
+				write_u32(&mut packet, event);
+				// let tracker figure out our ip
+				write_u32(&mut packet, 0);
+				write_u32(&mut packet, self.key);
+				// default number of peers
+				write_u32(&mut packet, 0xFFFFFFFF);
+				packet.push((self.args.port >> 8) as u8);
+				packet.push((self.args.port & 0xFF) as u8);
+			}
+			Request::Scrape => {
+				write_u32(&mut packet, ACTION_SCRAPE);
+				write_u32(&mut packet, transaction);
+				packet.extend_from_slice(&self.args.info_hash);
+			}
+		}
+		packet
+	}
+}
+
+fn build_connect(transaction: u32) -> Vec<u8> {
+	let mut packet = Vec::new();
+	write_u64(&mut packet, PROTOCOL_ID);
+	write_u32(&mut packet, ACTION_CONNECT);
+	write_u32(&mut packet, transaction);
+	packet
+}
+
+fn bind(address: &str) -> Option<UdpSocket> {
+	match net::UdpSocket::bind(address).and_then(UdpSocket::from_socket) {
+		Ok(socket) => Some(socket),
+		Err(e) => {
+			debug!("Failed to open tracker socket on {}: {:?}", address, e);
+			None
+		}
+	}
+}
+
+// turns "udp://host:port/whatever" into socket address,
+// blocks while the name is looked up
+fn resolve(url: &str) -> Option<SocketAddr> {
+	let rest = match url.find("://") {
+		Some(index) => &url[(index + 3)..],
+		None => url,
+	};
+	let host = match rest.find('/') {
+		Some(index) => &rest[..index],
+		None => rest,
+	};
+	host.to_socket_addrs().ok().and_then(|mut addresses| addresses.next())
+}
+
+fn write_u32(buffer: &mut Vec<u8>, value: u32) {
+	for i in 0..4 {
+		buffer.push((value >> (24 - i * 8)) as u8);
+	}
+}
+
+fn write_u64(buffer: &mut Vec<u8>, value: u64) {
+	write_u32(buffer, (value >> 32) as u32);
+	write_u32(buffer, value as u32);
+}
+
+fn read_u32(slice: &[u8]) -> u32 {
+	slice[..4].iter().fold(0, |acc, &byte| (acc << 8) | byte as u32)
+}
+
+fn read_u64(slice: &[u8]) -> u64 {
+	((read_u32(&slice[0..4]) as u64) << 32) | read_u32(&slice[4..8]) as u64
+}
+
+
+#[cfg(test)]
+mod test {
+	use std::net::{UdpSocket, SocketAddr, Ipv4Addr};
+	use std::thread;
+	use std::time::{Duration, Instant};
+	use downloader::{DownloaderId, PeerAddress};
+	use downloader::tracker::{Tracker, TrackerArgs};
+	use super::*;
+
+	// Minimal tracker that answers connect, announce and scrape requests.
+	// First `drop_first` packets are ignored to exercise retransmission.
+	fn stand_in_tracker(drop_first: usize, bad_transaction: bool) -> SocketAddr {
+		let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+		let address = socket.local_addr().unwrap();
+		thread::spawn(move || {
+			let mut buffer = [0_u8; 1024];
+			let mut received = 0;
+			let connection_id = 0x1122334455667788_u64;
+			loop {
+				let (size, from) = socket.recv_from(&mut buffer).unwrap();
+				let packet = &buffer[..size];
+				received += 1;
+				if received <= drop_first {
+					continue;
+				}
+				let action = read_u32(&packet[8..12]);
+				let transaction = read_u32(&packet[12..16]);
+				let mut response = Vec::new();
+				if bad_transaction {
+					// answer with wrong id first, client must ignore it
+					write_u32(&mut response, ACTION_CONNECT);
+					write_u32(&mut response, transaction.wrapping_add(1));
+					write_u64(&mut response, 1);
+					socket.send_to(&response, from).unwrap();
+					response.clear();
+				}
+				match action {
+					ACTION_CONNECT => {
+						assert_eq!(read_u64(&packet[0..8]), PROTOCOL_ID);
+						write_u32(&mut response, ACTION_CONNECT);
+						write_u32(&mut response, transaction);
+						write_u64(&mut response, connection_id);
+					}
+					ACTION_ANNOUNCE => {
+						assert_eq!(read_u64(&packet[0..8]), connection_id);
+						assert_eq!(size, 98);
+						assert_eq!(&packet[16..36], &[3; 20]);
+						write_u32(&mut response, ACTION_ANNOUNCE);
+						write_u32(&mut response, transaction);
+						write_u32(&mut response, 1800);
+						write_u32(&mut response, 1);
+						write_u32(&mut response, 2);
+						response.extend_from_slice(&[10, 0, 0, 1, 0x1A, 0xE1]);
+						response.extend_from_slice(&[10, 0, 0, 2, 0x1A, 0xE2]);
+					}
+					ACTION_SCRAPE => {
+						assert_eq!(read_u64(&packet[0..8]), connection_id);
+						write_u32(&mut response, ACTION_SCRAPE);
+						write_u32(&mut response, transaction);
+						write_u32(&mut response, 5);
+						write_u32(&mut response, 6);
+						write_u32(&mut response, 7);
+					}
+					_ => panic!("unexpected action"),
+				}
+				socket.send_to(&response, from).unwrap();
+			}
+		});
+		address
+	}
+
+	fn create_tracker(address: SocketAddr) -> UdpTracker {
+		let mut tracker = UdpTracker::new(TrackerArgs {
+			tracker_url: format!("udp://{}/announce", address),
+			info_hash: [3; 20],
+			id: DownloaderId([4; 20]),
+			port: 6981,
+		});
+		tracker.retransmit_timeout = Duration::from_millis(50);
+		tracker
+	}
+
+	fn update_until<F: Fn(&UdpTracker) -> bool>(tracker: &mut UdpTracker, done: F) {
+		let deadline = Instant::now() + Duration::from_secs(5);
+		while !done(tracker) {
+			assert!(Instant::now() < deadline, "tracker did not respond in time");
+			tracker.update(0, 0, 100);
+			thread::sleep(Duration::from_millis(5));
+		}
+	}
+
+	fn expected_peers() -> Vec<PeerAddress> {
+		vec![
+			PeerAddress::new(Ipv4Addr::new(10, 0, 0, 1), 6881),
+			PeerAddress::new(Ipv4Addr::new(10, 0, 0, 2), 6882),
+		]
+	}
+
+	#[test]
+	fn announce() {
+		let mut tracker = create_tracker(stand_in_tracker(0, false));
+		update_until(&mut tracker, |t| t.peers().count() > 0);
+		assert_eq!(tracker.peers().cloned().collect::<Vec<_>>(), expected_peers());
+		assert!(tracker.sent_started);
+		assert!(tracker.connection_id().is_some());
+	}
+
+	#[test]
+	fn retransmits_lost_requests() {
+		let mut tracker = create_tracker(stand_in_tracker(2, false));
+		update_until(&mut tracker, |t| t.peers().count() > 0);
+		assert_eq!(tracker.peers().cloned().collect::<Vec<_>>(), expected_peers());
+	}
+
+	#[test]
+	fn ignores_wrong_transaction_id() {
+		let mut tracker = create_tracker(stand_in_tracker(0, true));
+		update_until(&mut tracker, |t| t.peers().count() > 0);
+		assert_eq!(tracker.connection_id(), Some(0x1122334455667788));
+	}
+
+	#[test]
+	fn scrape() {
+		let mut tracker = create_tracker(stand_in_tracker(0, false));
+		update_until(&mut tracker, |t| t.peers().count() > 0);
+		tracker.scrape();
+		update_until(&mut tracker, |t| t.last_scrape().is_some());
+		assert_eq!(tracker.last_scrape(), Some(&ScrapeResult {
+			seeders: 5,
+			completed: 6,
+			leechers: 7,
+		}));
+	}
+
+	#[test]
+	fn bad_address_fails() {
+		let mut tracker = UdpTracker::new(TrackerArgs {
+			tracker_url: "udp://no port here/announce".to_string(),
+			info_hash: [3; 20],
+			id: DownloaderId([4; 20]),
+			port: 6981,
+		});
+		tracker.update(0, 0, 100);
+		assert_eq!(tracker.status(), Status::Unknown);
+		update_until(&mut tracker, |t| t.status() == Status::Failing);
+		assert!(tracker.resolving.is_none());
+	}
+}
@@ -0,0 +1,341 @@
+use std::collections::HashMap;
+use std::io;
+use std::io::ErrorKind;
+use std::net;
+use std::net::{SocketAddr, IpAddr, Ipv4Addr};
+use std::time::{Duration, Instant};
+use mio::net::UdpSocket;
+use net2::UdpBuilder;
+use downloader::PeerAddress;
+use downloader::connection::event_loop::EventLoop;
+use magnet::hex_digit;
+
+
+pub const MULTICAST_GROUP: Ipv4Addr = Ipv4Addr::new(239, 192, 152, 143);
+pub const MULTICAST_PORT: u16 = 6771;
+// every torrent is announced this often
+const ANNOUNCE_INTERVAL: u64 = 5 * 60; // seconds
+// announcements of all torrents that are due are sent together,
+// but not more often than this
+const MIN_SEND_INTERVAL: u64 = 60; // seconds
+// announcements of a torrent from a single host arriving
+// faster than this are ignored
+const MIN_RECEIVE_INTERVAL: u64 = 10; // seconds
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Announce {
+	pub port: u16,
+	pub info_hashes: Vec<[u8; 20]>,
+	pub cookie: Option<String>,
+}
+
+struct Torrent {
+	next_announce: Instant,
+	found: Vec<PeerAddress>,
+}
+
+// Local Service Discovery (BEP 14): finds peers on the local network by
+// multicasting which torrents we have. Must be updated regularly.
+pub struct Lsd {
+	socket: UdpSocket,
+	group: SocketAddr,
+	port: u16,
+	// lets us recognize our own announcements coming back
+	cookie: String,
+	torrents: HashMap<[u8; 20], Torrent>,
+	last_sent: Option<Instant>,
+	last_heard: HashMap<(IpAddr, [u8; 20]), Instant>,
+}
+
+impl Lsd {
+	// port is where we accept peer connections
+	pub fn new(port: u16) -> io::Result<Lsd> {
+		// other clients on this machine listen on the same port
+		let builder = try!(UdpBuilder::new_v4());
+		try!(builder.reuse_address(true));
+		let socket = try!(builder.bind(("0.0.0.0", MULTICAST_PORT)));
+		try!(socket.join_multicast_v4(&MULTICAST_GROUP, &Ipv4Addr::new(0, 0, 0, 0)));
+		try!(socket.set_multicast_loop_v4(true));
+		let group = SocketAddr::new(IpAddr::V4(MULTICAST_GROUP), MULTICAST_PORT);
+		Lsd::with_socket(socket, group, port)
+	}
+
+	// sends announcements to given address instead of the multicast group
+	fn with_socket(socket: net::UdpSocket, group: SocketAddr, port: u16) -> io::Result<Lsd> {
+		Ok(Lsd {
+			socket: try!(UdpSocket::from_socket(socket)),
+			group: group,
+			port: port,
+			cookie: format!("{:08x}", ::rand::random::<u32>()),
+			torrents: HashMap::new(),
+			last_sent: None,
+			last_heard: HashMap::new(),
+		})
+	}
+
+	// announcements wake the event loop up
+	pub fn register(&self, event_loop: &EventLoop) -> io::Result<()> {
+		event_loop.watch(&self.socket)
+	}
+
+	pub fn add_torrent(&mut self, info_hash: [u8; 20]) {
+		self.torrents.insert(info_hash, Torrent {
+			next_announce: Instant::now(),
+			found: Vec::new(),
+		});
+	}
+
+	pub fn remove_torrent(&mut self, info_hash: &[u8; 20]) {
+		self.torrents.remove(info_hash);
+	}
+
+	// peers found since the last call
+	pub fn take_peers(&mut self, info_hash: &[u8; 20]) -> Vec<PeerAddress> {
+		match self.torrents.get_mut(info_hash) {
+			Some(torrent) => ::std::mem::replace(&mut torrent.found, Vec::new()),
+			None => Vec::new(),
+		}
+	}
+
+	pub fn update(&mut self) {
+		self.receive();
+		self.announce();
+	}
+
+	fn announce(&mut self) {
+		let now = Instant::now();
+		if let Some(last) = self.last_sent {
+			if now - last < Duration::from_secs(MIN_SEND_INTERVAL) {
+				return;
+			}
+		}
+		let mut due = Vec::new();
+		for (info_hash, torrent) in self.torrents.iter_mut() {
+			if torrent.next_announce <= now {
+				torrent.next_announce = now + Duration::from_secs(ANNOUNCE_INTERVAL);
+				due.push(*info_hash);
+			}
+		}
+		if due.len() == 0 {
+			return;
+		}
+		self.last_sent = Some(now);
+		let message = build_announce(&self.group, self.port, &due, &self.cookie);
+		if let Err(e) = self.socket.send_to(message.as_bytes(), &self.group) {
+			debug!("Failed to send LSD announcement: {:?}", e);
+		}
+	}
+
+	fn receive(&mut self) {
+		let mut buffer = [0_u8; 1500];
+		loop {
+			match self.socket.recv_from(&mut buffer) {
+				Ok((size, from)) => {
+					self.handle_packet(&buffer[..size], from);
+				}
+				Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
+					return;
+				}
+				Err(e) => {
+					debug!("Failed to receive LSD announcement: {:?}", e);
+					return;
+				}
+			}
+		}
+	}
+
+	fn handle_packet(&mut self, packet: &[u8], from: SocketAddr) {
+		let announce = match parse_announce(packet) {
+			Some(announce) => announce,
+			None => {
+				debug!("Got malformed LSD announcement from {:?}", from);
+				return;
+			}
+		};
+		if announce.cookie.as_ref() == Some(&self.cookie) {
+			return;
+		}
+		let now = Instant::now();
+		let peer = PeerAddress::new(from.ip(), announce.port);
+		for info_hash in &announce.info_hashes {
+			if let Some(torrent) = self.torrents.get_mut(info_hash) {
+				let key = (from.ip(), *info_hash);
+				if let Some(&last) = self.last_heard.get(&key) {
+					if now - last < Duration::from_secs(MIN_RECEIVE_INTERVAL) {
+						continue;
+					}
+				}
+				self.last_heard.insert(key, now);
+				debug!("Found local peer {:?}", peer);
+				if !torrent.found.contains(&peer) {
+					torrent.found.push(peer.clone());
+				}
+			}
+		}
+	}
+}
+
+pub fn build_announce(group: &SocketAddr, port: u16, info_hashes: &[[u8; 20]], cookie: &str) -> String {
+	let mut message = format!("BT-SEARCH * HTTP/1.1\r\nHost: {}\r\nPort: {}\r\n", group, port);
+	for info_hash in info_hashes {
+		let hex = info_hash.iter().map(|byte| format!("{:02x}", byte)).collect::<String>();
+		message.push_str(&format!("Infohash: {}\r\n", hex));
+	}
+	message.push_str(&format!("cookie: {}\r\n\r\n\r\n", cookie));
+	message
+}
+
+pub fn parse_announce(packet: &[u8]) -> Option<Announce> {
+	let text = match ::std::str::from_utf8(packet) {
+		Ok(text) => text,
+		Err(_) => return None,
+	};
+	let mut lines = text.split("\r\n");
+	if lines.next() != Some("BT-SEARCH * HTTP/1.1") {
+		return None;
+	}
+
+	let mut port = None;
+	let mut info_hashes = Vec::new();
+	let mut cookie = None;
+	for line in lines {
+		let (name, value) = match line.find(':') {
+			Some(index) => (&line[..index], line[(index + 1)..].trim()),
+			None => continue,
+		};
+		match name.to_lowercase().as_str() {
+			"port" => port = value.parse::<u16>().ok().filter(|&port| port > 0),
+			"infohash" => {
+				if let Some(info_hash) = decode_hex_hash(value) {
+					info_hashes.push(info_hash);
+				}
+			}
+			"cookie" => cookie = Some(value.to_string()),
+			_ => {}
+		}
+	}
+
+	match port {
+		Some(port) if info_hashes.len() > 0 => Some(Announce {
+			port: port,
+			info_hashes: info_hashes,
+			cookie: cookie,
+		}),
+		_ => None,
+	}
+}
+
+fn decode_hex_hash(hex: &str) -> Option<[u8; 20]> {
+	// digits are checked byte by byte, value might not be ASCII
+	let hex = hex.as_bytes();
+	if hex.len() != 40 {
+		return None;
+	}
+	let mut hash = [0; 20];
+	for i in 0..20 {
+		match (hex_digit(hex[i * 2]), hex_digit(hex[i * 2 + 1])) {
+			(Some(high), Some(low)) => hash[i] = (high << 4) | low,
+			_ => return None,
+		}
+	}
+	Some(hash)
+}
+
+
+#[cfg(test)]
+mod test {
+	use std::net::{UdpSocket, Ipv4Addr};
+	use std::thread;
+	use std::time::{Duration, Instant};
+	use downloader::PeerAddress;
+	use super::*;
+
+	#[test]
+	fn announce_roundtrip() {
+		let group = "239.192.152.143:6771".parse().unwrap();
+		let message = build_announce(&group, 6881, &[[0xab; 20], [1; 20]], "c00k1e");
+		assert!(message.starts_with("BT-SEARCH * HTTP/1.1\r\nHost: 239.192.152.143:6771\r\n"));
+		assert_eq!(parse_announce(message.as_bytes()), Some(Announce {
+			port: 6881,
+			info_hashes: vec![[0xab; 20], [1; 20]],
+			cookie: Some("c00k1e".to_string()),
+		}));
+		assert_eq!(parse_announce(b"BT-SEARCH * HTTP/1.1\r\nPort: 1\r\n\r\n"), None);
+		assert_eq!(parse_announce(b"GET / HTTP/1.1\r\n\r\n"), None);
+		// 40 bytes, but not 40 hex digits
+		let bad_hash = format!("a\u{e9}{}", "0".repeat(37));
+		assert_eq!(bad_hash.len(), 40);
+		let message = format!("BT-SEARCH * HTTP/1.1\r\nPort: 1\r\nInfohash: {}\r\n\r\n", bad_hash);
+		assert_eq!(parse_announce(message.as_bytes()), None);
+		let message = format!("BT-SEARCH * HTTP/1.1\r\nPort: 1\r\nInfohash: +{}\r\n\r\n", "0".repeat(39));
+		assert_eq!(parse_announce(message.as_bytes()), None);
+	}
+
+	// instead of multicast both sides send straight to each other
+	fn pair() -> (Lsd, Lsd) {
+		let first = UdpSocket::bind("127.0.0.1:0").unwrap();
+		let second = UdpSocket::bind("127.0.0.1:0").unwrap();
+		let first_address = first.local_addr().unwrap();
+		let second_address = second.local_addr().unwrap();
+		(Lsd::with_socket(first, second_address, 1111).unwrap(),
+			Lsd::with_socket(second, first_address, 2222).unwrap())
+	}
+
+	#[test]
+	fn finds_local_peers() {
+		let (mut first, mut second) = pair();
+		first.add_torrent([7; 20]);
+		second.add_torrent([7; 20]);
+		second.add_torrent([8; 20]);
+
+		let expected = PeerAddress::new(Ipv4Addr::new(127, 0, 0, 1), 1111);
+		let deadline = Instant::now() + Duration::from_secs(5);
+		while second.torrents[&[7; 20]].found.len() == 0 {
+			assert!(Instant::now() < deadline, "announcement did not arrive");
+			first.update();
+			second.update();
+			thread::sleep(Duration::from_millis(5));
+		}
+		assert_eq!(second.take_peers(&[7; 20]), vec![expected]);
+		assert!(second.take_peers(&[8; 20]).is_empty());
+
+		// nothing more is sent until the interval passes
+		first.torrents.get_mut(&[7; 20]).unwrap().next_announce = Instant::now();
+		first.update();
+		thread::sleep(Duration::from_millis(50));
+		second.update();
+		assert!(second.take_peers(&[7; 20]).is_empty());
+	}
+
+	#[test]
+	fn limits_announcements_per_torrent() {
+		let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+		let address = socket.local_addr().unwrap();
+		let mut lsd = Lsd::with_socket(socket, address, 1111).unwrap();
+		lsd.add_torrent([7; 20]);
+		lsd.add_torrent([8; 20]);
+		let from = "10.0.0.2:6771".parse().unwrap();
+		let expected = vec![PeerAddress::new(Ipv4Addr::new(10, 0, 0, 2), 2222)];
+
+		lsd.handle_packet(build_announce(&address, 2222, &[[7; 20]], "x").as_bytes(), from);
+		// the same host announcing another torrent right away is heard
+		lsd.handle_packet(build_announce(&address, 2222, &[[8; 20]], "x").as_bytes(), from);
+		assert_eq!(lsd.take_peers(&[7; 20]), expected);
+		assert_eq!(lsd.take_peers(&[8; 20]), expected);
+
+		lsd.handle_packet(build_announce(&address, 2222, &[[7; 20]], "x").as_bytes(), from);
+		assert!(lsd.take_peers(&[7; 20]).is_empty());
+	}
+
+	#[test]
+	fn ignores_own_announcements() {
+		let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+		let address = socket.local_addr().unwrap();
+		let mut lsd = Lsd::with_socket(socket, address, 1111).unwrap();
+		lsd.add_torrent([7; 20]);
+		lsd.update();
+		thread::sleep(Duration::from_millis(50));
+		lsd.update();
+		assert!(lsd.take_peers(&[7; 20]).is_empty());
+	}
+}
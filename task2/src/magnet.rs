@@ -0,0 +1,165 @@
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Magnet {
+	pub info_hash: [u8; 20],
+	pub name: Option<String>,
+	pub trackers: Vec<String>,
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum ParseError {
+	NotMagnet,
+	MissingInfoHash,
+	BadInfoHash,
+	BadEncoding,
+}
+
+pub type ParseResult<T> = Result<T, ParseError>;
+
+pub fn parse(uri: &str) -> ParseResult<Magnet> {
+	if !uri.starts_with("magnet:?") {
+		return Err(ParseError::NotMagnet);
+	}
+
+	let mut info_hash = None;
+	let mut name = None;
+	let mut trackers = Vec::new();
+	for param in uri["magnet:?".len()..].split('&') {
+		let (key, value) = match param.find('=') {
+			Some(index) => (&param[..index], &param[(index + 1)..]),
+			None => continue,
+		};
+		let value = try!(percent_decode(value));
+		match key {
+			"xt" => {
+				if value.starts_with("urn:btih:") {
+					info_hash = Some(try!(decode_info_hash(&value["urn:btih:".len()..])));
+				}
+			}
+			"dn" => name = Some(value),
+			"tr" => trackers.push(value),
+			_ => {}
+		}
+	}
+
+	Ok(Magnet {
+		info_hash: try!(info_hash.ok_or(ParseError::MissingInfoHash)),
+		name: name,
+		trackers: trackers,
+	})
+}
+
+// info hash is either 40 hex digits, or 32 base32 characters
+fn decode_info_hash(hash: &str) -> ParseResult<[u8; 20]> {
+	let bytes = hash.as_bytes();
+	let mut result = [0_u8; 20];
+	if bytes.len() == 40 {
+		for i in 0..20 {
+			let high = try!(hex_digit(bytes[i * 2]).ok_or(ParseError::BadInfoHash));
+			let low = try!(hex_digit(bytes[i * 2 + 1]).ok_or(ParseError::BadInfoHash));
+			result[i] = (high << 4) | low;
+		}
+		Ok(result)
+	} else if bytes.len() == 32 {
+		let mut buffer = 0_u64;
+		let mut bits = 0;
+		let mut position = 0;
+		for &byte in bytes {
+			let value = try!(base32_digit(byte).ok_or(ParseError::BadInfoHash));
+			buffer = (buffer << 5) | value as u64;
+			bits += 5;
+			if bits >= 8 {
+				bits -= 8;
+				result[position] = (buffer >> bits) as u8;
+				position += 1;
+			}
+		}
+		Ok(result)
+	} else {
+		Err(ParseError::BadInfoHash)
+	}
+}
+
+fn percent_decode(value: &str) -> ParseResult<String> {
+	let bytes = value.as_bytes();
+	let mut decoded = Vec::new();
+	let mut i = 0;
+	while i < bytes.len() {
+		match bytes[i] {
+			b'%' if i + 2 < bytes.len() => {
+				let high = try!(hex_digit(bytes[i + 1]).ok_or(ParseError::BadEncoding));
+				let low = try!(hex_digit(bytes[i + 2]).ok_or(ParseError::BadEncoding));
+				decoded.push((high << 4) | low);
+				i += 3;
+			}
+			b'%' => return Err(ParseError::BadEncoding),
+			b'+' => {
+				decoded.push(b' ');
+				i += 1;
+			}
+			byte => {
+				decoded.push(byte);
+				i += 1;
+			}
+		}
+	}
+	String::from_utf8(decoded).map_err(|_| ParseError::BadEncoding)
+}
+
+pub fn hex_digit(ch: u8) -> Option<u8> {
+	match ch {
+		b'0' ... b'9' => Some(ch - b'0'),
+		b'a' ... b'f' => Some(ch - b'a' + 10),
+		b'A' ... b'F' => Some(ch - b'A' + 10),
+		_ => None,
+	}
+}
+
+fn base32_digit(ch: u8) -> Option<u8> {
+	match ch {
+		b'A' ... b'Z' => Some(ch - b'A'),
+		b'a' ... b'z' => Some(ch - b'a'),
+		b'2' ... b'7' => Some(ch - b'2' + 26),
+		_ => None,
+	}
+}
+
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn hex_hash() {
+		let magnet = parse("magnet:?xt=urn:btih:0123456789abcdef0123456789ABCDEF01234567\
+			&dn=Some+file%20name&tr=udp%3A%2F%2Ftracker%3A80&tr=http://other/announce").unwrap();
+		assert_eq!(magnet.info_hash, [
+			0x01, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd, 0xef, 0x01, 0x23,
+			0x45, 0x67, 0x89, 0xab, 0xcd, 0xef, 0x01, 0x23, 0x45, 0x67,
+		]);
+		assert_eq!(magnet.name, Some("Some file name".to_string()));
+		assert_eq!(magnet.trackers, vec![
+			"udp://tracker:80".to_string(),
+			"http://other/announce".to_string(),
+		]);
+	}
+
+	#[test]
+	fn base32_hash() {
+		let magnet = parse("magnet:?xt=urn:btih:AERUKZ4JVPG66AJDIVTYTK6N54ASGRLH").unwrap();
+		assert_eq!(magnet.info_hash, [
+			0x01, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd, 0xef, 0x01, 0x23,
+			0x45, 0x67, 0x89, 0xab, 0xcd, 0xef, 0x01, 0x23, 0x45, 0x67,
+		]);
+		assert_eq!(magnet.name, None);
+		assert_eq!(magnet.trackers.len(), 0);
+	}
+
+	#[test]
+	fn errors() {
+		assert_eq!(parse("http://foo"), Err(ParseError::NotMagnet));
+		assert_eq!(parse("magnet:?dn=x"), Err(ParseError::MissingInfoHash));
+		assert_eq!(parse("magnet:?xt=urn:btih:1234"), Err(ParseError::BadInfoHash));
+		assert_eq!(parse("magnet:?xt=urn:btih:0123456789abcdef0123456789ABCDEF01234567&dn=%4"),
+			Err(ParseError::BadEncoding));
+	}
+}
@@ -3,51 +3,185 @@
 extern crate rand;
 extern crate sha1;
 extern crate hyper;
+extern crate mio;
+extern crate num_cpus;
+extern crate net2;
 #[macro_use]
 extern crate log;
 
+#[macro_use]
 pub mod bencode;
 pub mod torrent;
 pub mod downloader;
 pub mod storage;
+pub mod magnet;
+pub mod dht;
+pub mod lsd;
 
 use std::fs::File;
 use std::io::Read;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::env;
+use std::io;
+use std::io::BufRead;
+use std::thread;
+use std::time::Duration;
 use log::{LogRecord, LogLevel, LogMetadata, SetLoggerError};
 
 use torrent::Torrent;
-use downloader::Downloader;
-use storage::memory::MemoryStorage;
-use storage::partial::PartialStorage;
+use downloader::{Downloader, Config};
+use downloader::metadata::MetadataFetcher;
+use downloader::picker::Priority;
+use downloader::selection::FileRule;
+use downloader::stream::TorrentReader;
+use storage::file::FileStorage;
 
 fn main() {
     Logger::init().expect("Failed to initialize logger");
 
-    let args: Vec<String> = env::args().collect();
-    let path = match args.into_iter().nth(1) {
-        Some(arg) => arg,
+    let mut config = Config::default();
+    let mut path = None;
+    let mut only_selected = false;
+    let mut stream = None;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--resume" => {
+                config.resume_file = args.next().map(PathBuf::from);
+            }
+            "--seed-ratio" => {
+                config.seed_ratio = args.next().and_then(|x| x.parse().ok());
+            }
+            "--no-dht" => {
+                config.dht = false;
+            }
+            "--no-lsd" => {
+                config.lsd = false;
+            }
+            "--sequential" => {
+                config.sequential = true;
+            }
+            "--stream" => {
+                let index = args.next().and_then(|x| x.parse::<usize>().ok());
+                let output = args.next().map(PathBuf::from);
+                stream = index.and_then(|index| output.map(|output| (index, output)));
+            }
+            "--dht-nodes" => {
+                config.dht_nodes_file = args.next().map(PathBuf::from);
+            }
+            "--seed-time" => {
+                config.seed_time = args.next()
+                    .and_then(|x| x.parse::<u64>().ok())
+                    .map(|minutes| Duration::from_secs(minutes * 60));
+            }
+            "--only" => {
+                if !only_selected {
+                    // everything else is skipped, no matter where it is given
+                    config.files.insert(0, FileRule::new("*", Priority::Skip));
+                    only_selected = true;
+                }
+                if let Some(files) = args.next() {
+                    config.files.push(FileRule::new(files, Priority::Normal));
+                }
+            }
+            "--skip" | "--low" | "--high" => {
+                let priority = match arg.as_str() {
+                    "--skip" => Priority::Skip,
+                    "--low" => Priority::Low,
+                    _ => Priority::High,
+                };
+                if let Some(files) = args.next() {
+                    config.files.push(FileRule::new(files, priority));
+                }
+            }
+            _ => {
+                path = Some(arg);
+            }
+        }
+    }
+    let path = match path {
+        Some(path) => path,
         None => {
-            println!("Usage: thing <torrent file>");
+            println!("Usage: thing <torrent file | magnet link> [options]");
+            println!("  --resume <state file>");
+            println!("  --seed-ratio <ratio>");
+            println!("  --seed-time <minutes>");
+            println!("  --no-dht");
+            println!("  --dht-nodes <node cache file>");
+            println!("  --no-lsd");
+            println!("  --sequential      download pieces in order");
+            println!("  --stream <file index> <output>");
+            println!("                    write file to output as soon as its data is there");
+            println!("  --only <files>    download only these files");
+            println!("  --skip <files>");
+            println!("  --low <files>");
+            println!("  --high <files>");
+            println!("    <files> is a comma separated list of file indexes");
+            println!("    and path patterns, e.g. 0,3,*.mkv");
             return;
         }
     };
 
-    println!("Torrent file: {}", path);
-    
-    let (torrent, info_hash) = read_torrent_file(path.clone()).unwrap();
+    let loaded = if path.starts_with("magnet:") {
+        println!("Magnet link: {}", path);
+        fetch_magnet_torrent(&path, &config)
+    } else {
+        println!("Torrent file: {}", path);
+        read_torrent_file(path.clone())
+    };
+    // errors are already printed
+    let (torrent, info_hash) = match loaded {
+        Some(loaded) => loaded,
+        None => return,
+    };
     
     println!("Parsed file!");
     println!("Downloading: {:?}", torrent.info.root);
+    for (index, file) in torrent.info.files.iter().enumerate() {
+        println!("  {}: {:?} ({} bytes)", index, file.path, file.length);
+    }
     
-    let mut downloader: Downloader<PartialStorage<MemoryStorage>> =
-        Downloader::new(info_hash, torrent.clone());
+    let mut downloader: Downloader<FileStorage> =
+        Downloader::new(info_hash, torrent, config);
+
+    if let Some((index, output)) = stream {
+        match downloader.file_reader(index) {
+            Some(reader) => stream_file(reader, output),
+            None => {
+                println!("There is no file #{} to stream", index);
+                return;
+            }
+        }
+    }
+
+    let stop = downloader.stop_handle();
+    thread::spawn(move || {
+        println!("Type \"stop\" to quit");
+        let stdin = io::stdin();
+        for line in stdin.lock().lines() {
+            match line {
+                Ok(ref line) if line.trim() == "stop" => {
+                    stop.stop();
+                    return;
+                }
+                Ok(_) => {}
+                Err(_) => return,
+            }
+        }
+    });
 
     downloader.run();
+}
 
-    println!("splitting");
-    split_to_files("./test.out", torrent);
+// Reader blocks until data is downloaded, so it is copied on its own thread.
+fn stream_file(mut reader: TorrentReader, output: PathBuf) {
+    thread::spawn(move || {
+        let copied = File::create(&output).and_then(|mut file| io::copy(&mut reader, &mut file));
+        match copied {
+            Ok(bytes) => println!("Streamed {} bytes to {:?}", bytes, output),
+            Err(e) => println!("Streaming to {:?} failed: {}", output, e),
+        }
+    });
 }
 
 fn read_torrent_file<P: AsRef<Path>>(path: P) -> Option<(Torrent, [u8; 20])> {
@@ -74,20 +208,43 @@ fn read_torrent_file<P: AsRef<Path>>(path: P) -> Option<(Torrent, [u8; 20])> {
     Some((torrent, info_hash))
 }
 
-fn split_to_files<P: AsRef<Path>>(source: P, torrent: Torrent) {
-    let mut source = File::open(source).expect("failed to open source file");
-    use std::io::prelude::*;
-    let mut data = Vec::new();
-    source.read_to_end(&mut data).expect("failed to read source");
-    let mut start = 0_usize;
-    for file in torrent.info.files.into_iter() {
-        let mut dest = File::create(file.path.clone()).expect("failed to create file");
-        let end = start + file.length as usize;
-        println!("interval {} - {} goes to {:?}", start, end, file.path.clone());
-        dest.write_all(&data[start..end]).expect("failed to write");
-        start = end;
+fn fetch_magnet_torrent(uri: &str, config: &Config) -> Option<(Torrent, [u8; 20])> {
+    let magnet = match magnet::parse(uri) {
+        Ok(x) => x,
+        Err(e) => {
+            println!("failed to parse magnet link:\n  {:?}", e);
+            return None;
+        }
+    };
+    if let Some(ref name) = magnet.name {
+        println!("Fetching metadata for: {}", name);
     }
-    println!("wrote total {} bytes", start);
+
+    // trackers from magnet link have no tiers, try them one after another
+    let trackers = magnet.trackers.into_iter()
+        .map(|tracker| vec![tracker])
+        .collect::<Vec<_>>();
+    let metadata = match MetadataFetcher::new(magnet.info_hash, trackers.clone(), config).run() {
+        Ok(x) => x,
+        Err(e) => {
+            println!("failed to fetch metadata:\n  {}", e);
+            return None;
+        }
+    };
+
+    let (info, info_hash) = match torrent::decode_info(metadata) {
+        Ok(x) => x,
+        Err(e) => {
+            println!("failed to parse metadata:\n  {:?}", e);
+            return None;
+        }
+    };
+
+    Some((Torrent {
+        trackers: trackers,
+        nodes: Vec::new(),
+        info: info,
+    }, info_hash))
 }
 
 const LOGGING_LEVEL: LogLevel = LogLevel::Debug;
@@ -0,0 +1,174 @@
+use storage::BLOCK_SIZE;
+use downloader::PeerAddress;
+
+
+// Keeps track of which blocks of a piece were received, so that blocks
+// can be stored in whatever order they arrive. Piece is divided into
+// BLOCK_SIZE blocks (last one may be shorter) and a block counts as
+// received only when all of its bytes were stored.
+pub struct BlockMap {
+	size: usize,
+	bits: Vec<u8>,
+	received: usize,
+	// peer that sent each block, to know whom to blame for bad data
+	sources: Vec<Option<PeerAddress>>,
+}
+
+impl BlockMap {
+	pub fn new(size: usize) -> BlockMap {
+		let blocks = (size + BLOCK_SIZE - 1) / BLOCK_SIZE;
+		BlockMap {
+			size: size,
+			bits: vec![0; (blocks + 7) / 8],
+			received: 0,
+			sources: vec![None; blocks],
+		}
+	}
+
+	pub fn block_count(&self) -> usize {
+		(self.size + BLOCK_SIZE - 1) / BLOCK_SIZE
+	}
+
+	pub fn has_block(&self, block: usize) -> bool {
+		self.bits[block / 8] & (1 << (7 - block % 8)) != 0
+	}
+
+	pub fn bytes_missing(&self) -> usize {
+		self.size - self.received
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.received == 0
+	}
+
+	pub fn is_complete(&self) -> bool {
+		self.received == self.size
+	}
+
+	// Marks blocks lying completely within given range as received,
+	// returns byte ranges of the ones that were missing until now.
+	// Bytes of blocks only partially covered by the range are ignored.
+	pub fn add(&mut self, offset: usize, length: usize, source: Option<&PeerAddress>) -> Vec<(usize, usize)> {
+		let end = offset + length;
+		let first = (offset + BLOCK_SIZE - 1) / BLOCK_SIZE;
+		let mut added: Vec<(usize, usize)> = Vec::new();
+		for block in first..self.block_count() {
+			let (start, block_end) = self.block_range(block);
+			if block_end > end {
+				break;
+			}
+			if self.has_block(block) {
+				continue;
+			}
+			self.bits[block / 8] |= 1 << (7 - block % 8);
+			self.sources[block] = source.cloned();
+			self.received += block_end - start;
+			match added.last_mut() {
+				Some(range) if range.1 == start => range.1 = block_end,
+				_ => added.push((start, block_end)),
+			}
+		}
+		added
+	}
+
+	pub fn fill(&mut self) {
+		let size = self.size;
+		self.add(0, size, None);
+	}
+
+	pub fn clear(&mut self) {
+		for byte in &mut self.bits {
+			*byte = 0;
+		}
+		for source in &mut self.sources {
+			*source = None;
+		}
+		self.received = 0;
+	}
+
+	// distinct peers that sent received blocks, None if some were added without one
+	pub fn sources(&self) -> Vec<Option<PeerAddress>> {
+		let mut sources: Vec<Option<PeerAddress>> = Vec::new();
+		for (block, source) in self.sources.iter().enumerate() {
+			if self.has_block(block) && !sources.contains(source) {
+				sources.push(source.clone());
+			}
+		}
+		sources
+	}
+
+	// byte ranges of runs of received blocks
+	pub fn received_ranges(&self) -> Vec<(usize, usize)> {
+		self.ranges(true)
+	}
+
+	// byte ranges of runs of missing blocks
+	pub fn missing_ranges(&self) -> Vec<(usize, usize)> {
+		self.ranges(false)
+	}
+
+	fn ranges(&self, received: bool) -> Vec<(usize, usize)> {
+		let mut ranges: Vec<(usize, usize)> = Vec::new();
+		for block in (0..self.block_count()).filter(|&b| self.has_block(b) == received) {
+			let (start, end) = self.block_range(block);
+			match ranges.last_mut() {
+				Some(range) if range.1 == start => range.1 = end,
+				_ => ranges.push((start, end)),
+			}
+		}
+		ranges
+	}
+
+	fn block_range(&self, block: usize) -> (usize, usize) {
+		let start = block * BLOCK_SIZE;
+		(start, ::std::cmp::min(start + BLOCK_SIZE, self.size))
+	}
+}
+
+
+#[cfg(test)]
+mod test {
+	use std::net::Ipv4Addr;
+	use storage::BLOCK_SIZE;
+	use downloader::PeerAddress;
+	use super::BlockMap;
+
+	#[test]
+	fn out_of_order_blocks() {
+		let size = 3 * BLOCK_SIZE + 10;
+		let mut map = BlockMap::new(size);
+		assert_eq!(map.missing_ranges(), vec![(0, size)]);
+
+		assert_eq!(map.add(3 * BLOCK_SIZE, 10, None), vec![(3 * BLOCK_SIZE, size)]);
+		assert_eq!(map.add(BLOCK_SIZE, BLOCK_SIZE, None), vec![(BLOCK_SIZE, 2 * BLOCK_SIZE)]);
+		// already received
+		assert_eq!(map.add(BLOCK_SIZE, BLOCK_SIZE, None), vec![]);
+		assert_eq!(map.bytes_missing(), 2 * BLOCK_SIZE);
+		assert_eq!(map.missing_ranges(), vec![(0, BLOCK_SIZE), (2 * BLOCK_SIZE, 3 * BLOCK_SIZE)]);
+		assert_eq!(map.received_ranges(), vec![(BLOCK_SIZE, 2 * BLOCK_SIZE), (3 * BLOCK_SIZE, size)]);
+
+		// only whole blocks count
+		assert_eq!(map.add(10, 3 * BLOCK_SIZE, None), vec![(2 * BLOCK_SIZE, 3 * BLOCK_SIZE)]);
+		assert_eq!(map.add(0, 2 * BLOCK_SIZE, None), vec![(0, BLOCK_SIZE)]);
+		assert!(map.is_complete());
+
+		map.clear();
+		assert!(map.is_empty());
+		assert_eq!(map.missing_ranges(), vec![(0, size)]);
+	}
+
+	#[test]
+	fn remembers_sources() {
+		let first = PeerAddress::new(Ipv4Addr::new(10, 0, 0, 1), 6881);
+		let second = PeerAddress::new(Ipv4Addr::new(10, 0, 0, 2), 6881);
+		let mut map = BlockMap::new(3 * BLOCK_SIZE);
+		map.add(0, BLOCK_SIZE, Some(&first));
+		map.add(BLOCK_SIZE, BLOCK_SIZE, None);
+		map.add(2 * BLOCK_SIZE, BLOCK_SIZE, Some(&second));
+		// block is already there, source is not changed
+		map.add(0, BLOCK_SIZE, Some(&second));
+		assert_eq!(map.sources(), vec![Some(first), None, Some(second)]);
+		map.clear();
+		assert!(map.sources().is_empty());
+	}
+}
@@ -0,0 +1,465 @@
+use std::fs;
+use std::io;
+use std::io::{Read, Write, Seek, SeekFrom};
+use std::path::PathBuf;
+use storage::*;
+use storage::blocks::BlockMap;
+use downloader::PeerAddress;
+use downloader::request::Request;
+use torrent::TorrentInfo;
+
+
+struct Piece {
+	size: usize,
+	blocks: BlockMap,
+	verified: bool,
+	wanted: bool,
+	hash: [u8; 20],
+}
+
+impl Piece {
+	fn is_complete(&self) -> bool {
+		self.verified
+	}
+
+	fn create_fill_requests(&self, index: usize) -> Vec<Request> {
+		self.blocks.missing_ranges()
+			.into_iter()
+			.map(|(start, end)| Request::new(index, start, end - start))
+			.collect()
+	}
+}
+
+struct FileEntry {
+	path: PathBuf,
+	start: u64,
+	length: u64,
+	handle: Option<fs::File>,
+}
+
+impl FileEntry {
+	fn end(&self) -> u64 {
+		self.start + self.length
+	}
+
+	fn open(&mut self) -> io::Result<&mut fs::File> {
+		if self.handle.is_none() {
+			if let Some(parent) = self.path.parent() {
+				if parent != PathBuf::new() {
+					try!(fs::create_dir_all(parent));
+				}
+			}
+			let file = try!(fs::OpenOptions::new()
+				.read(true)
+				.write(true)
+				.create(true)
+				.open(&self.path));
+			// data of existing files is left alone until it is checked,
+			// they are extended by writes if needed
+			if try!(file.metadata()).len() == 0 {
+				try!(file.set_len(self.length));
+			}
+			self.handle = Some(file);
+		}
+		Ok(self.handle.as_mut().unwrap())
+	}
+}
+
+// part of a byte range that falls into a single file
+struct Span {
+	file: usize,
+	file_offset: u64,
+	buffer_start: usize,
+	buffer_end: usize,
+}
+
+pub struct FileStorage {
+	pieces: Vec<Piece>,
+	files: Vec<FileEntry>,
+	piece_length: u64,
+	pieces_complete: usize,
+	// last piece read from disk, so that get_piece can return a slice
+	cached_piece: Option<(usize, Vec<u8>)>,
+}
+
+impl Storage for FileStorage {
+	fn new(info: TorrentInfo) -> Self {
+		let sizes = piece_sizes(&info);
+		let pieces = info.pieces.iter()
+			.zip(sizes)
+			.map(|(&hash, size)| Piece {
+				size: size,
+				blocks: BlockMap::new(size),
+				verified: false,
+				wanted: true,
+				hash: hash,
+			})
+			.collect();
+
+		let mut files = Vec::new();
+		let mut start = 0;
+		for file in &info.files {
+			files.push(FileEntry {
+				path: info.file_path(file),
+				start: start,
+				length: file.length,
+				handle: None,
+			});
+			start += file.length;
+		}
+
+		// empty files never receive any data, so create them right away
+		for file in files.iter_mut().filter(|f| f.length == 0) {
+			if let Err(e) = file.open() {
+				warn!("Failed to create file {:?}: {:?}", file.path, e);
+			}
+		}
+
+		FileStorage {
+			pieces: pieces,
+			files: files,
+			piece_length: info.piece_length,
+			pieces_complete: 0,
+			cached_piece: None,
+		}
+	}
+
+	fn get_piece(&mut self, index: usize) -> Option<&[u8]> {
+		match self.pieces.get(index) {
+			Some(piece) if piece.is_complete() => {}
+			_ => return None,
+		}
+
+		let cached = match self.cached_piece {
+			Some((cached, _)) => cached == index,
+			None => false,
+		};
+		if !cached {
+			match self.read_piece(index) {
+				Ok(data) => self.cached_piece = Some((index, data)),
+				Err(e) => {
+					warn!("Failed to read piece #{}: {:?}", index, e);
+					return None;
+				}
+			}
+		}
+		self.cached_piece.as_ref().map(|&(_, ref data)| data.as_slice())
+	}
+
+	fn store_block(&mut self, block: Block) -> Result<usize, StoreError> {
+		match self.pieces.get(block.piece) {
+			Some(piece) if block.offset + block.data.len() <= piece.size => {}
+			_ => return Err(StoreError::BadBlock),
+		}
+
+		let added = self.pieces[block.piece].blocks.add(block.offset, block.data.len(), block.peer.as_ref());
+		let piece_start = self.piece_start(block.piece);
+		let mut new_bytes = 0;
+		for (start, end) in added {
+			let data = &block.data[(start - block.offset)..(end - block.offset)];
+			if let Err(e) = self.write_at(piece_start + start as u64, data) {
+				// we no longer know which blocks made it to the disk
+				self.pieces[block.piece].blocks.clear();
+				return Err(StoreError::Io(e));
+			}
+			new_bytes += end - start;
+		}
+		Ok(new_bytes)
+	}
+
+	fn unverified_piece(&mut self, index: usize) -> Option<(Vec<u8>, [u8; 20])> {
+		match self.pieces.get(index) {
+			Some(piece) if piece.blocks.is_complete() && !piece.verified => {}
+			_ => return None,
+		}
+		match self.read_piece(index) {
+			Ok(data) => Some((data, self.pieces[index].hash)),
+			Err(e) => {
+				warn!("Failed to read piece #{}: {:?}", index, e);
+				self.pieces[index].blocks.clear();
+				None
+			}
+		}
+	}
+
+	fn piece_verified(&mut self, index: usize, correct: bool) {
+		match self.pieces.get_mut(index) {
+			Some(piece) if piece.blocks.is_complete() && !piece.verified => {
+				if !correct {
+					debug!("Hash mismatch, deleting piece #{}", index);
+					piece.blocks.clear();
+					return;
+				}
+				piece.verified = true;
+			}
+			_ => return,
+		}
+		self.pieces_complete += 1;
+		info!("Downloaded piece #{} (completed: {}/{})",
+			index,
+			self.pieces_complete,
+			self.pieces.len());
+	}
+
+	fn piece_sources(&self, index: usize) -> Vec<Option<PeerAddress>> {
+		match self.pieces.get(index) {
+			Some(piece) if !piece.is_complete() => piece.blocks.sources(),
+			_ => Vec::new(),
+		}
+	}
+
+	fn requests<'a>(&'a self) -> Box<Iterator<Item=Request> + 'a> {
+		Box::new(self.pieces.iter()
+			.enumerate()
+			.filter(|&(_, piece)| piece.wanted)
+			.flat_map(|(index, piece)| piece.create_fill_requests(index)))
+	}
+
+	fn bytes_missing(&self) -> usize {
+		self.pieces.iter()
+			.filter(|piece| piece.wanted)
+			.map(|ref piece| piece.blocks.bytes_missing())
+			.fold(0, |a, b| a + b)
+	}
+
+	// pieces that are still being verified are not missing, but not complete either
+	fn is_complete(&self) -> bool {
+		self.pieces.iter().all(|piece| !piece.wanted || piece.is_complete())
+	}
+
+	fn set_wanted(&mut self, wanted: &[bool]) {
+		for (piece, &wanted) in self.pieces.iter_mut().zip(wanted) {
+			piece.wanted = wanted;
+		}
+	}
+
+	fn has_piece(&mut self, index: usize) -> bool {
+		self.pieces.get(index).map(Piece::is_complete).unwrap_or(false)
+	}
+
+	fn existing_piece(&mut self, index: usize) -> Option<(Vec<u8>, [u8; 20])> {
+		match self.pieces.get(index) {
+			Some(piece) if !piece.is_complete() => {}
+			_ => return None,
+		}
+		let start = self.piece_start(index);
+		let size = self.pieces[index].size;
+		// don't create missing files just to find out they are empty
+		let files_exist = self.spans(start, size)
+			.iter()
+			.all(|span| self.files[span.file].path.exists());
+		if !files_exist {
+			return None;
+		}
+		match self.read_piece(index) {
+			Ok(data) => Some((data, self.pieces[index].hash)),
+			Err(e) => {
+				warn!("Failed to read piece #{}: {:?}", index, e);
+				None
+			}
+		}
+	}
+
+	fn mark_complete(&mut self, index: usize) -> bool {
+		match self.pieces.get_mut(index) {
+			Some(piece) => {
+				if !piece.is_complete() {
+					piece.blocks.fill();
+					piece.verified = true;
+					self.pieces_complete += 1;
+				}
+				true
+			}
+			None => false,
+		}
+	}
+
+	fn partial_blocks(&mut self) -> Vec<Block> {
+		let mut blocks = Vec::new();
+		for index in 0..self.pieces.len() {
+			if self.pieces[index].is_complete() {
+				continue;
+			}
+			let piece_start = self.piece_start(index);
+			for (start, end) in self.pieces[index].blocks.received_ranges() {
+				let mut data = vec![0; end - start];
+				match self.read_at(piece_start + start as u64, &mut data) {
+					Ok(()) => blocks.push(Block::new(index, start, data)),
+					Err(e) => warn!("Failed to read piece #{}: {:?}", index, e),
+				}
+			}
+		}
+		blocks
+	}
+}
+
+impl FileStorage {
+	fn piece_start(&self, index: usize) -> u64 {
+		index as u64 * self.piece_length
+	}
+
+	fn read_piece(&mut self, index: usize) -> io::Result<Vec<u8>> {
+		let mut data = vec![0; self.pieces[index].size];
+		let start = self.piece_start(index);
+		try!(self.read_at(start, &mut data));
+		Ok(data)
+	}
+
+	fn spans(&self, start: u64, length: usize) -> Vec<Span> {
+		let end = start + length as u64;
+		self.files.iter()
+			.enumerate()
+			.filter(|&(_, file)| file.start < end && start < file.end())
+			.map(|(index, file)| {
+				let from = ::std::cmp::max(start, file.start);
+				let to = ::std::cmp::min(end, file.end());
+				Span {
+					file: index,
+					file_offset: from - file.start,
+					buffer_start: (from - start) as usize,
+					buffer_end: (to - start) as usize,
+				}
+			})
+			.collect()
+	}
+
+	fn read_at(&mut self, start: u64, buffer: &mut [u8]) -> io::Result<()> {
+		for span in self.spans(start, buffer.len()) {
+			let file = try!(self.files[span.file].open());
+			try!(file.seek(SeekFrom::Start(span.file_offset)));
+			try!(file.read_exact(&mut buffer[span.buffer_start..span.buffer_end]));
+		}
+		Ok(())
+	}
+
+	fn write_at(&mut self, start: u64, data: &[u8]) -> io::Result<()> {
+		for span in self.spans(start, data.len()) {
+			let file = try!(self.files[span.file].open());
+			try!(file.seek(SeekFrom::Start(span.file_offset)));
+			try!(file.write_all(&data[span.buffer_start..span.buffer_end]));
+		}
+		// invalidate cache if it was overwritten
+		self.cached_piece = None;
+		Ok(())
+	}
+}
+
+
+#[cfg(test)]
+mod test {
+	use std::fs;
+	use std::path::PathBuf;
+	use storage::{Storage, Block, StoreError, BLOCK_SIZE};
+	use storage::piece_hash as hash;
+	use torrent::{TorrentInfo, File};
+	use super::FileStorage;
+
+	#[test]
+	fn piece_spanning_files() {
+		let root = ::std::env::temp_dir().join(format!("task2-file-storage-{}", ::rand::random::<u32>()));
+
+		let data = b"0123456789abcdefghij";
+		let info = TorrentInfo {
+			root: root.clone(),
+			piece_length: 8,
+			pieces: vec![hash(&data[0..8]), hash(&data[8..16]), hash(&data[16..20])],
+			files: vec![
+				File { path: PathBuf::from("a"), length: 5 },
+				File { path: PathBuf::from("dir/b"), length: 0 },
+				File { path: PathBuf::from("dir/c"), length: 15 },
+			],
+			private: false,
+			single_file: false,
+		};
+
+		let mut storage = FileStorage::new(info);
+		assert_eq!(storage.bytes_missing(), 20);
+		for piece in 0..3 {
+			let start = piece * 8;
+			let end = ::std::cmp::min(start + 8, data.len());
+			let block = Block::new(piece, 0, data[start..end].to_vec());
+			assert_eq!(storage.store_block(block).ok(), Some(end - start));
+			let (received, expected) = storage.unverified_piece(piece).unwrap();
+			assert_eq!(received, &data[start..end]);
+			storage.piece_verified(piece, hash(&received) == expected);
+		}
+		assert!(storage.is_complete());
+		assert_eq!(storage.get_piece(0), Some(&data[0..8]));
+		assert_eq!(storage.get_piece(2), Some(&data[16..20]));
+
+		assert_eq!(fs::read(root.join("a")).unwrap(), &data[0..5]);
+		assert_eq!(fs::read(root.join("dir/b")).unwrap(), b"");
+		assert_eq!(fs::read(root.join("dir/c")).unwrap(), &data[5..20]);
+		let _ = fs::remove_dir_all(&root);
+	}
+
+	#[test]
+	fn corrupt_piece_is_discarded() {
+		let root = ::std::env::temp_dir().join(format!("task2-file-storage-corrupt-{}", ::rand::random::<u32>()));
+
+		let size = 2 * BLOCK_SIZE;
+		let info = TorrentInfo {
+			root: root.clone(),
+			piece_length: size as u64,
+			pieces: vec![hash(&vec![1; size])],
+			files: vec![File { path: PathBuf::from("x"), length: size as u64 }],
+			private: false,
+			single_file: false,
+		};
+
+		let mut storage = FileStorage::new(info);
+		assert_eq!(storage.store_block(Block::new(0, BLOCK_SIZE, vec![1; BLOCK_SIZE])).ok(), Some(BLOCK_SIZE));
+		assert_eq!(storage.partial_blocks().len(), 1);
+		assert_eq!(storage.store_block(Block::new(0, 0, vec![2; BLOCK_SIZE])).ok(), Some(BLOCK_SIZE));
+		let (received, expected) = storage.unverified_piece(0).unwrap();
+		storage.piece_verified(0, hash(&received) == expected);
+		assert_eq!(storage.bytes_missing(), size);
+		assert!(storage.get_piece(0).is_none());
+		let _ = fs::remove_dir_all(&root);
+	}
+
+	#[test]
+	fn existing_files_are_kept() {
+		let root = ::std::env::temp_dir().join(format!("task2-file-storage-existing-{}", ::rand::random::<u32>()));
+		fs::create_dir_all(&root).unwrap();
+		// left over from something else, longer than the torrent's file
+		fs::write(root.join("x"), vec![1; 3 * BLOCK_SIZE]).unwrap();
+
+		let info = TorrentInfo {
+			root: root.clone(),
+			piece_length: BLOCK_SIZE as u64,
+			pieces: vec![hash(&vec![1; BLOCK_SIZE]), hash(&vec![2; BLOCK_SIZE])],
+			files: vec![File { path: PathBuf::from("x"), length: 2 * BLOCK_SIZE as u64 }],
+			private: false,
+			single_file: false,
+		};
+		let mut storage = FileStorage::new(info);
+		let (data, expected) = storage.existing_piece(0).unwrap();
+		assert_eq!(hash(&data), expected);
+		assert_eq!(fs::metadata(root.join("x")).unwrap().len(), 3 * BLOCK_SIZE as u64);
+		let _ = fs::remove_dir_all(&root);
+	}
+
+	#[test]
+	fn write_errors_are_reported() {
+		let root = ::std::env::temp_dir().join(format!("task2-file-storage-unwritable-{}", ::rand::random::<u32>()));
+		// directory where the file should be
+		fs::create_dir_all(root.join("x")).unwrap();
+
+		let info = TorrentInfo {
+			root: root.clone(),
+			piece_length: BLOCK_SIZE as u64,
+			pieces: vec![hash(&vec![1; BLOCK_SIZE])],
+			files: vec![File { path: PathBuf::from("x"), length: BLOCK_SIZE as u64 }],
+			private: false,
+			single_file: false,
+		};
+		let mut storage = FileStorage::new(info);
+		match storage.store_block(Block::new(0, 0, vec![1; BLOCK_SIZE])) {
+			Err(StoreError::Io(_)) => {}
+			_ => panic!("write should fail"),
+		}
+		assert_eq!(storage.bytes_missing(), BLOCK_SIZE);
+		let _ = fs::remove_dir_all(&root);
+	}
+}
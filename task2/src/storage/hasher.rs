@@ -0,0 +1,175 @@
+use std::io;
+use std::thread;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Sender, Receiver};
+use downloader::connection::event_loop::{EventLoop, Waker, WakeHandle};
+use storage::Storage;
+
+
+// pieces read from disk while checking existing data that may wait
+// for a free worker, so that whole torrent is not read into memory
+const MAX_QUEUED: usize = 16;
+
+struct Job {
+	piece: usize,
+	data: Vec<u8>,
+	hash: [u8; 20],
+}
+
+pub struct HashResult {
+	pub piece: usize,
+	pub correct: bool,
+}
+
+// Checks hashes of pieces on worker threads, so that the network thread
+// does not stall while large pieces are being hashed. Finished results
+// wake up the event loop the pool is registered with.
+pub struct HashPool {
+	jobs: Sender<Job>,
+	results: Receiver<HashResult>,
+	waker: Waker,
+	pending: usize,
+}
+
+impl HashPool {
+	pub fn new(workers: usize) -> HashPool {
+		let (jobs, job_receiver) = channel();
+		let (result_sender, results) = channel();
+		let waker = Waker::new();
+		let job_receiver = Arc::new(Mutex::new(job_receiver));
+		for _ in 0..::std::cmp::max(workers, 1) {
+			let jobs = job_receiver.clone();
+			let results = result_sender.clone();
+			let wake = waker.handle();
+			thread::spawn(move || work(jobs, results, wake));
+		}
+		HashPool {
+			jobs: jobs,
+			results: results,
+			waker: waker,
+			pending: 0,
+		}
+	}
+
+	pub fn register(&self, event_loop: &EventLoop) -> io::Result<()> {
+		event_loop.watch(&self.waker)
+	}
+
+	pub fn submit(&mut self, piece: usize, data: Vec<u8>, hash: [u8; 20]) {
+		let job = Job {
+			piece: piece,
+			data: data,
+			hash: hash,
+		};
+		self.jobs.send(job).expect("hash workers have stopped");
+		self.pending += 1;
+	}
+
+	// pieces that are still being hashed
+	pub fn pending(&self) -> usize {
+		self.pending
+	}
+
+	// Results that are ready, does not block.
+	pub fn results(&mut self) -> Vec<HashResult> {
+		self.waker.reset();
+		let results = self.results.try_iter().collect::<Vec<_>>();
+		self.pending -= results.len();
+		results
+	}
+
+	// Waits for the next result, None if nothing is being hashed.
+	pub fn wait(&mut self) -> Option<HashResult> {
+		if self.pending == 0 {
+			return None;
+		}
+		let result = self.results.recv().ok();
+		if result.is_some() {
+			self.pending -= 1;
+		}
+		result
+	}
+}
+
+fn work(jobs: Arc<Mutex<Receiver<Job>>>, results: Sender<HashResult>, wake: WakeHandle) {
+	loop {
+		// pool was dropped if channel is closed
+		let job = match jobs.lock().unwrap().recv() {
+			Ok(job) => job,
+			Err(_) => return,
+		};
+		let mut hasher = ::sha1::Sha1::new();
+		hasher.update(&job.data);
+		let result = HashResult {
+			piece: job.piece,
+			correct: hasher.digest().bytes() == job.hash,
+		};
+		if results.send(result).is_err() {
+			return;
+		}
+		wake.wake();
+	}
+}
+
+// Checks data of given pieces that is already present (e.g. files left
+// over from previous run) using workers of the pool and keeps pieces
+// that have correct hashes.
+pub fn verify_existing<S, I>(storage: &mut S, pool: &mut HashPool, pieces: I)
+		where S: Storage, I: IntoIterator<Item=usize> {
+	let mut checked = 0;
+	let mut found = 0;
+	for index in pieces {
+		if storage.has_piece(index) {
+			continue;
+		}
+		let (data, hash) = match storage.existing_piece(index) {
+			Some(piece) => piece,
+			None => continue,
+		};
+		if checked == 0 {
+			info!("Checking existing data");
+		}
+		checked += 1;
+		while pool.pending() >= MAX_QUEUED {
+			found += keep_correct(storage, pool.wait());
+		}
+		pool.submit(index, data, hash);
+	}
+	while pool.pending() > 0 {
+		found += keep_correct(storage, pool.wait());
+	}
+	if checked > 0 {
+		info!("Found {}/{} checked pieces complete", found, checked);
+	}
+}
+
+fn keep_correct<S: Storage>(storage: &mut S, result: Option<HashResult>) -> usize {
+	match result {
+		Some(ref result) if result.correct && storage.mark_complete(result.piece) => 1,
+		_ => 0,
+	}
+}
+
+
+#[cfg(test)]
+mod test {
+	use storage::piece_hash as hash;
+	use super::HashPool;
+
+	#[test]
+	fn hashes_on_workers() {
+		let mut pool = HashPool::new(2);
+		pool.submit(0, b"good".to_vec(), hash(b"good"));
+		pool.submit(1, b"bad".to_vec(), hash(b"good"));
+		pool.submit(2, b"good".to_vec(), hash(b"good"));
+
+		let mut results = Vec::new();
+		while let Some(result) = pool.wait() {
+			results.push((result.piece, result.correct));
+		}
+		results.sort();
+		assert_eq!(results, vec![(0, true), (1, false), (2, true)]);
+		assert_eq!(pool.pending(), 0);
+		assert!(pool.results().is_empty());
+	}
+}
@@ -1,90 +1,61 @@
 use storage::*;
+use storage::blocks::BlockMap;
+use downloader::PeerAddress;
 use downloader::request::Request;
-use torrent::{TorrentInfo, File};
+use torrent::TorrentInfo;
 
 
 struct Piece {
 	index: usize,
-	size: usize,
 	data: Vec<u8>,
+	blocks: BlockMap,
+	verified: bool,
+	wanted: bool,
 	hash: [u8; 20],
 }
 
 impl Piece {
 	fn is_complete(&self) -> bool {
-		self.size == self.data.len()
+		self.verified
 	}
 
-	fn is_correct(&self) -> bool {
-		if !self.is_complete() {
-			return true;
-		}
-		let mut hasher = ::sha1::Sha1::new();
-		hasher.update(&self.data);
-		hasher.digest().bytes() == self.hash
-	}
-
-	fn validate(&mut self) {
-		if !self.is_correct() {
-			debug!("Hash mismatch, deleting piece #{}", self.index);
-			self.data.clear();
-		}
-	}
-
-	fn create_fill_request(&self) -> Option<Request> {
-		let missing_size = self.size - self.data.len();
-		let offset = self.data.len();
-		if missing_size > 0 {
-			Some(Request::new(self.index, offset, missing_size))
-		} else {
-			None
-		}
+	fn create_fill_requests(&self) -> Vec<Request> {
+		self.blocks.missing_ranges()
+			.into_iter()
+			.map(|(start, end)| Request::new(self.index, start, end - start))
+			.collect()
 	}
 }
 
 pub struct MemoryStorage {
 	pieces: Vec<Piece>,
-	files: Vec<File>,
 	pieces_complete: usize,
 }
 
 impl Storage for MemoryStorage {
 	fn new(info: TorrentInfo) -> Self {
-		let mut size = info.files.iter()
-			.map(|ref f| f.length as usize)
-			.fold(0, |a, b| a + b);
+		let sizes = piece_sizes(&info);
 		let mut pieces = Vec::new();
-		for hash in info.pieces {
-			if size == 0 {
-				panic!("Cannot divide to pieces");
-			}
-			let s = if size > info.piece_length as usize {
-				info.piece_length as usize
-			} else {
-				size
-			};
-			size -= s;
+		for (hash, size) in info.pieces.into_iter().zip(sizes) {
 			let index = pieces.len();
 			pieces.push(Piece {
 				index: index,
-				size: s,
-				data: Vec::new(),
+				data: vec![0; size],
+				blocks: BlockMap::new(size),
+				verified: false,
+				wanted: true,
 				hash: hash,
 			});
 		}
-		if size != 0 {
-			panic!("Cannot divide to pieces");
-		}
 		MemoryStorage {
 			pieces: pieces,
-			files: info.files,
 			pieces_complete: 0,
 		}
 	}
 
 	fn get_piece(&mut self, index: usize) -> Option<&[u8]> {
 		self.pieces.get(index).and_then(|ref piece| {
-			if piece.data.len() == piece.size {
+			if piece.is_complete() {
 				Some(piece.data.as_slice())
 			} else {
 				None
@@ -92,71 +63,163 @@ impl Storage for MemoryStorage {
 		})
 	}
 
-	fn store_block(&mut self, block: Block) -> Result<usize, BadBlock> {
-		let mut completed_piece = false;
+	fn store_block(&mut self, block: Block) -> Result<usize, StoreError> {
+		let piece = match self.pieces.get_mut(block.piece) {
+			Some(piece) => piece,
+			None => return Err(StoreError::BadBlock),
+		};
+		if block.offset + block.data.len() > piece.data.len() {
+			return Err(StoreError::BadBlock);
+		}
 
-		let res = self.pieces.get_mut(block.piece)
-			.ok_or(BadBlock)
-			.and_then(|ref mut piece| {
-			let old_end = piece.data.len();
-			let new_end = block.offset + block.data.len();
-			if new_end > piece.size {
-				Err(BadBlock)
-			} else {
-				if new_end > old_end && block.offset <= old_end {
-					let skip = block.offset - old_end;
-					for &byte in &block.data[skip..] {
-						piece.data.push(byte);
-					}
-					piece.validate();
-					if piece.is_complete() {
-						completed_piece = true;
-					}
-					// piece was possibly removed because of bad hash
-					if block.data.len() < skip {
-						Ok(0)
-					} else {
-						Ok(block.data.len() - skip)
-					}
-				} else {
-					// given block cannot be attached to prefix, forget about it
-					Ok(0)
-				}
+		let mut added = 0;
+		for (start, end) in piece.blocks.add(block.offset, block.data.len(), block.peer.as_ref()) {
+			let from = start - block.offset;
+			let to = end - block.offset;
+			piece.data[start..end].copy_from_slice(&block.data[from..to]);
+			added += end - start;
+		}
+		Ok(added)
+	}
+
+	fn unverified_piece(&mut self, index: usize) -> Option<(Vec<u8>, [u8; 20])> {
+		match self.pieces.get(index) {
+			Some(piece) if piece.blocks.is_complete() && !piece.verified => {
+				Some((piece.data.clone(), piece.hash))
 			}
-		});
-		if completed_piece {
-			self.pieces_complete += 1;
-			info!("Downloaded piece #{} (completed: {}/{})",
-				block.piece,
-				self.pieces_complete,
-				self.pieces.len());
+			_ => None,
 		}
-		if self.is_complete() {
-			self.dump_to_file();
+	}
+
+	fn piece_verified(&mut self, index: usize, correct: bool) {
+		{
+			let piece = match self.pieces.get_mut(index) {
+				Some(piece) if piece.blocks.is_complete() && !piece.verified => piece,
+				_ => return,
+			};
+			if !correct {
+				debug!("Hash mismatch, deleting piece #{}", index);
+				piece.blocks.clear();
+				return;
+			}
+			piece.verified = true;
+		}
+
+		self.pieces_complete += 1;
+		info!("Downloaded piece #{} (completed: {}/{})",
+			index,
+			self.pieces_complete,
+			self.pieces.len());
+	}
+
+	fn piece_sources(&self, index: usize) -> Vec<Option<PeerAddress>> {
+		match self.pieces.get(index) {
+			Some(piece) if !piece.is_complete() => piece.blocks.sources(),
+			_ => Vec::new(),
 		}
-		res
 	}
 
 	fn requests<'a>(&'a self) -> Box<Iterator<Item=Request> + 'a> {
-		Box::new(self.pieces.iter().filter_map(|x| x.create_fill_request()))
+		Box::new(self.pieces.iter()
+			.filter(|piece| piece.wanted)
+			.flat_map(Piece::create_fill_requests))
 	}
 
 	fn bytes_missing(&self) -> usize {
 		self.pieces.iter()
-			.map(|ref piece| piece.size - piece.data.len())
+			.filter(|piece| piece.wanted)
+			.map(|ref piece| piece.blocks.bytes_missing())
 			.fold(0, |a, b| a + b)
 	}
+
+	// pieces that are still being verified are not missing, but not complete either
+	fn is_complete(&self) -> bool {
+		self.pieces.iter().all(|piece| !piece.wanted || piece.is_complete())
+	}
+
+	fn set_wanted(&mut self, wanted: &[bool]) {
+		for (piece, &wanted) in self.pieces.iter_mut().zip(wanted) {
+			piece.wanted = wanted;
+		}
+	}
+
+	fn partial_blocks(&mut self) -> Vec<Block> {
+		let mut blocks = Vec::new();
+		for piece in self.pieces.iter().filter(|piece| !piece.is_complete()) {
+			for (start, end) in piece.blocks.received_ranges() {
+				blocks.push(Block::new(piece.index, start, piece.data[start..end].to_vec()));
+			}
+		}
+		blocks
+	}
 }
 
-impl MemoryStorage {
-	fn dump_to_file(&self) {
-		use std::io::prelude::*;
-		let mut file = ::std::fs::File::create("./test.out").expect("Failed to create file");
-		let mut total_size = 0_usize;
-		for piece in &self.pieces {
-			total_size += piece.size;
-			file.write_all(&piece.data).expect("failed to write to file");
+
+#[cfg(test)]
+mod test {
+	use std::path::PathBuf;
+	use storage::{Storage, Block, BLOCK_SIZE};
+	use storage::piece_hash as hash;
+	use downloader::request::Request;
+	use torrent::{TorrentInfo, File};
+	use super::MemoryStorage;
+
+	// first piece has given data, second one is one byte long
+	fn storage(data: &[u8]) -> MemoryStorage {
+		MemoryStorage::new(TorrentInfo {
+			root: PathBuf::from("."),
+			piece_length: data.len() as u64,
+			pieces: vec![hash(data), hash(&[0])],
+			files: vec![File { path: PathBuf::from("x"), length: data.len() as u64 + 1 }],
+			private: false,
+			single_file: false,
+		})
+	}
+
+	#[test]
+	fn blocks_in_any_order() {
+		let data = (0..3 * BLOCK_SIZE + 100).map(|i| i as u8).collect::<Vec<_>>();
+		let mut storage = storage(&data);
+		for &block in &[2, 0, 3] {
+			let start = block * BLOCK_SIZE;
+			let end = ::std::cmp::min(start + BLOCK_SIZE, data.len());
+			let stored = storage.store_block(Block::new(0, start, data[start..end].to_vec()));
+			assert_eq!(stored.ok(), Some(end - start));
 		}
-		println!("Wrote to file, total size: {}", total_size);
+		assert_eq!(storage.bytes_missing(), BLOCK_SIZE + 1);
+		assert_eq!(storage.requests().collect::<Vec<_>>(),
+			vec![Request::new(0, BLOCK_SIZE, BLOCK_SIZE), Request::new(1, 0, 1)]);
+		assert!(storage.get_piece(0).is_none());
+
+		let block = Block::new(0, BLOCK_SIZE, data[BLOCK_SIZE..2 * BLOCK_SIZE].to_vec());
+		assert_eq!(storage.store_block(block).ok(), Some(BLOCK_SIZE));
+		assert!(storage.get_piece(0).is_none());
+		let (received, expected) = storage.unverified_piece(0).unwrap();
+		assert_eq!((received.as_slice(), expected), (data.as_slice(), hash(&data)));
+		storage.piece_verified(0, true);
+		assert!(storage.unverified_piece(0).is_none());
+		assert_eq!(storage.get_piece(0), Some(data.as_slice()));
+	}
+
+	#[test]
+	fn corrupt_piece_is_discarded() {
+		let data = vec![1; 2 * BLOCK_SIZE];
+		let mut storage = storage(&data);
+		assert_eq!(storage.store_block(Block::new(0, BLOCK_SIZE, vec![1; BLOCK_SIZE])).ok(), Some(BLOCK_SIZE));
+		assert_eq!(storage.partial_blocks().len(), 1);
+		assert_eq!(storage.store_block(Block::new(0, 0, vec![2; BLOCK_SIZE])).ok(), Some(BLOCK_SIZE));
+		storage.piece_verified(0, false);
+		assert_eq!(storage.bytes_missing(), 2 * BLOCK_SIZE + 1);
+		assert!(storage.get_piece(0).is_none());
+		assert!(storage.store_block(Block::new(0, BLOCK_SIZE, vec![1; BLOCK_SIZE + 1])).is_err());
+	}
+
+	#[test]
+	fn skipped_pieces_are_not_missing() {
+		let mut storage = storage(&vec![1; 2 * BLOCK_SIZE]);
+		storage.set_wanted(&[false, true]);
+		assert_eq!(storage.bytes_missing(), 1);
+		assert_eq!(storage.requests().collect::<Vec<_>>(), vec![Request::new(1, 0, 1)]);
+		assert!(!storage.is_complete());
 	}
 }
@@ -1,16 +1,32 @@
+pub mod blocks;
 pub mod memory;
-pub mod partial;
+pub mod file;
+pub mod hasher;
+pub mod resume;
 
+use std::io;
 use torrent::TorrentInfo;
+use downloader::PeerAddress;
 use downloader::request::Request;
 
 
-pub struct BadBlock;
+// pieces are stored and requested in blocks of this size
+pub const BLOCK_SIZE: usize = 0x4000; // 16 kb
+
+#[derive(Debug)]
+pub enum StoreError {
+	// block does not fit into its piece
+	BadBlock,
+	// block could not be written, e.g. because the disk is full
+	Io(io::Error),
+}
 
 pub struct Block {
 	pub piece: usize,
 	pub offset: usize,
 	pub data: Vec<u8>,
+	// peer that sent the block, if it came from the network
+	pub peer: Option<PeerAddress>,
 }
 
 impl Block {
@@ -19,17 +35,83 @@ impl Block {
 			piece: piece,
 			offset: offset,
 			data: data,
+			peer: None,
+		}
+	}
+
+	pub fn from_peer(piece: usize, offset: usize, data: Vec<u8>, peer: PeerAddress) -> Block {
+		Block {
+			peer: Some(peer),
+			..Block::new(piece, offset, data)
+		}
+	}
+}
+
+pub fn piece_sizes(info: &TorrentInfo) -> Vec<usize> {
+	let mut size = info.files.iter()
+		.map(|ref f| f.length as usize)
+		.fold(0, |a, b| a + b);
+	let mut sizes = Vec::new();
+	for _ in &info.pieces {
+		if size == 0 {
+			panic!("Cannot divide to pieces");
 		}
+		let s = if size > info.piece_length as usize {
+			info.piece_length as usize
+		} else {
+			size
+		};
+		size -= s;
+		sizes.push(s);
+	}
+	if size != 0 {
+		panic!("Cannot divide to pieces");
 	}
+	sizes
+}
+
+pub fn bitfield<S: Storage>(storage: &mut S, piece_count: usize) -> Vec<u8> {
+	let mut bits = vec![0_u8; (piece_count + 7) / 8];
+	for piece in 0..piece_count {
+		if storage.has_piece(piece) {
+			bits[piece / 8] |= 1 << (7 - piece % 8);
+		}
+	}
+	bits
+}
+
+// hash of piece data, for torrents made up in tests
+#[cfg(test)]
+pub fn piece_hash(data: &[u8]) -> [u8; 20] {
+	let mut hasher = ::sha1::Sha1::new();
+	hasher.update(data);
+	hasher.digest().bytes()
 }
 
 pub trait Storage {
 	fn new(info: TorrentInfo) -> Self;
 	fn get_piece(&mut self, index: usize) -> Option<&[u8]>;
-	fn store_block(&mut self, block: Block) -> Result<usize, BadBlock>;
+	fn store_block(&mut self, block: Block) -> Result<usize, StoreError>;
 	fn bytes_missing(&self) -> usize;
 	fn requests<'a>(&'a self) -> Box<Iterator<Item=Request> + 'a>;
 
+	// data and expected hash of a piece whose blocks were all received,
+	// but which was not verified yet; the piece becomes available only
+	// after piece_verified tells that the hash is correct
+	fn unverified_piece(&mut self, index: usize) -> Option<(Vec<u8>, [u8; 20])>;
+	// incorrect pieces are discarded and downloaded again
+	fn piece_verified(&mut self, index: usize, correct: bool);
+
+	// Pieces that are not wanted (e.g. they only have data of skipped
+	// files) are not requested and don't count as missing.
+	fn set_wanted(&mut self, wanted: &[bool]);
+
+	// peers that sent blocks of a piece that is not verified yet,
+	// None stands for blocks nobody can be blamed for (e.g. restored ones)
+	fn piece_sources(&self, _index: usize) -> Vec<Option<PeerAddress>> {
+		Vec::new()
+	}
+
 	fn is_complete(&self) -> bool {
 		self.bytes_missing() == 0
 	}
@@ -37,4 +119,21 @@ pub trait Storage {
 	fn has_piece(&mut self, index: usize) -> bool {
 		self.get_piece(index).is_some()
 	}
+
+	// data and expected hash of a piece that might be already present
+	// (e.g. in files left over from previous run), see verify_existing
+	fn existing_piece(&mut self, _index: usize) -> Option<(Vec<u8>, [u8; 20])> {
+		None
+	}
+
+	// marks piece as present without checking it, returns false
+	// if storage cannot do that (e.g. it does not persist data)
+	fn mark_complete(&mut self, _index: usize) -> bool {
+		false
+	}
+
+	// blocks of pieces that are not complete yet
+	fn partial_blocks(&mut self) -> Vec<Block> {
+		Vec::new()
+	}
 }
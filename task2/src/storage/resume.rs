@@ -0,0 +1,379 @@
+use std::fs;
+use std::io;
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::time::UNIX_EPOCH;
+use bencode::{BValue, encode, decode};
+use storage::{Storage, Block, bitfield};
+use torrent::TorrentInfo;
+
+
+// State that lets us restart download without rehashing all the data.
+// Saved file is a bencoded dict:
+//   "info hash" - hash of torrent this state belongs to
+//   "files"     - list of [length, modification time] for every file,
+//                 used to detect files that were changed after saving
+//   "pieces"    - bitfield of completed pieces
+//   "partial"   - list of [piece, offset, data] for incomplete pieces
+pub struct ResumeFile {
+	path: PathBuf,
+	info_hash: [u8; 20],
+	files: Vec<PathBuf>,
+	// range of pieces every file has data in
+	file_pieces: Vec<(usize, usize)>,
+	piece_count: usize,
+}
+
+impl ResumeFile {
+	pub fn new(path: PathBuf, info_hash: [u8; 20], info: &TorrentInfo) -> ResumeFile {
+		let mut file_pieces = Vec::new();
+		let mut start = 0;
+		for file in &info.files {
+			let end = start + file.length;
+			let first = (start / info.piece_length) as usize;
+			let last = ((end + info.piece_length - 1) / info.piece_length) as usize;
+			file_pieces.push((first, ::std::cmp::max(first, last)));
+			start = end;
+		}
+		ResumeFile {
+			path: path,
+			info_hash: info_hash,
+			files: info.files.iter().map(|file| info.file_path(file)).collect(),
+			file_pieces: file_pieces,
+			piece_count: info.pieces.len(),
+		}
+	}
+
+	pub fn save<S: Storage>(&self, storage: &mut S) -> io::Result<()> {
+		let pieces = bitfield(storage, self.piece_count);
+
+		let partial = storage.partial_blocks()
+			.into_iter()
+			.map(|block| blist![
+				BValue::Int(block.piece as i64),
+				BValue::Int(block.offset as i64),
+				BValue::Str(block.data)
+			])
+			.collect();
+
+		let state = bdict![
+			b"info hash".to_vec() => BValue::Str(self.info_hash.to_vec()),
+			b"files".to_vec() => BValue::List(self.file_stamps()),
+			b"pieces".to_vec() => BValue::Str(pieces),
+			b"partial".to_vec() => BValue::List(partial)
+		];
+
+		// write to temporary file first so that crash
+		// while saving would not destroy previous state
+		let mut temp_path = self.path.clone().into_os_string();
+		temp_path.push(".tmp");
+		{
+			let mut file = try!(fs::File::create(&temp_path));
+			try!(file.write_all(&encode(&state)));
+			try!(file.sync_all());
+		}
+		fs::rename(&temp_path, &self.path)
+	}
+
+	// Restores saved state, returns pieces that might have been written
+	// since it was saved (their data has to be checked again), None if
+	// there is no usable state.
+	pub fn load<S: Storage>(&self, storage: &mut S) -> Option<Vec<usize>> {
+		let mut contents = Vec::new();
+		let read = fs::File::open(&self.path)
+			.and_then(|mut file| file.read_to_end(&mut contents));
+		if let Err(e) = read {
+			debug!("Failed to read resume file {:?}: {:?}", self.path, e);
+			return None;
+		}
+
+		let state = match decode(&contents) {
+			Ok(state) => state,
+			Err(e) => {
+				warn!("Resume file is malformed: {:?}", e);
+				return None;
+			}
+		};
+
+		match self.restore(state, storage) {
+			Ok(changed) => {
+				info!("Restored state from {:?}", self.path);
+				Some(changed)
+			}
+			Err(e) => {
+				warn!("Cannot use resume file: {}", e);
+				None
+			}
+		}
+	}
+
+	fn restore<S: Storage>(&self, state: BValue, storage: &mut S) -> Result<Vec<usize>, &'static str> {
+		let mut dict = try!(state.get_dict().ok_or("not a dict"));
+
+		let info_hash = try!(dict
+			.remove(&b"info hash"[..])
+			.and_then(BValue::get_string)
+			.ok_or("missing info hash"));
+		if info_hash != &self.info_hash[..] {
+			return Err("state belongs to different torrent");
+		}
+
+		let files = try!(dict
+			.remove(&b"files"[..])
+			.and_then(BValue::get_list)
+			.ok_or("missing files"));
+		if files.len() != self.files.len() {
+			return Err("state has different files");
+		}
+		// Files that were modified after saving got new blocks of pieces
+		// that were not complete yet. Complete pieces are never written
+		// again, so they are only checked if the file changed its length,
+		// i.e. someone else replaced or cut it.
+		let mut changed = vec![false; self.piece_count];
+		let mut replaced = vec![false; self.piece_count];
+		for (index, (saved, current)) in files.iter().zip(self.file_stamps()).enumerate() {
+			if *saved != current {
+				let resized = stamp_length(saved) != stamp_length(&current);
+				debug!("{:?} was modified, resized: {}", self.files[index], resized);
+				let (first, end) = self.file_pieces[index];
+				for piece in first..end {
+					changed[piece] = true;
+					replaced[piece] |= resized;
+				}
+			}
+		}
+
+		let pieces = try!(dict
+			.remove(&b"pieces"[..])
+			.and_then(BValue::get_string)
+			.ok_or("missing pieces"));
+		if pieces.len() != (self.piece_count + 7) / 8 {
+			return Err("bad piece bitfield");
+		}
+
+		let mut partial = Vec::new();
+		let partial_list = try!(dict
+			.remove(&b"partial"[..])
+			.and_then(BValue::get_list)
+			.ok_or("missing partial pieces"));
+		for block in partial_list {
+			partial.push(try!(decode_block(block)));
+		}
+
+		for index in 0..self.piece_count {
+			if pieces[index / 8] & (1 << (7 - index % 8)) != 0 && !replaced[index] {
+				if !storage.mark_complete(index) {
+					return Err("storage does not support resuming");
+				}
+				changed[index] = false;
+			}
+		}
+		for block in partial {
+			if block.piece >= self.piece_count {
+				return Err("bad partial block");
+			}
+			if changed[block.piece] {
+				continue;
+			}
+			if storage.store_block(block).is_err() {
+				return Err("bad partial block");
+			}
+		}
+
+		Ok((0..self.piece_count).filter(|&index| changed[index]).collect())
+	}
+
+	fn file_stamps(&self) -> Vec<BValue> {
+		self.files.iter()
+			.map(|path| {
+				let (length, modified) = match fs::metadata(path) {
+					Ok(metadata) => {
+						let modified = metadata.modified()
+							.ok()
+							.and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+							.map(|time| time.as_secs())
+							.unwrap_or(0);
+						(metadata.len(), modified)
+					}
+					Err(_) => (0, 0),
+				};
+				blist![BValue::Int(length as i64), BValue::Int(modified as i64)]
+			})
+			.collect()
+	}
+}
+
+fn stamp_length(stamp: &BValue) -> Option<i64> {
+	stamp.get_list_ref()
+		.and_then(|items| items.first())
+		.and_then(BValue::get_int)
+}
+
+fn decode_block(value: BValue) -> Result<Block, &'static str> {
+	let mut items = try!(value.get_list().ok_or("bad partial block")).into_iter();
+	let piece = items.next().and_then(|x| x.get_int());
+	let offset = items.next().and_then(|x| x.get_int());
+	let data = items.next().and_then(BValue::get_string);
+	match (piece, offset, data) {
+		(Some(piece), Some(offset), Some(data)) if piece >= 0 && offset >= 0 => {
+			Ok(Block::new(piece as usize, offset as usize, data))
+		}
+		_ => Err("bad partial block"),
+	}
+}
+
+
+#[cfg(test)]
+mod test {
+	use std::fs;
+	use std::path::PathBuf;
+	use std::time::{Duration, UNIX_EPOCH};
+	use storage::{Storage, Block, BLOCK_SIZE};
+	use storage::piece_hash as hash;
+	use storage::file::FileStorage;
+	use storage::hasher::{HashPool, verify_existing};
+	use torrent::{TorrentInfo, File};
+	use super::ResumeFile;
+
+	#[test]
+	fn restores_complete_and_partial_pieces() {
+		let root = ::std::env::temp_dir().join(format!("task2-resume-{}", ::rand::random::<u32>()));
+		fs::create_dir_all(&root).unwrap();
+
+		let size = 2 * BLOCK_SIZE;
+		let data = (0..2 * size).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+		let info = TorrentInfo {
+			root: root.clone(),
+			piece_length: size as u64,
+			pieces: vec![hash(&data[0..size]), hash(&data[size..])],
+			files: vec![File { path: PathBuf::from("data"), length: 2 * size as u64 }],
+			private: false,
+			single_file: false,
+		};
+		let resume = ResumeFile::new(root.join("state"), [7; 20], &info);
+
+		{
+			let mut storage = FileStorage::new(info.clone());
+			storage.store_block(Block::new(0, 0, data[0..size].to_vec())).ok().unwrap();
+			storage.piece_verified(0, true);
+			let second_block = data[(size + BLOCK_SIZE)..].to_vec();
+			storage.store_block(Block::new(1, BLOCK_SIZE, second_block)).ok().unwrap();
+			resume.save(&mut storage).unwrap();
+		}
+
+		let mut storage = FileStorage::new(info.clone());
+		assert_eq!(resume.load(&mut storage), Some(vec![]));
+		assert!(storage.has_piece(0));
+		let partial = storage.partial_blocks();
+		assert_eq!(partial.len(), 1);
+		assert_eq!((partial[0].piece, partial[0].offset), (1, BLOCK_SIZE));
+		assert_eq!(partial[0].data, &data[(size + BLOCK_SIZE)..]);
+
+		// full recheck finds the completed piece too
+		let mut storage = FileStorage::new(info);
+		verify_existing(&mut storage, &mut HashPool::new(2), 0..2);
+		assert!(storage.has_piece(0));
+		assert!(!storage.has_piece(1));
+
+		let _ = fs::remove_dir_all(&root);
+	}
+
+	#[test]
+	fn rechecks_only_modified_files() {
+		let root = ::std::env::temp_dir().join(format!("task2-resume-modified-{}", ::rand::random::<u32>()));
+		fs::create_dir_all(&root).unwrap();
+
+		// every file has a piece of its own
+		let data = (0..2 * BLOCK_SIZE).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+		let info = TorrentInfo {
+			root: root.join("files"),
+			piece_length: BLOCK_SIZE as u64,
+			pieces: data.chunks(BLOCK_SIZE).map(hash).collect(),
+			files: vec![
+				File { path: PathBuf::from("a"), length: BLOCK_SIZE as u64 },
+				File { path: PathBuf::from("b"), length: BLOCK_SIZE as u64 },
+			],
+			private: false,
+			single_file: false,
+		};
+		let resume = ResumeFile::new(root.join("state"), [7; 20], &info);
+		{
+			let mut storage = FileStorage::new(info.clone());
+			storage.store_block(Block::new(0, 0, data[..BLOCK_SIZE].to_vec())).ok().unwrap();
+			storage.piece_verified(0, true);
+			resume.save(&mut storage).unwrap();
+			// second file is only written after the state was saved
+			storage.store_block(Block::new(1, 0, data[BLOCK_SIZE..].to_vec())).ok().unwrap();
+			storage.piece_verified(1, true);
+		}
+
+		let mut storage = FileStorage::new(info);
+		let changed = resume.load(&mut storage).unwrap();
+		assert_eq!(changed, vec![1]);
+		assert!(storage.has_piece(0));
+		assert!(!storage.has_piece(1));
+		verify_existing(&mut storage, &mut HashPool::new(2), changed);
+		assert!(storage.has_piece(1));
+
+		let _ = fs::remove_dir_all(&root);
+	}
+
+	// single file with a piece in every block, first piece saved complete
+	// and the second one written after saving
+	fn written_after_save(root: &PathBuf) -> (TorrentInfo, ResumeFile) {
+		let data = (0..2 * BLOCK_SIZE).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+		let info = TorrentInfo {
+			root: root.clone(),
+			piece_length: BLOCK_SIZE as u64,
+			pieces: data.chunks(BLOCK_SIZE).map(hash).collect(),
+			files: vec![File { path: PathBuf::from("data"), length: 2 * BLOCK_SIZE as u64 }],
+			private: false,
+			single_file: false,
+		};
+		let resume = ResumeFile::new(root.join("state"), [7; 20], &info);
+		let mut storage = FileStorage::new(info.clone());
+		storage.store_block(Block::new(0, 0, data[..BLOCK_SIZE].to_vec())).ok().unwrap();
+		storage.piece_verified(0, true);
+		resume.save(&mut storage).unwrap();
+		storage.store_block(Block::new(1, 0, data[BLOCK_SIZE..].to_vec())).ok().unwrap();
+		storage.piece_verified(1, true);
+		(info, resume)
+	}
+
+	#[test]
+	fn trusts_complete_pieces_of_written_files() {
+		let root = ::std::env::temp_dir().join(format!("task2-resume-written-{}", ::rand::random::<u32>()));
+		fs::create_dir_all(&root).unwrap();
+		let (info, resume) = written_after_save(&root);
+		// writes within the same second don't always change the time
+		fs::OpenOptions::new().write(true).open(root.join("data")).unwrap()
+			.set_modified(UNIX_EPOCH + Duration::from_secs(1)).unwrap();
+
+		let mut storage = FileStorage::new(info);
+		let changed = resume.load(&mut storage).unwrap();
+		assert_eq!(changed, vec![1]);
+		assert!(storage.has_piece(0));
+		verify_existing(&mut storage, &mut HashPool::new(2), changed);
+		assert!(storage.has_piece(1));
+
+		let _ = fs::remove_dir_all(&root);
+	}
+
+	#[test]
+	fn rechecks_cut_files() {
+		let root = ::std::env::temp_dir().join(format!("task2-resume-cut-{}", ::rand::random::<u32>()));
+		fs::create_dir_all(&root).unwrap();
+		let (info, resume) = written_after_save(&root);
+		fs::OpenOptions::new().write(true).open(root.join("data")).unwrap()
+			.set_len(BLOCK_SIZE as u64 / 2).unwrap();
+
+		let mut storage = FileStorage::new(info);
+		let changed = resume.load(&mut storage).unwrap();
+		assert_eq!(changed, vec![0, 1]);
+		assert!(!storage.has_piece(0));
+		verify_existing(&mut storage, &mut HashPool::new(2), changed);
+		assert!(!storage.has_piece(0));
+
+		let _ = fs::remove_dir_all(&root);
+	}
+}
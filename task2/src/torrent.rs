@@ -1,9 +1,12 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf, Component};
 use bencode::{BValue, encode};
 
 #[derive(Clone)]
 pub struct Torrent {
-	pub tracker_url: String,
+	// tracker urls grouped in tiers, first tier is the most preferred one
+	pub trackers: Vec<Vec<String>>,
+	// "host:port" of DHT nodes, for torrents that rely on DHT
+	pub nodes: Vec<String>,
 	pub info: TorrentInfo,
 }
 
@@ -13,6 +16,22 @@ pub struct TorrentInfo {
 	pub piece_length: u64,
 	pub pieces: Vec<[u8; 20]>,
 	pub files: Vec<File>,
+	// peers may only come from trackers (BEP 27)
+	pub private: bool,
+	// root names the only file itself instead of a directory
+	pub single_file: bool,
+}
+
+impl TorrentInfo {
+	pub fn file_path(&self, file: &File) -> PathBuf {
+		// single file torrents name the file itself, otherwise
+		// name is a directory that all files are placed in
+		if self.single_file {
+			file.path.clone()
+		} else {
+			self.root.join(&file.path)
+		}
+	}
 }
 
 #[derive(Clone)]
@@ -33,6 +52,8 @@ pub enum DecodeError {
 	BadPieces,
 	BadFile,
 	BadFilePath,
+	BadAnnounceList,
+	BadNodes,
 	UTF8Error,
 }
 
@@ -41,11 +62,29 @@ pub type DecodeResult<T> = Result<T, DecodeError>;
 pub fn from_bvalue(value: BValue) -> DecodeResult<(Torrent, [u8; 20])> {
 	let mut dict = try!(value.get_dict().ok_or(DecodeError::MissingTracker));
 
-	let tracker = try!(dict
+	let announce = dict
 		.remove(&b"announce"[..])
 		.and_then(BValue::get_string)
-		.ok_or(DecodeError::MissingTracker)
-		.and_then(decode_string));
+		.map(decode_string);
+
+	let announce_list = dict
+		.remove(&b"announce-list"[..])
+		.and_then(BValue::get_list)
+		.map(decode_announce_list);
+
+	// announce-list takes priority over announce when both are present,
+	// torrents with neither are trackerless and use DHT instead
+	let trackers = match (announce_list, announce) {
+		(Some(Ok(tiers)), _) if tiers.len() > 0 => tiers,
+		(_, Some(Ok(tracker))) => vec![vec![tracker]],
+		(Some(Err(e)), _) | (_, Some(Err(e))) => return Err(e),
+		_ => Vec::new(),
+	};
+
+	let nodes = match dict.remove(&b"nodes"[..]).and_then(BValue::get_list) {
+		Some(nodes) => try!(decode_nodes(nodes)),
+		None => Vec::new(),
+	};
 
 	let (info, hash) = try!(dict
 		.remove(&b"info"[..])
@@ -53,7 +92,8 @@ pub fn from_bvalue(value: BValue) -> DecodeResult<(Torrent, [u8; 20])> {
 		.and_then(decode_info));
 
 	Ok((Torrent {
-		tracker_url: tracker,
+		trackers: trackers,
+		nodes: nodes,
 		info: info,
 	}, hash))
 }
@@ -65,7 +105,7 @@ fn hash_info(value: &BValue) -> [u8; 20] {
 	hasher.digest().bytes()
 }
 
-fn decode_info(value: BValue) -> DecodeResult<(TorrentInfo, [u8; 20])> {
+pub fn decode_info(value: BValue) -> DecodeResult<(TorrentInfo, [u8; 20])> {
 	let hash = hash_info(&value);
 	let mut dict = try!(value.get_dict().ok_or(DecodeError::MissingName));
 
@@ -75,6 +115,7 @@ fn decode_info(value: BValue) -> DecodeResult<(TorrentInfo, [u8; 20])> {
 		.ok_or(DecodeError::MissingName)
 		.and_then(decode_string)
 		.map(PathBuf::from));
+	try!(check_path(&name));
 
 	let piece_length = try!(dict
 		.remove(&b"piece length"[..])
@@ -88,6 +129,8 @@ fn decode_info(value: BValue) -> DecodeResult<(TorrentInfo, [u8; 20])> {
 		.ok_or(DecodeError::MissingPieces)
 		.and_then(split_piece_hashes));
 
+	let private = dict.remove(&b"private"[..]).and_then(|x| x.get_int()) == Some(1);
+
 	let length = dict.remove(&b"length"[..]).and_then(|x| x.get_int());
 
 	let files = match length {
@@ -118,6 +161,8 @@ fn decode_info(value: BValue) -> DecodeResult<(TorrentInfo, [u8; 20])> {
 		piece_length: piece_length,
 		pieces: pieces,
 		files: files,
+		private: private,
+		single_file: length.is_some(),
 	}, hash))
 }
 
@@ -130,6 +175,7 @@ fn decode_file(value: BValue) -> DecodeResult<File> {
 		.ok_or(DecodeError::BadFile)
 		.and_then(decode_path)
 		.map(PathBuf::from));
+	try!(check_path(&path));
 
 	let length = try!(dict
 		.remove(&b"length"[..])
@@ -143,6 +189,45 @@ fn decode_file(value: BValue) -> DecodeResult<File> {
 	})
 }
 
+fn decode_announce_list(tiers: Vec<BValue>) -> DecodeResult<Vec<Vec<String>>> {
+	let mut decoded = Vec::new();
+	for tier in tiers.into_iter() {
+		let urls = try!(tier.get_list().ok_or(DecodeError::BadAnnounceList));
+		let mut decoded_tier = Vec::new();
+		for url in urls.into_iter() {
+			let url = try!(url
+				.get_string()
+				.ok_or(DecodeError::BadAnnounceList)
+				.and_then(decode_string));
+			decoded_tier.push(url);
+		}
+		if decoded_tier.len() > 0 {
+			decoded.push(decoded_tier);
+		}
+	}
+	Ok(decoded)
+}
+
+// nodes are given as list of [host, port] pairs
+fn decode_nodes(nodes: Vec<BValue>) -> DecodeResult<Vec<String>> {
+	let mut decoded = Vec::new();
+	for node in nodes.into_iter() {
+		let mut pair = try!(node.get_list().ok_or(DecodeError::BadNodes));
+		if pair.len() != 2 {
+			return Err(DecodeError::BadNodes);
+		}
+		let port = try!(pair[1].get_int()
+			.and_then(int_to_unsigned)
+			.ok_or(DecodeError::BadNodes));
+		let host = try!(pair.remove(0)
+			.get_string()
+			.ok_or(DecodeError::BadNodes)
+			.and_then(decode_string));
+		decoded.push(format!("{}:{}", host, port));
+	}
+	Ok(decoded)
+}
+
 fn decode_string(bytes: Vec<u8>) -> DecodeResult<String> {
 	String::from_utf8(bytes).map_err(|_| DecodeError::UTF8Error)
 }
@@ -161,6 +246,26 @@ fn decode_path(segments: Vec<BValue>) -> DecodeResult<PathBuf> {
 	Ok(path)
 }
 
+// Paths come from whoever made the torrent (or from any peer, for magnet
+// links), files must not end up outside of the download directory.
+fn check_path(path: &Path) -> DecodeResult<()> {
+	let mut normal = false;
+	for component in path.components() {
+		match component {
+			Component::Normal(_) => normal = true,
+			Component::CurDir => {}
+			Component::ParentDir | Component::RootDir | Component::Prefix(_) => {
+				return Err(DecodeError::BadFilePath);
+			}
+		}
+	}
+	if normal {
+		Ok(())
+	} else {
+		Err(DecodeError::BadFilePath)
+	}
+}
+
 fn int_to_unsigned(i: i64) -> Option<u64> {
 	if i >= 0 {
 		Some(i as u64)
@@ -186,3 +291,139 @@ fn split_piece_hashes(raw: Vec<u8>) -> DecodeResult<Vec<[u8; 20]>> {
 		Ok(result)
 	}
 }
+
+
+
+#[cfg(test)]
+mod test {
+	use bencode::BValue;
+	use super::*;
+
+	fn bstr(literal: &[u8]) -> BValue {
+		BValue::Str(literal.to_vec())
+	}
+
+	fn torrent(announce: Option<BValue>, announce_list: Option<BValue>) -> BValue {
+		let mut dict = ::std::collections::BTreeMap::new();
+		if let Some(announce) = announce {
+			dict.insert(b"announce".to_vec(), announce);
+		}
+		if let Some(list) = announce_list {
+			dict.insert(b"announce-list".to_vec(), list);
+		}
+		dict.insert(b"info".to_vec(), bdict![
+			b"name".to_vec() => bstr(b"file"),
+			b"piece length".to_vec() => BValue::Int(4),
+			b"pieces".to_vec() => BValue::Str(vec![0; 20]),
+			b"length".to_vec() => BValue::Int(3)
+		]);
+		BValue::Dict(dict)
+	}
+
+	#[test]
+	fn single_tracker() {
+		let value = torrent(Some(bstr(b"http://a")), None);
+		let (torrent, _) = from_bvalue(value).ok().unwrap();
+		assert_eq!(torrent.trackers, vec![vec!["http://a".to_string()]]);
+	}
+
+	#[test]
+	fn announce_list_tiers() {
+		let list = blist![
+			blist![bstr(b"http://a"), bstr(b"udp://b")],
+			blist![],
+			blist![bstr(b"http://c")]
+		];
+		let value = torrent(Some(bstr(b"http://a")), Some(list));
+		let (torrent, _) = from_bvalue(value).ok().unwrap();
+		assert_eq!(torrent.trackers, vec![
+			vec!["http://a".to_string(), "udp://b".to_string()],
+			vec!["http://c".to_string()],
+		]);
+	}
+
+	#[test]
+	fn private_flag() {
+		let info = |private: i64| bdict![
+			b"name".to_vec() => bstr(b"file"),
+			b"piece length".to_vec() => BValue::Int(4),
+			b"pieces".to_vec() => BValue::Str(vec![0; 20]),
+			b"length".to_vec() => BValue::Int(3),
+			b"private".to_vec() => BValue::Int(private)
+		];
+		assert!(decode_info(info(1)).ok().unwrap().0.private);
+		assert!(!decode_info(info(0)).ok().unwrap().0.private);
+		let (torrent, _) = from_bvalue(torrent(Some(bstr(b"http://a")), None)).ok().unwrap();
+		assert!(!torrent.info.private);
+	}
+
+	#[test]
+	fn unsafe_paths() {
+		let info = |name: &[u8], path: BValue| bdict![
+			b"name".to_vec() => bstr(name),
+			b"piece length".to_vec() => BValue::Int(4),
+			b"pieces".to_vec() => BValue::Str(vec![0; 20]),
+			b"files".to_vec() => blist![bdict![
+				b"length".to_vec() => BValue::Int(3),
+				b"path".to_vec() => path
+			]]
+		];
+		assert!(decode_info(info(b"dir", blist![bstr(b"a"), bstr(b"b")])).is_ok());
+		let unsafe_paths = vec![
+			(&b"dir"[..], blist![bstr(b".."), bstr(b"x")]),
+			(&b"dir"[..], blist![bstr(b"a/../../x")]),
+			(&b"dir"[..], blist![bstr(b"/etc"), bstr(b"passwd")]),
+			(&b"dir"[..], blist![]),
+			(&b".."[..], blist![bstr(b"x")]),
+			(&b"/tmp"[..], blist![bstr(b"x")]),
+		];
+		for (name, path) in unsafe_paths {
+			match decode_info(info(name, path)) {
+				Err(DecodeError::BadFilePath) => {}
+				_ => panic!("path should be rejected: {:?}", name),
+			}
+		}
+	}
+
+	#[test]
+	fn file_paths() {
+		let single = bdict![
+			b"name".to_vec() => bstr(b"file"),
+			b"piece length".to_vec() => BValue::Int(4),
+			b"pieces".to_vec() => BValue::Str(vec![0; 20]),
+			b"length".to_vec() => BValue::Int(3)
+		];
+		let (info, _) = decode_info(single).ok().unwrap();
+		assert_eq!(info.file_path(&info.files[0]), PathBuf::from("file"));
+
+		// directory may hold a file with its own name
+		let multi = bdict![
+			b"name".to_vec() => bstr(b"file"),
+			b"piece length".to_vec() => BValue::Int(4),
+			b"pieces".to_vec() => BValue::Str(vec![0; 20]),
+			b"files".to_vec() => blist![bdict![
+				b"length".to_vec() => BValue::Int(3),
+				b"path".to_vec() => blist![bstr(b"file")]
+			]]
+		];
+		let (info, _) = decode_info(multi).ok().unwrap();
+		assert_eq!(info.file_path(&info.files[0]), PathBuf::from("file/file"));
+	}
+
+	#[test]
+	fn trackerless() {
+		let mut value = torrent(None, Some(blist![]));
+		if let BValue::Dict(ref mut dict) = value {
+			dict.insert(b"nodes".to_vec(), blist![
+				blist![bstr(b"router.example.com"), BValue::Int(6881)],
+				blist![bstr(b"10.0.0.1"), BValue::Int(80)]
+			]);
+		}
+		let (torrent, _) = from_bvalue(value).ok().unwrap();
+		assert!(torrent.trackers.is_empty());
+		assert_eq!(torrent.nodes, vec![
+			"router.example.com:6881".to_string(),
+			"10.0.0.1:80".to_string(),
+		]);
+	}
+}